@@ -13,12 +13,19 @@ pub struct Cursor<'a> {
 
 impl<'a> Cursor<'a> {
 	pub fn vec<X>(vec: &mut Vec<u8>, f: impl FnOnce(Cursor) -> X) -> X {
-		let mut ptr = vec.as_ptr() as usize;
-		let t = f(Cursor { slice: vec, pivot: &mut ptr });
-		vec.truncate(ptr - vec.as_ptr() as usize);
+		let (t, len) = Self::slice(vec, f);
+		vec.truncate(len);
 		t
 	}
 
+	/// Passes a cursor over the slice to `f`, returning its result and the index of the pivot once it has returned.
+	pub fn slice<X>(slice: &mut [u8], f: impl FnOnce(Cursor) -> X) -> (X, usize) {
+		let base = slice.as_ptr() as usize;
+		let mut ptr = base;
+		let t = f(Cursor { slice, pivot: &mut ptr });
+		(t, ptr - base)
+	}
+
 	/// Gets the index of the pivot position within the slice.
 	#[inline]
 	pub fn pivot(&self) -> usize {
@@ -38,36 +38,63 @@ impl Slice {
 		unsafe { slice::from_raw_parts(ptr.as_ptr(), n) }
 	}
 
-	pub fn split_bytes(&self, n: usize) -> &[u8] {
-		assert!(n <= self.len());
+	/// Splits off the first `n` bytes, returning `None` if the slice is shorter.
+	pub fn try_split_bytes(&self, n: usize) -> Option<&[u8]> {
+		if n > self.len() {
+			return None;
+		}
 
 		let ptr = self.ptr.get();
 
 		self.ptr.set(unsafe { ptr.add(n) });
 		self.len.set(self.len() - n);
 
-		unsafe { slice::from_raw_parts(ptr.as_ptr(), n) }
+		Some(unsafe { slice::from_raw_parts(ptr.as_ptr(), n) })
 	}
 
-	pub fn rsplit_bytes(&self, n: usize) -> &[u8] {
-		let new_len = self.len().checked_sub(n).unwrap();
+	/// Splits off the last `n` bytes, returning `None` if the slice is shorter.
+	pub fn try_rsplit_bytes(&self, n: usize) -> Option<&[u8]> {
+		let new_len = self.len().checked_sub(n)?;
 
 		self.len.set(new_len);
 		let ptr = unsafe { self.ptr.get().add(new_len) };
 
-		unsafe { slice::from_raw_parts(ptr.as_ptr(), n) }
+		Some(unsafe { slice::from_raw_parts(ptr.as_ptr(), n) })
+	}
+
+	/// Splits off `n` instances of a type, returning `None` if the slice is shorter.
+	pub fn try_split_n<T: Cast>(&self, n: usize) -> Option<&[T]> {
+		self.try_split_bytes(n.checked_mul(size_of::<T>())?).map(bytes::as_slice)
+	}
+
+	/// Splits off an instance of a type from the start, returning `None` if the slice is shorter.
+	pub fn try_split<T: Cast>(&self) -> Option<&T> {
+		self.try_split_bytes(size_of::<T>()).map(bytes::cast)
+	}
+
+	/// Splits off an instance of a type from the end, returning `None` if the slice is shorter.
+	pub fn try_rsplit<T: Cast>(&self) -> Option<&T> {
+		self.try_rsplit_bytes(size_of::<T>()).map(bytes::cast)
+	}
+
+	pub fn split_bytes(&self, n: usize) -> &[u8] {
+		self.try_split_bytes(n).unwrap()
+	}
+
+	pub fn rsplit_bytes(&self, n: usize) -> &[u8] {
+		self.try_rsplit_bytes(n).unwrap()
 	}
 
 	pub fn split_n<T: Cast>(&self, n: usize) -> &[T] {
-		bytes::as_slice(self.split_bytes(n * size_of::<T>()))
+		self.try_split_n(n).unwrap()
 	}
 
 	pub fn split<T: Cast>(&self) -> &T {
-		bytes::cast(self.split_bytes(size_of::<T>()))
+		self.try_split().unwrap()
 	}
 
 	pub fn rsplit<T: Cast>(&self) -> &T {
-		bytes::cast(self.rsplit_bytes(size_of::<T>()))
+		self.try_rsplit().unwrap()
 	}
 
 	pub fn truncate(&self, len: usize) {
@@ -91,3 +118,49 @@ impl DerefMut for Slice {
 		unsafe { slice::from_raw_parts_mut(self.ptr.get().as_ptr(), self.len.get()) }
 	}
 }
+
+#[cfg(test)]
+fn from(bytes: &[u8]) -> Slice {
+	let mut slice = Slice::new(bytes.len());
+	slice.copy_from_slice(bytes);
+	slice
+}
+
+#[test]
+fn test_try_split_bytes() {
+	let buf = from(&[1, 2, 3, 4, 5]);
+
+	assert_eq!(buf.try_split_bytes(2), Some(&[1, 2][..]));
+	assert_eq!(buf.try_rsplit_bytes(1), Some(&[5][..]));
+	assert_eq!(&*buf, &[3, 4]);
+
+	// A failed split leaves the slice unchanged.
+	assert_eq!(buf.try_split_bytes(3), None);
+	assert_eq!(buf.try_rsplit_bytes(3), None);
+	assert_eq!(&*buf, &[3, 4]);
+
+	assert_eq!(buf.try_split_bytes(2), Some(&[3, 4][..]));
+	assert!(buf.is_empty());
+}
+
+#[test]
+fn test_try_split() {
+	let buf = from(&[1, 2, 3]);
+
+	assert_eq!(buf.try_split::<[u8; 2]>(), Some(&[1, 2]));
+	assert_eq!(buf.try_split::<[u8; 2]>(), None);
+	assert_eq!(buf.try_rsplit::<u8>(), Some(&3));
+	assert_eq!(buf.try_rsplit::<u8>(), None);
+}
+
+#[test]
+fn test_try_split_n() {
+	let buf = from(&[1, 2, 3, 4, 5]);
+
+	assert_eq!(buf.try_split_n::<[u8; 2]>(2), Some(&[[1, 2], [3, 4]][..]));
+	assert_eq!(buf.try_split_n::<[u8; 2]>(1), None);
+
+	// The length in bytes overflows, rather than wrapping to a small length.
+	assert_eq!(buf.try_split_n::<[u8; 2]>(usize::MAX / 2 + 1), None);
+	assert_eq!(&*buf, &[5]);
+}
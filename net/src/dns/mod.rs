@@ -9,8 +9,10 @@ use rand::Rng;
 use stakker::{fwd_to, Actor, FixedTimerKey, Ret, CX};
 use utils::bytes::Cast;
 use utils::endian::{u16be, u32be, BigEndian};
+use utils::error::*;
 
-use crate::ip::SocketAddr;
+use crate::icmp::{Kind, Report, Unreachable};
+use crate::ip::{Info, SocketAddr};
 use crate::udp;
 
 const TIMEOUT: Duration = Duration::from_secs(10);
@@ -42,7 +44,8 @@ impl Resolver {
 
 		cx.defer(move |s| {
 			net.apply(s, move |n, c| {
-				let socket = udp::Socket::bind_eph(n, c, fwd_to!([actor], process() as (SocketAddr, Slice)));
+				let socket = udp::Socket::bind_eph(n, c, fwd_to!([actor], process() as (SocketAddr, Slice, Info)));
+				socket.set_error_callback(fwd_to!([actor], error() as (Report)));
 
 				c.defer(move |s| actor.apply_prep(s, move |_| Some(Self { socket, primary: addr, in_flight: HashMap::new() })))
 			})
@@ -135,8 +138,29 @@ impl Resolver {
 		})
 	}
 
-	fn process(&mut self, cx: CX![], src: SocketAddr, buf: Slice) {
-		let header: &Header = buf.split();
+	/// Fails every in-flight request to a server which is reported to not be listening, rather than waiting to retry them.
+	fn error(&mut self, cx: CX![], report: Report) {
+		let Report { kind: Kind::Unreachable(Unreachable::Port), quoted, .. } = report else { return };
+
+		if quoted.dst.port != 53 {
+			return;
+		}
+
+		let failed: Vec<u16> = self.in_flight.iter().filter(|(_, e)| e.server == quoted.dst.addr).map(|(&id, _)| id).collect();
+
+		for id in failed {
+			warn!("DNS server {} is unreachable, failing request 0x{:x}", quoted.dst.addr, id);
+
+			// Dropping the callback fails the request.
+			let Entry { retry, .. } = self.in_flight.remove(&id).unwrap();
+			cx.timer_del(retry);
+		}
+	}
+
+	fn process(&mut self, cx: CX![], src: SocketAddr, buf: Slice, _: Info) {
+		let Some(header) = buf.try_split::<Header>() else {
+			return warn!("DNS message too short (got {} bytes)", buf.len());
+		};
 
 		info!("Recieved DNS response for 0x{:x}", header.id);
 
@@ -148,65 +172,71 @@ impl Resolver {
 			}
 		};
 
-		let flags = header.flags.get();
-
-		assert!(flags.qr());
-
-		// Expect there to be one resource record, which corresponds to an answer
-		assert!(header.qdcount.get() == 1);
-		assert!(header.ancount.get() == 1);
-		assert!(header.nscount.get() == 0);
-		assert!(header.arcount.get() == 0);
-
-		macro_rules! skip_name {
-			() => {
-				loop {
-					let len: u8 = *buf.split();
-
-					match len >> 6 {
-						// The octet is a length. Skip the number of bytes of its value.
-						0b00 => {}
-						// The octet is a pointer. Skip the second byte of the pointer.
-						0b11 => {
-							let _: &u8 = buf.split();
-							break;
-						}
-						_ => unimplemented!(),
-					}
-
-					if len == 0 {
-						break;
-					}
-
-					buf.split_bytes(len as _);
-				}
-			};
+		// Malformed responses are ignored, so the request is retried.
+		let Ok(addr) = answer(header, &buf) else { return };
+
+		log::info!("Resolved to {}", addr);
+
+		let Entry { ret, retry, .. } = entry.remove();
+
+		// Call the callback
+		ret.ret(addr);
+		// Cancel the retry timer, since the request has been resolved
+		cx.timer_del(retry);
+	}
+}
+
+/// Skips a domain name, which ends with either a zero-length label or a pointer.
+fn skip_name(buf: &Slice) -> Result {
+	loop {
+		let &len: &u8 = buf.try_split().ok_or_else(|| warn!("DNS name is truncated"))?;
+
+		match len >> 6 {
+			// The octet is a length. Skip the number of bytes of its value.
+			0b00 => {}
+			// The octet is a pointer. Skip the second byte of the pointer.
+			0b11 => {
+				buf.try_split_bytes(1).ok_or_else(|| warn!("DNS name pointer is truncated"))?;
+				return Ok(());
+			}
+			_ => return Err(warn!("DNS name has unsupported label type ({len:#04x})")),
 		}
 
-		// Skip QD
-		skip_name!();
-		buf.split_bytes(4);
+		if len == 0 {
+			return Ok(());
+		}
 
-		// Skip RNAME
-		skip_name!();
+		buf.try_split_bytes(len as _).ok_or_else(|| warn!("DNS label is truncated"))?;
+	}
+}
 
-		let rr: &RR = buf.split();
+/// Parses the address answered by a response to an A query, which follows its header.
+fn answer(header: &Header, buf: &Slice) -> Result<Ipv4Addr> {
+	if !header.flags.get().qr() {
+		return Err(warn!("DNS message is not a response"));
+	}
 
-		assert!(rr.ty.get() == TY_A);
-		assert!(rr.class.get() == CLASS_IN);
-		assert!(rr.rdlength.get() == 4);
+	// Expect there to be one resource record, which corresponds to an answer
+	let counts = [header.qdcount.get(), header.ancount.get(), header.nscount.get(), header.arcount.get()];
 
-		let addr: &Ipv4Addr = buf.split();
+	if counts != [1, 1, 0, 0] {
+		return Err(warn!("DNS response has unsupported section counts {counts:?}"));
+	}
 
-		log::info!("Resolved to {}", addr);
+	// Skip QD
+	skip_name(buf)?;
+	buf.try_split_bytes(4).ok_or_else(|| warn!("DNS question is truncated"))?;
 
-		let Entry { ret, retry, .. } = entry.remove();
+	// Skip RNAME
+	skip_name(buf)?;
 
-		// Call the callback
-		ret.ret(*addr);
-		// Cancel the retry timer, since the request has been resolved
-		cx.timer_del(retry);
+	let rr: &RR = buf.try_split().ok_or_else(|| warn!("DNS resource record is truncated"))?;
+
+	if rr.ty.get() != TY_A || rr.class.get() != CLASS_IN || rr.rdlength.get() != 4 {
+		return Err(warn!("DNS answer is not an IPv4 address"));
 	}
+
+	buf.try_split().copied().ok_or_else(|| warn!("DNS answer is truncated"))
 }
 
 #[bitsize(4)]
@@ -295,3 +325,86 @@ struct RR {
 	/// an unsigned 16 bit integer that specifies the length in octets of the RDATA field.
 	rdlength: u16be,
 }
+
+#[test]
+fn test_resolve() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use stakker::{actor, ret_nop, Fwd};
+
+	use crate::link;
+
+	let (mut s, a, b) = link::connect();
+
+	let queries = Rc::new(RefCell::new(Vec::new()));
+	let q = queries.clone();
+
+	let server = b.query(&mut s, |n, cx| udp::Socket::bind(n, cx, 53, Fwd::new(move |(src, buf, _): (SocketAddr, Slice, Info)| q.borrow_mut().push((src, buf.to_vec()))))).unwrap().unwrap();
+
+	let resolver = actor!(s, Resolver::init(a.clone(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))), ret_nop!());
+	link::run(&mut s);
+
+	let resolved = Rc::new(RefCell::new(Vec::new()));
+	let r = resolved.clone();
+
+	resolver.query(&mut s, |dns, cx| dns.v4(cx, "example.com", Ret::new(move |addr: Option<Ipv4Addr>| r.borrow_mut().push(addr))));
+	link::run(&mut s);
+
+	let (src, query) = queries.borrow_mut().pop().unwrap();
+
+	// The query asks one question for the A record of the name.
+	assert_eq!(&query[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+	assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+
+	// The response echoes the header and question, with the QR flag and an answer whose name points to the question.
+	let mut response = query.clone();
+	response[2] |= 0x80;
+	response[7] = 1;
+	response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 93, 184, 216, 34]);
+
+	// A malformed response is ignored, leaving the request in flight.
+	let truncated = response[..response.len() - 2].to_vec();
+
+	server.write(src, move |buf| {
+		buf.push(&truncated[..]);
+	});
+
+	link::run(&mut s);
+	assert!(resolved.borrow().is_empty());
+
+	server.write(src, move |buf| {
+		buf.push(&response[..]);
+	});
+
+	link::run(&mut s);
+	assert_eq!(*resolved.borrow(), [Some(Ipv4Addr::new(93, 184, 216, 34))]);
+}
+
+#[test]
+fn test_unreachable() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use stakker::{actor, ret_nop};
+
+	use crate::link;
+
+	let (mut s, a, _b) = link::connect();
+
+	let resolver = actor!(s, Resolver::init(a.clone(), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))), ret_nop!());
+	link::run(&mut s);
+
+	let resolved = Rc::new(RefCell::new(Vec::new()));
+
+	for server in [Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3)] {
+		let r = resolved.clone();
+		resolver.query(&mut s, |dns, cx| dns.v4_with(cx, "example.com", IpAddr::V4(server), Ret::new(move |addr: Option<Ipv4Addr>| r.borrow_mut().push((server, addr)))));
+	}
+
+	link::run(&mut s);
+
+	// The request to the server which has no listener on port 53 fails without waiting to be retried, while the other stays in flight.
+	assert_eq!(*resolved.borrow(), [(Ipv4Addr::new(10, 0, 0, 2), None)]);
+	assert_eq!(resolver.query(&mut s, |dns, _| dns.in_flight.values().map(|e| e.server).collect::<Vec<_>>()), Some(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))]));
+}
@@ -0,0 +1,988 @@
+//! Internet Control Message Protocol, for IPv4 (RFC 792) and IPv6 (RFC 4443).
+
+use core::mem::size_of;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use core::time::Duration;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use collections::bytes::{Cursor, Slice};
+use log::{debug, info, warn};
+use stakker::{FixedTimerKey, Ret, CX};
+use utils::bytes::{self, Cast};
+use utils::error::*;
+
+use crate::ip::{self, conntrack, ext, pmtu, Checksum, Info, Params, Protocol, SocketAddr, ToS, ECN};
+
+/// ICMPv4 Echo Reply.
+pub(crate) const V4_ECHO_REPLY: u8 = 0;
+/// ICMPv4 Echo Request.
+pub(crate) const V4_ECHO_REQUEST: u8 = 8;
+/// ICMPv4 Destination Unreachable.
+const V4_UNREACHABLE: u8 = 3;
+/// The Destination Unreachable code for a packet of a protocol which is not implemented.
+const V4_PROTOCOL_UNREACHABLE: u8 = 2;
+/// The Destination Unreachable code for a packet sent to a port without a listener.
+const V4_PORT_UNREACHABLE: u8 = 3;
+/// The Destination Unreachable code for a packet which needed fragmentation, but had the Don't Fragment flag set.
+const V4_FRAGMENTATION_NEEDED: u8 = 4;
+/// The Destination Unreachable code for a packet which was administratively prohibited, as specified by RFC 1812.
+const V4_ADMIN_PROHIBITED: u8 = 13;
+/// ICMPv4 Time Exceeded.
+const V4_TIME_EXCEEDED: u8 = 11;
+/// ICMPv4 Parameter Problem.
+const V4_PARAMETER_PROBLEM: u8 = 12;
+
+/// ICMPv6 Destination Unreachable.
+const V6_UNREACHABLE: u8 = 1;
+/// The Destination Unreachable code for a packet which was administratively prohibited.
+const V6_ADMIN_PROHIBITED: u8 = 1;
+/// The Destination Unreachable code for a packet sent to a port without a listener.
+const V6_PORT_UNREACHABLE: u8 = 4;
+/// ICMPv6 Packet Too Big.
+const V6_PACKET_TOO_BIG: u8 = 2;
+/// ICMPv6 Time Exceeded.
+const V6_TIME_EXCEEDED: u8 = 3;
+/// ICMPv6 Parameter Problem.
+const V6_PARAMETER_PROBLEM: u8 = 4;
+/// The Parameter Problem code for an unrecognised Next Header type.
+const V6_UNRECOGNISED_NEXT_HEADER: u8 = 1;
+
+/// ICMPv6 Echo Request.
+pub(crate) const V6_ECHO_REQUEST: u8 = 128;
+/// ICMPv6 Echo Reply.
+pub(crate) const V6_ECHO_REPLY: u8 = 129;
+
+/// The length of the IPv6 header.
+const V6_HEADER_LEN: usize = 40;
+
+/// The time after which an echo request without a reply is abandoned.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of error messages which may be sent in a burst.
+const ERROR_BURST: u32 = 10;
+/// The interval at which the error messages of a burst are replenished.
+const ERROR_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Cast)]
+#[repr(C)]
+struct Header {
+	/// The message type.
+	ty: u8,
+	/// The message subtype.
+	code: u8,
+	csum: [u8; 2],
+	/// The type-specific data of the header.
+	data: [u8; 4],
+}
+
+/// An error reported to the source of a packet.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Error {
+	/// The time-to-live or hop limit of a forwarded packet reached zero.
+	TimeExceeded,
+	/// A forwarded packet exceeded the MTU of the next link, which is reported.
+	TooBig(usize),
+	/// A packet was rejected by the packet filter.
+	Prohibited,
+	/// A packet was sent to a port without a listener.
+	PortUnreachable,
+	/// A packet was of a protocol which is not implemented.
+	ProtocolUnreachable,
+	/// A field of a received packet was invalid, at the octet offset of the pointer.
+	ParameterProblem { code: u8, pointer: u32 },
+}
+
+impl Error {
+	/// Returns the type, code and header data of the ICMPv4 message reporting the error.
+	fn v4(self) -> (u8, u8, [u8; 4]) {
+		match self {
+			Self::TimeExceeded => (V4_TIME_EXCEEDED, 0, [0; 4]),
+			Self::TooBig(mtu) => {
+				let [hi, lo] = (mtu.min(u16::MAX as usize) as u16).to_be_bytes();
+				(V4_UNREACHABLE, V4_FRAGMENTATION_NEEDED, [0, 0, hi, lo])
+			}
+			Self::Prohibited => (V4_UNREACHABLE, V4_ADMIN_PROHIBITED, [0; 4]),
+			Self::PortUnreachable => (V4_UNREACHABLE, V4_PORT_UNREACHABLE, [0; 4]),
+			Self::ProtocolUnreachable => (V4_UNREACHABLE, V4_PROTOCOL_UNREACHABLE, [0; 4]),
+			Self::ParameterProblem { code, pointer } => (V4_PARAMETER_PROBLEM, code, [pointer.min(u8::MAX as u32) as u8, 0, 0, 0]),
+		}
+	}
+
+	/// Returns the type, code and header data of the ICMPv6 message reporting the error, given the offset of the Next Header field which
+	/// identifies the upper-layer protocol of the packet.
+	fn v6(self, next_header: usize) -> (u8, u8, [u8; 4]) {
+		match self {
+			Self::TimeExceeded => (V6_TIME_EXCEEDED, 0, [0; 4]),
+			Self::TooBig(mtu) => (V6_PACKET_TOO_BIG, 0, (mtu.min(u32::MAX as usize) as u32).to_be_bytes()),
+			Self::Prohibited => (V6_UNREACHABLE, V6_ADMIN_PROHIBITED, [0; 4]),
+			Self::PortUnreachable => (V6_UNREACHABLE, V6_PORT_UNREACHABLE, [0; 4]),
+			// IPv6 reports unrecognised protocols as a problem with the Next Header field which identifies the protocol.
+			Self::ProtocolUnreachable => (V6_PARAMETER_PROBLEM, V6_UNRECOGNISED_NEXT_HEADER, (next_header as u32).to_be_bytes()),
+			Self::ParameterProblem { code, pointer } => (V6_PARAMETER_PROBLEM, code, pointer.to_be_bytes()),
+		}
+	}
+}
+
+/// The reason a destination was unreachable, as reported by a Destination Unreachable message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Unreachable {
+	Net,
+	Host,
+	Protocol,
+	Port,
+	/// The packet needed fragmentation, but had the Don't Fragment flag set. Carries the MTU of the next hop, which is zero if it was not
+	/// reported.
+	FragmentationNeeded(u16),
+	SourceRouteFailed,
+	/// Communication with the destination is administratively prohibited.
+	Prohibited,
+	/// A code without a specific meaning to the interface.
+	Other(u8),
+}
+
+/// The error reported by an ICMP error message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+	Unreachable(Unreachable),
+	/// The packet exceeded the MTU of the next hop on an IPv6 path, which is reported.
+	PacketTooBig(u32),
+	/// The time-to-live of the packet reached zero in transit, or its reassembly timed out.
+	TimeExceeded { reassembly: bool },
+	/// A field of the header of the packet was invalid, at the octet offset of the pointer.
+	ParameterProblem { pointer: u32 },
+}
+
+impl Kind {
+	/// Returns the error reported by an ICMPv4 message, if it is an error message.
+	fn v4(ty: u8, code: u8, data: [u8; 4]) -> Option<Self> {
+		Some(match ty {
+			V4_UNREACHABLE => Self::Unreachable(match code {
+				0 | 6 => Unreachable::Net,
+				1 | 7 => Unreachable::Host,
+				V4_PROTOCOL_UNREACHABLE => Unreachable::Protocol,
+				V4_PORT_UNREACHABLE => Unreachable::Port,
+				V4_FRAGMENTATION_NEEDED => Unreachable::FragmentationNeeded(u16::from_be_bytes([data[2], data[3]])),
+				5 => Unreachable::SourceRouteFailed,
+				9 | 10 | V4_ADMIN_PROHIBITED => Unreachable::Prohibited,
+				code => Unreachable::Other(code),
+			}),
+			V4_TIME_EXCEEDED => Self::TimeExceeded { reassembly: code == 1 },
+			V4_PARAMETER_PROBLEM => Self::ParameterProblem { pointer: data[0] as u32 },
+			_ => return None,
+		})
+	}
+
+	/// Returns the error reported by an ICMPv6 message, if it is an error message.
+	fn v6(ty: u8, code: u8, data: [u8; 4]) -> Option<Self> {
+		Some(match ty {
+			V6_UNREACHABLE => Self::Unreachable(match code {
+				0 => Unreachable::Net,
+				V6_ADMIN_PROHIBITED | 5 => Unreachable::Prohibited,
+				3 => Unreachable::Host,
+				V6_PORT_UNREACHABLE => Unreachable::Port,
+				code => Unreachable::Other(code),
+			}),
+			V6_PACKET_TOO_BIG => Self::PacketTooBig(u32::from_be_bytes(data)),
+			V6_TIME_EXCEEDED => Self::TimeExceeded { reassembly: code == 1 },
+			V6_PARAMETER_PROBLEM => Self::ParameterProblem { pointer: u32::from_be_bytes(data) },
+			_ => return None,
+		})
+	}
+}
+
+/// The headers of the packet quoted by an ICMP error message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Quoted {
+	/// The protocol number.
+	pub proto: u8,
+	/// The source of the packet, with the port of TCP and UDP packets, or the identifier of echo requests.
+	pub src: SocketAddr,
+	/// The destination of the packet, with the port of TCP and UDP packets, or the identifier of echo replies.
+	pub dst: SocketAddr,
+}
+
+/// An ICMP error message about a packet sent by the interface.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Report {
+	/// The address of the node which sent the message.
+	pub from: IpAddr,
+	/// The error reported by the message.
+	pub kind: Kind,
+	/// The headers of the packet which caused the error.
+	pub quoted: Quoted,
+}
+
+impl Quoted {
+	/// Parses the headers of a quoted packet, of which only the start of the transport header may be present.
+	pub(crate) fn parse(quote: &[u8]) -> Option<Self> {
+		let (proto, src, dst, payload) = match quote.first()? >> 4 {
+			4 if quote.len() >= 20 => {
+				let header_len = 4 * (quote[0] & 0xf) as usize;
+				(quote[9], IpAddr::V4(*bytes::cast(&quote[12..])), IpAddr::V4(*bytes::cast(&quote[16..])), quote.get(header_len..)?)
+			}
+			6 if quote.len() >= V6_HEADER_LEN => {
+				(quote[6], IpAddr::V6(*bytes::cast(&quote[8..])), IpAddr::V6(*bytes::cast(&quote[24..])), &quote[V6_HEADER_LEN..])
+			}
+			_ => return None,
+		};
+
+		let [sport, dport] = conntrack::ports(proto, payload)?;
+
+		Some(Self { proto, src: SocketAddr { addr: src, port: sport }, dst: SocketAddr { addr: dst, port: dport } })
+	}
+}
+
+/// Returns the identifier and sequence number of a quoted echo request.
+fn quoted_echo(quote: &[u8]) -> Option<(u16, u16)> {
+	let (proto, header_len) = match quote.first()? >> 4 {
+		4 => (Protocol::from(*quote.get(9)?), 4 * (quote[0] & 0xf) as usize),
+		6 => (Protocol::from(*quote.get(6)?), V6_HEADER_LEN),
+		_ => return None,
+	};
+
+	match (proto, quote.get(header_len..header_len + 8)?) {
+		(Protocol::Icmp, &[V4_ECHO_REQUEST, _, _, _, i0, i1, s0, s1]) | (Protocol::Icmpv6, &[V6_ECHO_REQUEST, _, _, _, i0, i1, s0, s1]) => {
+			Some((u16::from_be_bytes([i0, i1]), u16::from_be_bytes([s0, s1])))
+		}
+		_ => None,
+	}
+}
+
+/// An echo request awaiting its reply.
+struct Pending {
+	/// The time at which the request was sent.
+	sent: Instant,
+	/// The callback for the round-trip time.
+	ret: Ret<Duration>,
+	/// The timer which abandons the request.
+	timer: FixedTimerKey,
+}
+
+/// A token bucket limiting the rate of error messages, as recommended by RFC 1812 and required by RFC 4443.
+struct Bucket {
+	tokens: u32,
+	/// The time at which the bucket was last replenished, or `None` if it has never been used.
+	replenished: Option<Instant>,
+}
+
+impl Default for Bucket {
+	fn default() -> Self {
+		Self { tokens: ERROR_BURST, replenished: None }
+	}
+}
+
+impl Bucket {
+	/// Takes a token from the bucket, returning false if it is empty.
+	fn take(&mut self, now: Instant) -> bool {
+		let replenished = *self.replenished.get_or_insert(now);
+		let new = ((now - replenished).as_nanos() / ERROR_INTERVAL.as_nanos()).min(ERROR_BURST as u128) as u32;
+
+		self.tokens = (self.tokens + new).min(ERROR_BURST);
+		// The time spent full does not count towards the next token.
+		self.replenished = Some(if self.tokens == ERROR_BURST { now } else { replenished + ERROR_INTERVAL * new });
+
+		if self.tokens == 0 {
+			return false;
+		}
+
+		self.tokens -= 1;
+		true
+	}
+}
+
+/// The echo requests sent by the interface, and the rate limit of its error messages.
+#[derive(Default)]
+pub(crate) struct Interface {
+	/// The requests awaiting their replies, keyed by their destination, identifier and sequence number.
+	pending: HashMap<(IpAddr, u16, u16), Pending>,
+	/// The rate limits of ICMPv4 and ICMPv6 error messages.
+	errors_v4: Bucket,
+	errors_v6: Bucket,
+}
+
+/// Returns the checksum of the pseudo-header of an ICMP message, which is only included for ICMPv6.
+fn pseudo_checksum(src: IpAddr, dst: IpAddr, len: usize) -> Checksum {
+	match dst {
+		IpAddr::V4(_) => Checksum::default(),
+		IpAddr::V6(_) => {
+			let mut csum = ip::pseudo_checksum(Protocol::Icmpv6, src, dst);
+			csum.push(&(len as u32).to_be_bytes());
+			csum
+		}
+	}
+}
+
+/// Writes an echo message with the given type, identifier and sequence number, and payload.
+fn write_echo(buf: Cursor, mut csum: Checksum, ty: u8, data: [u8; 4], payload: &[u8]) {
+	let (header, buf): (&mut Header, _) = buf.split();
+	*header = Header { ty, code: 0, csum: [0, 0], data };
+	buf.push(payload);
+
+	csum.push(bytes::as_slice(header));
+	csum.push(payload);
+
+	header.csum = csum.end();
+}
+
+/// Returns whether an ICMPv4 message type is an error message.
+pub(crate) fn is_error_v4(ty: u8) -> bool {
+	matches!(ty, 3 | 4 | 5 | 11 | 12)
+}
+
+impl crate::Interface {
+	/// Reports an error to the source of a received IP packet, whose header has been validated. No message is sent where RFC 1812 and RFC
+	/// 4443 forbid it, such as in response to another error message.
+	pub(crate) fn send_icmp_error(&mut self, cx: CX![], error: Error, packet: &[u8]) {
+		match packet[0] >> 4 {
+			4 => self.send_icmp_error_v4(cx, error, packet),
+			6 => self.send_icmp_error_v6(cx, error, packet),
+			_ => {}
+		}
+	}
+
+	fn send_icmp_error_v4(&mut self, cx: CX![], error: Error, packet: &[u8]) {
+		let header_len = 4 * (packet[0] & 0xf) as usize;
+
+		let src: Ipv4Addr = *bytes::cast(&packet[12..]);
+		let dst: Ipv4Addr = *bytes::cast(&packet[16..]);
+
+		let offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+		let is_error = packet[9] == Protocol::Icmp.into() && packet.get(header_len).is_some_and(|&ty| is_error_v4(ty));
+
+		// Errors are not reported about errors, fragments other than the first, or packets which do not identify a single source host.
+		if is_error || offset != 0 || src.is_unspecified() || src.is_broadcast() || src.is_multicast() || dst.is_broadcast() || dst.is_multicast() {
+			return debug!("Not reporting {error:?} for packet from {src} to {dst}");
+		}
+
+		if !self.icmp.errors_v4.take(cx.now()) {
+			return debug!("Not reporting {error:?} for packet from {src} to {dst}, as the rate limit is exceeded");
+		}
+
+		let Some(local) = self.ip.addrs.source(IpAddr::V4(src)) else {
+			return warn!("No source address for ICMP message to {src}");
+		};
+
+		// The message quotes the header of the packet and the first 8 octets of its payload.
+		let quote = &packet[..packet.len().min(header_len + 8)];
+		let (ty, code, data) = error.v4();
+
+		self.write(cx, Protocol::Icmp, local, IpAddr::V4(src), Params::default(), |buf| {
+			let (header, buf): (&mut Header, _) = buf.split();
+			*header = Header { ty, code, csum: [0, 0], data };
+			buf.push(quote);
+
+			let mut csum = Checksum::of(bytes::as_slice(header));
+			csum.push(quote);
+
+			header.csum = csum.end();
+		});
+	}
+
+	fn send_icmp_error_v6(&mut self, cx: CX![], error: Error, packet: &[u8]) {
+		let src: Ipv6Addr = *bytes::cast(&packet[8..]);
+		let dst: Ipv6Addr = *bytes::cast(&packet[24..]);
+
+		// The upper-layer header follows any extension headers. Informational messages have the high-order bit of their type set.
+		let Some(upper) = ext::upper(packet) else {
+			return debug!("Not reporting {error:?} for packet from {src} to {dst} with truncated extension headers");
+		};
+
+		let is_error = upper.proto == Protocol::Icmpv6 && upper.at.and_then(|at| packet.get(at)).is_some_and(|&ty| ty & 0x80 == 0);
+
+		// Packet Too Big is reported about packets sent to multicast addresses, as it is needed for path MTU discovery, as are unrecognised
+		// options whose type requests it.
+		let multicast = match error {
+			Error::TooBig(_) => true,
+			Error::ParameterProblem { code: ext::UNRECOGNISED_OPTION, pointer } => packet.get(pointer as usize).is_some_and(|ty| ty >> 6 == 0b10),
+			_ => false,
+		};
+
+		if is_error || src.is_unspecified() || src.is_multicast() || (dst.is_multicast() && !multicast) {
+			return debug!("Not reporting {error:?} for packet from {src} to {dst}");
+		}
+
+		if !self.icmp.errors_v6.take(cx.now()) {
+			return debug!("Not reporting {error:?} for packet from {src} to {dst}, as the rate limit is exceeded");
+		}
+
+		let Some(local) = self.ip.addrs.source(IpAddr::V6(src)) else {
+			return warn!("No source address for ICMPv6 message to {src}");
+		};
+
+		// The message quotes as much of the packet as possible without exceeding the minimum MTU.
+		let quote = &packet[..packet.len().min(pmtu::MIN_V6 - V6_HEADER_LEN - size_of::<Header>())];
+		let (ty, code, data) = error.v6(upper.field);
+
+		let mut csum = pseudo_checksum(local, IpAddr::V6(src), size_of::<Header>() + quote.len());
+
+		self.write(cx, Protocol::Icmpv6, local, IpAddr::V6(src), Params::default(), |buf| {
+			let (header, buf): (&mut Header, _) = buf.split();
+			*header = Header { ty, code, csum: [0, 0], data };
+			buf.push(quote);
+
+			csum.push(bytes::as_slice(header));
+			csum.push(quote);
+
+			header.csum = csum.end();
+		});
+	}
+
+	/// Sends an echo request to an address, with the given identifier, sequence number and payload. The round-trip time is returned through
+	/// `ret` once the reply is received, and `None` is returned if an error is reported instead, or no reply is received within 10 seconds.
+	pub fn ping(&mut self, cx: CX![], dst: IpAddr, ident: u16, seq: u16, payload: Vec<u8>, ret: Ret<Duration>) {
+		let (proto, ty) = match dst {
+			IpAddr::V4(_) => (Protocol::Icmp, V4_ECHO_REQUEST),
+			IpAddr::V6(_) => (Protocol::Icmpv6, V6_ECHO_REQUEST),
+		};
+
+		if self.nat.in_use(proto, ident) {
+			return warn!("Cannot ping {dst}, as identifier {ident} is in use by a masqueraded flow");
+		}
+
+		let Some(src) = self.ip.addrs.source(dst) else {
+			return warn!("No source address for echo request to {dst}");
+		};
+
+		let key = (dst, ident, seq);
+		let actor = cx.access_actor().clone();
+
+		let timer = cx.after(ECHO_TIMEOUT, move |s| {
+			actor.apply(s, move |this, _| {
+				if this.icmp.pending.remove(&key).is_some() {
+					debug!("Echo request {seq} to {dst} timed out");
+				}
+			})
+		});
+
+		// A request with the same identifier and sequence number replaces the previous one.
+		if let Some(previous) = self.icmp.pending.insert(key, Pending { sent: cx.now(), ret, timer }) {
+			cx.timer_del(previous.timer);
+		}
+
+		let [i0, i1] = ident.to_be_bytes();
+		let [s0, s1] = seq.to_be_bytes();
+
+		let csum = pseudo_checksum(src, dst, size_of::<Header>() + payload.len());
+
+		self.write(cx, proto, src, dst, Params::default(), |buf| write_echo(buf, csum, ty, [i0, i1, s0, s1], &payload));
+	}
+
+	pub(crate) fn recv_icmp_v4(&mut self, cx: CX![], addr: IpAddr, info: Info, buf: Slice) -> Result {
+		if buf.len() < size_of::<Header>() {
+			warn!("ICMP message too short (got {} bytes)", buf.len());
+			return Err(());
+		}
+
+		if Checksum::of(&buf).end() != [0, 0] {
+			warn!("ICMP message has invalid checksum");
+			return Err(());
+		}
+
+		let header: &Header = buf.split();
+
+		match (header.ty, header.code) {
+			(V4_ECHO_REQUEST, 0) => {
+				// The reply is sent from the address the request was sent to, with the same Differentiated Services code point.
+				let params = Params { tos: ToS::new(ECN::NotECT, info.tos.ds()), ..Params::default() };
+				self.write(cx, Protocol::Icmp, info.dst, addr, params, |b| write_echo(b, Checksum::default(), V4_ECHO_REPLY, header.data, &buf));
+
+				Ok(())
+			}
+			(V4_ECHO_REPLY, 0) => self.recv_echo_reply(cx, addr, header.data),
+			(ty, code) => match Kind::v4(ty, code, header.data) {
+				Some(kind) => self.recv_report(cx, addr, kind, &buf),
+				None => Err(debug!("Unimplemented ICMP message type {ty} (code {code})")),
+			},
+		}
+	}
+
+	/// Returns the round-trip time of the echo request answered by a reply.
+	fn recv_echo_reply(&mut self, cx: CX![], addr: IpAddr, data: [u8; 4]) -> Result {
+		let ident = u16::from_be_bytes([data[0], data[1]]);
+		let seq = u16::from_be_bytes([data[2], data[3]]);
+
+		let Some(Pending { sent, ret, timer }) = self.icmp.pending.remove(&(addr, ident, seq)) else {
+			debug!("Unexpected echo reply {seq} from {addr}");
+			return Err(());
+		};
+
+		cx.timer_del(timer);
+		ret.ret(cx.now() - sent);
+
+		Ok(())
+	}
+
+	/// Handles an error message quoting a packet sent by the interface.
+	fn recv_report(&mut self, cx: CX![], from: IpAddr, kind: Kind, quote: &[u8]) -> Result {
+		let Some(quoted) = Quoted::parse(quote) else {
+			warn!("{kind:?} message from {from} is truncated");
+			return Err(());
+		};
+
+		if !self.ip.addrs.is_local(quoted.src.addr) {
+			warn!("{kind:?} message from {from} quotes a packet from {}", quoted.src.addr);
+			return Err(());
+		}
+
+		debug!("{kind:?} reported by {from} for packet from {} to {}", quoted.src, quoted.dst);
+
+		// An echo request which caused an error is not answered.
+		if let Some((ident, seq)) = quoted_echo(quote) {
+			if let Some(pending) = self.icmp.pending.remove(&(quoted.dst.addr, ident, seq)) {
+				cx.timer_del(pending.timer);
+			}
+		}
+
+		if quoted.proto == u8::from(Protocol::Udp) {
+			self.udp.report(Report { from, kind, quoted });
+		}
+
+		match kind {
+			Kind::Unreachable(Unreachable::FragmentationNeeded(mtu)) => {
+				let mtu = match mtu as usize {
+					// Routers which predate RFC 1191 do not report the MTU of the next hop.
+					0 => pmtu::plateau(u16::from_be_bytes([quote[2], quote[3]]) as usize),
+					mtu => mtu,
+				};
+
+				self.lower_path_mtu(cx, quoted.dst.addr, mtu)
+			}
+			Kind::PacketTooBig(mtu) => self.lower_path_mtu(cx, quoted.dst.addr, mtu as usize),
+			_ => Ok(()),
+		}
+	}
+
+	pub(crate) fn recv_icmp_v6(&mut self, cx: CX![], addr: IpAddr, info: Info, buf: Slice) -> Result {
+		let len: u32 = buf.len().try_into().map_err(|_| warn!("ICMPv6 message too big ({} bytes)", buf.len()))?;
+
+		if buf.len() < size_of::<Header>() {
+			warn!("ICMPv6 message too short (got {} bytes)", buf.len());
+			return Err(());
+		}
+
+		let mut csum = ip::pseudo_checksum(Protocol::Icmpv6, addr, info.dst);
+
+		csum.push(&len.to_be_bytes());
+		csum.push(&buf);
+
+		if csum.end() != [0, 0] {
+			warn!("ICMPv6 message has invalid checksum");
+			return Err(());
+		}
+
+		let header: &Header = buf.split();
+
+		match (header.ty, header.code) {
+			(V6_ECHO_REQUEST, 0) => {
+				// The reply is sent from the address the request was sent to, with the same Differentiated Services code point.
+				let params = Params { tos: ToS::new(ECN::NotECT, info.tos.ds()), ..Params::default() };
+				let csum = pseudo_checksum(info.dst, addr, len as usize);
+
+				self.write(cx, Protocol::Icmpv6, info.dst, addr, params, |b| write_echo(b, csum, V6_ECHO_REPLY, header.data, &buf));
+
+				Ok(())
+			}
+			(V6_ECHO_REPLY, 0) => self.recv_echo_reply(cx, addr, header.data),
+			(ty, code) => match Kind::v6(ty, code, header.data) {
+				Some(kind) => self.recv_report(cx, addr, kind, &buf),
+				None => Err(debug!("Unimplemented ICMPv6 message type {ty} (code {code})")),
+			},
+		}
+	}
+
+	/// Lowers the estimated MTU of the path to an address, as reported by an ICMP message.
+	fn lower_path_mtu(&mut self, cx: CX![], addr: IpAddr, mtu: usize) -> Result {
+		// RFC 8201 requires messages reporting an MTU below the minimum to be discarded.
+		if mtu < pmtu::min(addr) {
+			warn!("Discarding report of path MTU {mtu} to {addr}, which is below the minimum");
+			return Err(());
+		}
+
+		if self.pmtu.lower(cx.now(), addr, mtu) {
+			info!("Path MTU to {addr} lowered to {mtu}");
+		}
+
+		Ok(())
+	}
+}
+
+#[test]
+fn test_echo() {
+	use crate::ip;
+	use crate::link;
+
+	let (mut s, a, sent) = link::capture();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+
+	let mut request = ip::packet(Protocol::Icmp, endpoint("10.0.0.2", 7), endpoint("10.0.0.1", 0), [&[V4_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 1][..], b"hello"].concat());
+
+	// Expedited Forwarding, with the congestion experienced mark.
+	request[1] = 46 << 2 | 0b11;
+	ip::checksum(&mut request);
+
+	ip::deliver(&mut s, &a, &request);
+
+	// The reply echoes the identifier, sequence number and payload, with the code point of the request but no congestion mark.
+	let reply = sent.borrow_mut().pop().unwrap();
+	let expected = ip::packet(Protocol::Icmp, endpoint("10.0.0.1", 0), endpoint("10.0.0.2", 7), [&[V4_ECHO_REPLY, 0, 0, 0, 0, 0, 0, 1][..], b"hello"].concat());
+
+	assert_eq!(reply[1], 46 << 2);
+	assert_eq!((&reply[12..20], &reply[20..]), (&expected[12..20], &expected[20..]));
+
+	// Requests with an invalid checksum are not answered.
+	request[22] ^= 1;
+	ip::deliver(&mut s, &a, &request);
+
+	assert!(sent.borrow().is_empty());
+}
+
+#[test]
+fn test_ping() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::link;
+
+	let (mut s, a, b) = link::connect();
+
+	let rtts = Rc::new(RefCell::new(Vec::new()));
+
+	let ping = |s: &mut stakker::Stakker, dst: &str, seq| {
+		let rtts = rtts.clone();
+		let ret = Ret::new(move |rtt: Option<Duration>| rtts.borrow_mut().push(rtt));
+
+		a.query(s, |n, cx| n.ping(cx, dst.parse().unwrap(), 1, seq, b"ping".to_vec(), ret));
+	};
+
+	// The round-trip time is the time between sending the request and receiving the reply.
+	ping(&mut s, "10.0.0.2", 1);
+
+	let now = s.now() + Duration::from_millis(50);
+	s.run(now, false);
+	link::run(&mut s);
+
+	assert_eq!(rtts.borrow_mut().pop(), Some(Some(Duration::from_millis(50))));
+
+	// Requests to which no reply is received are abandoned.
+	ping(&mut s, "10.0.0.3", 2);
+	link::run(&mut s);
+
+	assert!(rtts.borrow().is_empty());
+
+	let now = s.now() + ECHO_TIMEOUT + Duration::from_secs(1);
+	s.run(now, false);
+
+	assert_eq!(rtts.borrow_mut().pop(), Some(None));
+
+	// Requests which cause an error are abandoned immediately.
+	b.query(&mut s, |n, cx| n.load_filter(cx, "input reject proto icmp")).unwrap().unwrap();
+
+	ping(&mut s, "10.0.0.2", 3);
+	link::run(&mut s);
+
+	assert_eq!(rtts.borrow_mut().pop(), Some(None));
+	assert!(a.query(&mut s, |n, _| n.icmp.pending.is_empty()).unwrap());
+}
+
+#[test]
+fn test_parse() {
+	use crate::ip;
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let (src, dst) = (endpoint("10.0.0.1", 5000), endpoint("192.0.2.1", 53));
+
+	// Only the IP header and the ports of the transport header need be quoted.
+	let udp = ip::packet(Protocol::Udp, src, dst, vec![0; 12]);
+
+	assert_eq!(Quoted::parse(&udp[..24]), Some(Quoted { proto: 17, src, dst }));
+	assert_eq!(Quoted::parse(&udp[..22]), None);
+	assert_eq!(Quoted::parse(&udp[..19]), None);
+
+	// Echo requests are quoted with their identifier in place of the source port.
+	let echo = ip::packet(Protocol::Icmp, endpoint("10.0.0.1", 7), endpoint("192.0.2.1", 0), vec![V4_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 9]);
+
+	assert_eq!(Quoted::parse(&echo), Some(Quoted { proto: 1, src: endpoint("10.0.0.1", 7), dst: endpoint("192.0.2.1", 0) }));
+	assert_eq!(quoted_echo(&echo), Some((7, 9)));
+	assert_eq!(quoted_echo(&udp), None);
+
+	// The transport header follows any options.
+	let mut options = [&udp[..20], &[1, 1, 1, 0], &udp[20..]].concat();
+	options[0] = 0x46;
+
+	assert_eq!(Quoted::parse(&options), Some(Quoted { proto: 17, src, dst }));
+
+	let kinds = [
+		((V4_UNREACHABLE, 1), Kind::Unreachable(Unreachable::Host)),
+		((V4_UNREACHABLE, V4_PORT_UNREACHABLE), Kind::Unreachable(Unreachable::Port)),
+		((V4_UNREACHABLE, V4_FRAGMENTATION_NEEDED), Kind::Unreachable(Unreachable::FragmentationNeeded(1400))),
+		((V4_UNREACHABLE, V4_ADMIN_PROHIBITED), Kind::Unreachable(Unreachable::Prohibited)),
+		((V4_UNREACHABLE, 15), Kind::Unreachable(Unreachable::Other(15))),
+		((V4_TIME_EXCEEDED, 1), Kind::TimeExceeded { reassembly: true }),
+		((V4_PARAMETER_PROBLEM, 0), Kind::ParameterProblem { pointer: 5 }),
+	];
+
+	for ((ty, code), kind) in kinds {
+		assert_eq!(Kind::v4(ty, code, [5, 0, 0x05, 0x78]), Some(kind));
+	}
+
+	assert_eq!(Kind::v4(V4_ECHO_REQUEST, 0, [0; 4]), None);
+}
+
+#[test]
+fn test_echo_v6() {
+	use crate::ip;
+	use crate::link;
+
+	let (mut s, a, sent) = link::capture();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+
+	// The reply echoes the request, and its checksum covers the pseudo-header.
+	let request = ip::packet(Protocol::Icmpv6, endpoint("fd00::2", 7), endpoint("fd00::1", 0), [&[V6_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 1][..], b"hello"].concat());
+	ip::deliver(&mut s, &a, &request);
+
+	let reply = sent.borrow_mut().pop().unwrap();
+	let expected = ip::packet(Protocol::Icmpv6, endpoint("fd00::1", 0), endpoint("fd00::2", 7), [&[V6_ECHO_REPLY, 0, 0, 0, 0, 0, 0, 1][..], b"hello"].concat());
+
+	assert_eq!(&reply[8..], &expected[8..]);
+
+	// Packets with an invalid checksum are discarded.
+	let mut corrupt = request.clone();
+	corrupt[8] ^= 1;
+	ip::deliver(&mut s, &a, &corrupt);
+
+	assert!(sent.borrow().is_empty());
+}
+
+#[test]
+fn test_ping_v6() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::link;
+
+	let (mut s, a, _b) = link::connect();
+
+	let rtt = Rc::new(RefCell::new(None));
+	let ret = rtt.clone();
+
+	a.query(&mut s, |n, cx| n.ping(cx, "fd00::2".parse().unwrap(), 1, 1, b"ping".to_vec(), Ret::new(move |v| *ret.borrow_mut() = Some(v))));
+
+	let now = s.now() + Duration::from_millis(20);
+	s.run(now, false);
+	link::run(&mut s);
+
+	assert_eq!(rtt.borrow_mut().take(), Some(Some(Duration::from_millis(20))));
+}
+
+#[test]
+fn test_packet_too_big() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::link;
+
+	// An echo request exceeding the MTU of the next hop is answered with Packet Too Big, which lowers the path MTU.
+	let (mut s, a, _r, _c) = link::router(1280);
+
+	let rtt = Rc::new(RefCell::new(None));
+	let ret = rtt.clone();
+
+	a.query(&mut s, |n, cx| n.ping(cx, "fd01::1".parse().unwrap(), 1, 1, vec![0; 1400], Ret::new(move |v| *ret.borrow_mut() = Some(v))));
+	link::run(&mut s);
+
+	assert_eq!(rtt.borrow_mut().take(), Some(None));
+	assert_eq!(a.query(&mut s, |n, cx| n.path_mtu(cx, "fd01::1".parse().unwrap())), Some(1280));
+}
+
+#[test]
+fn test_packet_too_big_report() {
+	use crate::ip;
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let dst = endpoint("fd01::1", 53);
+
+	let too_big = |mtu: u32, src: &str| {
+		let quoted = ip::packet(Protocol::Udp, endpoint(src, 5000), dst, vec![0; 1400]);
+		let message = [&[V6_PACKET_TOO_BIG, 0, 0, 0][..], &mtu.to_be_bytes(), &quoted[..1232]].concat();
+
+		ip::packet(Protocol::Icmpv6, endpoint("fd00::fe", 0), endpoint("fd00::1", 0), message)
+	};
+
+	let mtu = |s: &mut stakker::Stakker| a.query(s, |n, cx| n.path_mtu(cx, dst.addr)).unwrap();
+
+	// Reports below the minimum MTU, or quoting a packet which was not sent by the interface, are discarded.
+	ip::deliver(&mut s, &a, &too_big(1000, "fd00::1"));
+	ip::deliver(&mut s, &a, &too_big(1300, "fd00::2"));
+
+	assert_eq!(mtu(&mut s), 1500);
+
+	ip::deliver(&mut s, &a, &too_big(1300, "fd00::1"));
+
+	assert_eq!(mtu(&mut s), 1300);
+}
+
+#[test]
+fn test_parse_v6() {
+	use crate::ip;
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let (src, dst) = (endpoint("fd00::1", 40000), endpoint("fd01::1", 80));
+
+	let tcp = ip::packet(Protocol::Tcp, src, dst, vec![0; 20]);
+
+	assert_eq!(Quoted::parse(&tcp[..44]), Some(Quoted { proto: 6, src, dst }));
+	assert_eq!(Quoted::parse(&tcp[..42]), None);
+	assert_eq!(Quoted::parse(&tcp[..39]), None);
+
+	let echo = ip::packet(Protocol::Icmpv6, endpoint("fd00::1", 7), endpoint("fd01::1", 0), vec![V6_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 9]);
+	assert_eq!(quoted_echo(&echo), Some((7, 9)));
+
+	let kinds = [
+		((V6_UNREACHABLE, 0), Kind::Unreachable(Unreachable::Net)),
+		((V6_UNREACHABLE, 3), Kind::Unreachable(Unreachable::Host)),
+		((V6_UNREACHABLE, V6_ADMIN_PROHIBITED), Kind::Unreachable(Unreachable::Prohibited)),
+		((V6_UNREACHABLE, V6_PORT_UNREACHABLE), Kind::Unreachable(Unreachable::Port)),
+		((V6_UNREACHABLE, 6), Kind::Unreachable(Unreachable::Other(6))),
+		((V6_PACKET_TOO_BIG, 0), Kind::PacketTooBig(1400)),
+		((V6_TIME_EXCEEDED, 0), Kind::TimeExceeded { reassembly: false }),
+		((V6_PARAMETER_PROBLEM, V6_UNRECOGNISED_NEXT_HEADER), Kind::ParameterProblem { pointer: 1400 }),
+	];
+
+	for ((ty, code), kind) in kinds {
+		assert_eq!(Kind::v6(ty, code, 1400u32.to_be_bytes()), Some(kind));
+	}
+
+	assert_eq!(Kind::v6(V6_ECHO_REPLY, 0, [0; 4]), None);
+}
+
+#[test]
+fn test_errors() {
+	use crate::ip;
+	use crate::link;
+
+	let (mut s, a, sent) = link::capture();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let udp = |src: &str, dst: &str| ip::packet(Protocol::Udp, endpoint(src, 5000), endpoint(dst, 9), vec![0; 12]);
+
+	// Returns the type, code and data of the error message sent in reply to a packet, and its quote.
+	let reply = |s: &mut stakker::Stakker, packet: &[u8]| {
+		ip::deliver(s, &a, packet);
+
+		let reply = sent.borrow_mut().pop()?;
+		assert!(sent.borrow().is_empty());
+
+		let l4 = if reply[0] >> 4 == 4 { 20 } else { 40 };
+
+		// The checksums of the message cover the quote, which is complete for IPv6.
+		match reply[0] >> 4 {
+			4 => assert_eq!(Checksum::of(&reply[l4..]).end(), [0, 0]),
+			_ => {
+				let mut expected = reply.clone();
+				ip::checksum(&mut expected);
+				assert_eq!(reply, expected);
+			}
+		}
+
+		Some((reply[l4], reply[l4 + 1], [reply[l4 + 4], reply[l4 + 5], reply[l4 + 6], reply[l4 + 7]], reply[l4 + 8..].to_vec()))
+	};
+
+	// Packets to unbound ports are answered with Port Unreachable, quoting the header and 8 octets of the payload for IPv4, and as much of
+	// the packet as fits within the minimum MTU for IPv6.
+	let packet = udp("10.0.0.2", "10.0.0.1");
+	assert_eq!(reply(&mut s, &packet), Some((V4_UNREACHABLE, V4_PORT_UNREACHABLE, [0; 4], packet[..28].to_vec())));
+
+	let packet = udp("fd00::2", "fd00::1");
+	assert_eq!(reply(&mut s, &packet), Some((V6_UNREACHABLE, V6_PORT_UNREACHABLE, [0; 4], packet.clone())));
+
+	// Packets of unknown protocols are answered with Protocol Unreachable for IPv4, and Parameter Problem pointing at the Next Header field
+	// for IPv6.
+	let packet = ip::packet(Protocol::Unknown(253), endpoint("10.0.0.2", 0), endpoint("10.0.0.1", 0), vec![0; 8]);
+	assert_eq!(reply(&mut s, &packet), Some((V4_UNREACHABLE, V4_PROTOCOL_UNREACHABLE, [0; 4], packet.clone())));
+
+	let packet = ip::packet(Protocol::Unknown(253), endpoint("fd00::2", 0), endpoint("fd00::1", 0), vec![0; 8]);
+	assert_eq!(reply(&mut s, &packet), Some((V6_PARAMETER_PROBLEM, V6_UNRECOGNISED_NEXT_HEADER, [0, 0, 0, 6], packet.clone())));
+
+	// Errors are not reported about packets which do not identify a single source host.
+	a.query(&mut s, |n, cx| {
+		for prefix in ["224.0.0.0/4", "255.255.255.255/32", "ff00::/8"] {
+			n.add_prefix(cx, prefix.parse().unwrap()).unwrap();
+		}
+	});
+
+	for (src, dst) in [("10.0.0.2", "224.0.0.251"), ("10.0.0.2", "255.255.255.255"), ("0.0.0.0", "10.0.0.1"), ("fd00::2", "ff02::1"), ("::", "fd00::1")] {
+		assert_eq!(reply(&mut s, &udp(src, dst)), None, "{src} to {dst}");
+	}
+
+	// Errors are reported about echo requests, but not about other error messages.
+	a.query(&mut s, |n, cx| n.load_filter(cx, "input reject proto icmp\ninput reject proto icmpv6\nforward reject")).unwrap().unwrap();
+
+	for (src, dst, proto, request, prohibited, error) in [
+		("10.0.0.2", "10.0.0.1", Protocol::Icmp, V4_ECHO_REQUEST, (V4_UNREACHABLE, V4_ADMIN_PROHIBITED), V4_UNREACHABLE),
+		("fd00::2", "fd00::1", Protocol::Icmpv6, V6_ECHO_REQUEST, (V6_UNREACHABLE, V6_ADMIN_PROHIBITED), V6_UNREACHABLE),
+	] {
+		let echo = ip::packet(proto, endpoint(src, 1), endpoint(dst, 0), vec![request, 0, 0, 0, 0, 0, 0, 1]);
+		assert_eq!(reply(&mut s, &echo).map(|(ty, code, ..)| (ty, code)), Some(prohibited));
+
+		let quoted = udp(dst, src);
+		let message = ip::packet(proto, endpoint(src, 0), endpoint(dst, 0), [&[error, 3, 0, 0, 0, 0, 0, 0][..], &quoted].concat());
+		assert_eq!(reply(&mut s, &message), None);
+	}
+
+	// Errors are reported about the first fragment of a forwarded packet, but not about the others.
+	a.query(&mut s, |n, cx| n.set_forwarding(cx, true));
+
+	let mut fragment = udp("10.0.0.2", "192.0.2.1");
+	fragment[6..8].copy_from_slice(&[0x20, 0]);
+	ip::checksum(&mut fragment);
+
+	assert_eq!(reply(&mut s, &fragment).map(|(ty, code, ..)| (ty, code)), Some((V4_UNREACHABLE, V4_ADMIN_PROHIBITED)));
+
+	fragment[6..8].copy_from_slice(&[0, 0x10]);
+	ip::checksum(&mut fragment);
+
+	assert_eq!(reply(&mut s, &fragment), None);
+}
+
+#[test]
+fn test_bucket() {
+	use crate::ip;
+	use crate::link;
+
+	let now = Instant::now();
+	let mut bucket = Bucket::default();
+
+	// A burst may be sent at once, after which tokens are replenished at the interval.
+	assert!((0..ERROR_BURST).all(|_| bucket.take(now)));
+	assert!(!bucket.take(now));
+	assert!(!bucket.take(now + ERROR_INTERVAL / 2));
+	assert!(bucket.take(now + ERROR_INTERVAL));
+	assert!(!bucket.take(now + ERROR_INTERVAL));
+	assert!(bucket.take(now + ERROR_INTERVAL * 3) && bucket.take(now + ERROR_INTERVAL * 3));
+	assert!(!bucket.take(now + ERROR_INTERVAL * 3));
+
+	// The bucket holds no more than a burst, however long it is idle.
+	let later = now + Duration::from_secs(60);
+
+	assert!((0..ERROR_BURST).all(|_| bucket.take(later)));
+	assert!(!bucket.take(later));
+
+	// The limits of ICMPv4 and ICMPv6 are separate.
+	let (mut s, a, sent) = link::capture();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+
+	for (src, dst) in [("10.0.0.2", "10.0.0.1"), ("fd00::2", "fd00::1")] {
+		for _ in 0..ERROR_BURST + 5 {
+			ip::deliver(&mut s, &a, &ip::packet(Protocol::Udp, endpoint(src, 5000), endpoint(dst, 9), vec![0; 8]));
+		}
+
+		assert_eq!(sent.borrow_mut().drain(..).count(), ERROR_BURST as usize);
+	}
+}
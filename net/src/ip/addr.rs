@@ -0,0 +1,378 @@
+//! Interface addresses and source address selection.
+
+use core::fmt::{Debug, Display};
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use core::str::FromStr;
+
+use log::warn;
+use stakker::CX;
+use utils::error::*;
+
+/// An address with a prefix length.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Cidr {
+	addr: IpAddr,
+	len: u8,
+}
+
+impl Cidr {
+	/// The prefix containing every IPv4 address.
+	pub const ALL_V4: Self = Self { addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED), len: 0 };
+	/// The prefix containing every IPv6 address.
+	pub const ALL_V6: Self = Self { addr: IpAddr::V6(Ipv6Addr::UNSPECIFIED), len: 0 };
+
+	/// Creates an address with a prefix length, failing if the length exceeds the length of the address.
+	pub fn new(addr: IpAddr, len: u8) -> Result<Self> {
+		if len > Self::host(addr).len {
+			warn!("Prefix length {len} exceeds the length of {addr}");
+			return Err(());
+		}
+
+		Ok(Self { addr, len })
+	}
+
+	/// Creates a prefix containing only a single address.
+	pub fn host(addr: IpAddr) -> Self {
+		let len = match addr {
+			IpAddr::V4(_) => 32,
+			IpAddr::V6(_) => 128,
+		};
+
+		Self { addr, len }
+	}
+
+	pub fn addr(&self) -> IpAddr {
+		self.addr
+	}
+
+	pub fn prefix_len(&self) -> u8 {
+		self.len
+	}
+
+	/// Returns the prefix with the bits of the address beyond the prefix length cleared, such as `10.0.0.0/8` for `10.0.0.5/8`.
+	pub fn network(&self) -> Self {
+		let addr = match self.addr {
+			IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0)).into()),
+			IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0)).into()),
+		};
+
+		Self { addr, len: self.len }
+	}
+
+	/// Returns whether an address is within the prefix.
+	pub fn contains(&self, addr: IpAddr) -> bool {
+		common_len(self.addr, addr).is_some_and(|len| len >= self.len)
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = ();
+
+	/// Parses an address with an optional prefix length, such as `10.0.0.0/8`. An address without a prefix length is a single host.
+	fn from_str(s: &str) -> Result<Self> {
+		let (addr, len) = s.split_once('/').map_or((s, None), |(addr, len)| (addr, Some(len)));
+		let addr: IpAddr = addr.parse().map_err(|_| warn!("Invalid address {addr}"))?;
+
+		match len {
+			Some(len) => Self::new(addr, len.parse().map_err(|_| warn!("Invalid prefix length {len}"))?),
+			None => Ok(Self::host(addr)),
+		}
+	}
+}
+
+impl Debug for Cidr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		<Self as Display>::fmt(self, f)
+	}
+}
+
+impl Display for Cidr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_fmt(format_args!("{}/{}", self.addr, self.len))
+	}
+}
+
+/// Returns the length of the longest common prefix of two addresses, or `None` if they are of different families.
+fn common_len(a: IpAddr, b: IpAddr) -> Option<u8> {
+	match (a, b) {
+		(IpAddr::V4(a), IpAddr::V4(b)) => Some((u32::from(a) ^ u32::from(b)).leading_zeros() as u8),
+		(IpAddr::V6(a), IpAddr::V6(b)) => Some((u128::from(a) ^ u128::from(b)).leading_zeros() as u8),
+		_ => None,
+	}
+}
+
+/// How an entry of the address set is used.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Kind {
+	/// A single address, with the prefix length of its subnet. The address may be used as a source address.
+	Address,
+	/// A whole prefix, of which every address is local. The addresses are not used as source addresses.
+	Prefix,
+}
+
+/// The default policy table of RFC 6724, mapping prefixes to labels.
+const POLICY: [(u128, u8, u8); 9] = [
+	(0x0000_0000_0000_0000_0000_0000_0000_0001, 128, 0),
+	(0x0000_0000_0000_0000_0000_0000_0000_0000, 0, 1),
+	(0x0000_0000_0000_0000_0000_ffff_0000_0000, 96, 4),
+	(0x2002_0000_0000_0000_0000_0000_0000_0000, 16, 2),
+	(0x2001_0000_0000_0000_0000_0000_0000_0000, 32, 5),
+	(0xfc00_0000_0000_0000_0000_0000_0000_0000, 7, 13),
+	(0x0000_0000_0000_0000_0000_0000_0000_0000, 96, 3),
+	(0xfec0_0000_0000_0000_0000_0000_0000_0000, 10, 11),
+	(0x3ffe_0000_0000_0000_0000_0000_0000_0000, 16, 12),
+];
+
+/// Returns the label of an address in the default policy table.
+fn label(addr: Ipv6Addr) -> u8 {
+	let addr = u128::from(addr);
+
+	POLICY
+		.iter()
+		.filter(|(prefix, len, _)| (addr ^ prefix).leading_zeros() >= *len as u32)
+		.max_by_key(|(_, len, _)| *len)
+		.map_or(1, |(_, _, label)| *label)
+}
+
+/// Returns the scope of an address, as defined by RFC 6724.
+fn scope(addr: Ipv6Addr) -> u8 {
+	if addr.is_multicast() {
+		addr.octets()[1] & 0xf
+	} else if addr.is_loopback() || addr.segments()[0] & 0xffc0 == 0xfe80 {
+		// The loopback and link-local addresses have link-local scope.
+		0x2
+	} else {
+		0xe
+	}
+}
+
+/// The set of addresses and prefixes assigned to an interface.
+#[derive(Default)]
+pub struct Addresses {
+	entries: Vec<(Cidr, Kind)>,
+}
+
+impl Addresses {
+	/// Adds an entry to the set, failing if it is already present.
+	pub fn add(&mut self, cidr: Cidr, kind: Kind) -> Result {
+		if self.entries.iter().any(|(c, _)| c.addr == cidr.addr) {
+			warn!("Address {} is already assigned", cidr.addr);
+			return Err(());
+		}
+
+		self.entries.push((cidr, kind));
+
+		Ok(())
+	}
+
+	/// Removes the entry with the given address from the set, failing if it is not present.
+	pub fn remove(&mut self, addr: IpAddr) -> Result {
+		let idx = self.entries.iter().position(|(c, _)| c.addr == addr).ok_or_else(|| warn!("Address {addr} is not assigned"))?;
+		self.entries.remove(idx);
+
+		Ok(())
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &(Cidr, Kind)> {
+		self.entries.iter()
+	}
+
+	/// Returns whether packets with a destination address are delivered locally.
+	pub fn is_local(&self, addr: IpAddr) -> bool {
+		self.entries.iter().any(|(c, kind)| match kind {
+			Kind::Address => c.addr == addr,
+			Kind::Prefix => c.contains(addr),
+		})
+	}
+
+	/// Selects the source address of packets sent to a destination address. IPv6 addresses are selected as specified by RFC 6724. IPv4
+	/// addresses are selected by preferring the address of the subnet containing the destination, then the longest matching prefix.
+	pub fn source(&self, dst: IpAddr) -> Option<IpAddr> {
+		// Candidates are reversed so that the earliest assigned address is selected when several are equally preferred.
+		let candidates = self.entries.iter().rev().filter(|(c, kind)| *kind == Kind::Address && c.addr.is_ipv4() == dst.is_ipv4());
+
+		match dst {
+			IpAddr::V4(_) => candidates.max_by_key(|(c, _)| (c.addr == dst, c.contains(dst), common_len(c.addr, dst))).map(|(c, _)| c.addr),
+			IpAddr::V6(d) => candidates
+				.filter_map(|(c, _)| match c.addr {
+					IpAddr::V6(addr) => Some((addr, c.len)),
+					IpAddr::V4(_) => None,
+				})
+				.max_by(|&(a, a_len), &(b, b_len)| {
+					// Rule 1: prefer the same address.
+					(a == d).cmp(&(b == d))
+						// Rule 2: prefer the smallest scope which is not smaller than the scope of the destination.
+						.then_with(|| {
+							let (sa, sb, sd) = (scope(a), scope(b), scope(d));
+							(sa >= sd).cmp(&(sb >= sd)).then(if sa >= sd { sb.cmp(&sa) } else { sa.cmp(&sb) })
+						})
+						// Rule 6: prefer a matching label.
+						.then_with(|| (label(a) == label(d)).cmp(&(label(b) == label(d))))
+						// Rule 8: prefer the longest matching prefix, up to the prefix length of the source subnet.
+						.then_with(|| {
+							let a = common_len(a.into(), dst).unwrap_or(0).min(a_len);
+							let b = common_len(b.into(), dst).unwrap_or(0).min(b_len);
+							a.cmp(&b)
+						})
+				})
+				.map(|(addr, _)| IpAddr::V6(addr)),
+		}
+	}
+}
+
+impl crate::Interface {
+	/// Assigns an address to the interface, with the prefix length of its subnet.
+	pub fn add_address(&mut self, _: CX![], cidr: Cidr) -> Result {
+		self.ip.addrs.add(cidr, Kind::Address)
+	}
+
+	/// Assigns a whole prefix to the interface, so that packets sent to any address within it are delivered locally.
+	pub fn add_prefix(&mut self, _: CX![], cidr: Cidr) -> Result {
+		self.ip.addrs.add(cidr, Kind::Prefix)
+	}
+
+	/// Removes an address or prefix from the interface.
+	pub fn remove_address(&mut self, _: CX![], addr: IpAddr) -> Result {
+		self.ip.addrs.remove(addr)
+	}
+
+	/// Returns the addresses and prefixes assigned to the interface.
+	pub fn addresses(&self) -> impl Iterator<Item = &(Cidr, Kind)> {
+		self.ip.addrs.iter()
+	}
+}
+
+#[cfg(test)]
+fn addresses(entries: &[(&str, Kind)]) -> Addresses {
+	let mut addrs = Addresses::default();
+
+	for (cidr, kind) in entries {
+		addrs.add(cidr.parse().unwrap(), *kind).unwrap();
+	}
+
+	addrs
+}
+
+#[test]
+fn test_cidr() {
+	let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+
+	assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+	assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+	assert!(!cidr.contains("::a00:1".parse().unwrap()));
+	assert_eq!(cidr.to_string(), "10.0.0.0/8");
+
+	assert_eq!("fd00::1".parse::<Cidr>().unwrap().prefix_len(), 128);
+	assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+	assert!("10.0.0.0/a".parse::<Cidr>().is_err());
+	assert!("10.0.0/8".parse::<Cidr>().is_err());
+
+	assert!(Cidr::ALL_V6.contains("2001:db8::1".parse().unwrap()));
+}
+
+#[test]
+fn test_local() {
+	let mut addrs = addresses(&[("10.0.0.1/24", Kind::Address), ("10.9.0.0/16", Kind::Prefix)]);
+
+	assert!(addrs.is_local("10.0.0.1".parse().unwrap()));
+	assert!(addrs.is_local("10.9.200.7".parse().unwrap()));
+	assert!(!addrs.is_local("10.0.0.2".parse().unwrap()));
+
+	// Addresses of a prefix are not used as source addresses.
+	assert_eq!(addrs.source("10.9.0.1".parse().unwrap()), Some("10.0.0.1".parse().unwrap()));
+
+	assert!(addrs.add("10.9.0.0/24".parse().unwrap(), Kind::Address).is_err());
+	assert!(addrs.remove("10.9.0.0".parse().unwrap()).is_ok());
+	assert!(addrs.remove("10.9.0.0".parse().unwrap()).is_err());
+	assert!(!addrs.is_local("10.9.200.7".parse().unwrap()));
+}
+
+#[test]
+fn test_source_v4() {
+	let addrs = addresses(&[("192.168.0.1/24", Kind::Address), ("10.0.0.1/8", Kind::Address), ("10.1.0.1/24", Kind::Address)]);
+	let source = |dst: &str| addrs.source(dst.parse().unwrap()).unwrap().to_string();
+
+	assert_eq!(source("10.1.0.1"), "10.1.0.1");
+	assert_eq!(source("10.1.0.9"), "10.1.0.1");
+	assert_eq!(source("10.2.0.9"), "10.0.0.1");
+	assert_eq!(source("192.168.0.9"), "192.168.0.1");
+
+	// Without a subnet containing the destination, the longest matching prefix is preferred.
+	assert_eq!(source("192.169.0.9"), "192.168.0.1");
+	assert_eq!(addrs.source("fd00::1".parse().unwrap()), None);
+}
+
+#[test]
+fn test_source_v6() {
+	let source = |entries: &[&str], dst: &str| {
+		let entries: Vec<_> = entries.iter().map(|cidr| (*cidr, Kind::Address)).collect();
+		addresses(&entries).source(dst.parse().unwrap()).unwrap().to_string()
+	};
+
+	// Rule 1: prefer the same address.
+	assert_eq!(source(&["fd00::1/64", "fd00::2/64"], "fd00::2"), "fd00::2");
+
+	// Rule 2: prefer the appropriate scope.
+	assert_eq!(source(&["2a00::1/64", "fe80::1/64"], "fe80::2"), "fe80::1");
+	assert_eq!(source(&["fe80::1/64", "2a00::1/64"], "2a01::1"), "2a00::1");
+
+	// Rule 6: prefer a matching label, over a longer matching prefix.
+	assert_eq!(source(&["fd00::1/64", "2000::1/64"], "fe00::1"), "2000::1");
+	assert_eq!(source(&["2000::1/64", "fd00::1/64"], "fc00::1"), "fd00::1");
+
+	// Rule 8: prefer the longest matching prefix, up to the prefix length of the subnet.
+	assert_eq!(source(&["fd00:1::1/64", "fd00:2::1/64"], "fd00:2::5"), "fd00:2::1");
+	assert_eq!(source(&["fd00:1::1/32", "fd00:1::8000:0:0:1/32"], "fd00:1::8000:0:0:9"), "fd00:1::1");
+	assert_eq!(source(&["fd00:1::1/128", "fd00:1::8000:0:0:1/128"], "fd00:1::8000:0:0:9"), "fd00:1::8000:0:0:1");
+}
+
+#[test]
+fn test_runtime() {
+	use core::net::Ipv4Addr;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use collections::bytes::Slice;
+	use stakker::Fwd;
+
+	use crate::{link, udp, Info, SocketAddr};
+
+	let (mut s, a, b) = link::connect();
+
+	let got = Rc::new(RefCell::new(Vec::new()));
+	let g = got.clone();
+
+	let _sock_a = a.query(&mut s, |n, cx| udp::Socket::bind(n, cx, 7, Fwd::new(move |(_, _, info): (SocketAddr, Slice, Info)| g.borrow_mut().push(info.dst)))).unwrap().unwrap();
+	let sock_b = b.query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+
+	let mut send = |s: &mut stakker::Stakker, addr: Ipv4Addr| {
+		sock_b.write(SocketAddr { addr: IpAddr::V4(addr), port: 7 }, |buf| {
+			buf.push(b"x");
+		});
+
+		link::run(s);
+		got.borrow_mut().pop()
+	};
+
+	let (addr, prefix) = (Ipv4Addr::new(10, 0, 0, 3), Ipv4Addr::new(10, 9, 1, 2));
+
+	assert_eq!(send(&mut s, addr), None);
+	assert_eq!(send(&mut s, prefix), None);
+
+	a.query(&mut s, |n, cx| {
+		n.add_address(cx, "10.0.0.3/24".parse().unwrap()).unwrap();
+		n.add_prefix(cx, "10.9.0.0/16".parse().unwrap()).unwrap();
+		assert_eq!(n.addresses().count(), 4);
+	});
+
+	assert_eq!(send(&mut s, addr), Some(IpAddr::V4(addr)));
+	assert_eq!(send(&mut s, prefix), Some(IpAddr::V4(prefix)));
+
+	a.query(&mut s, |n, cx| {
+		n.remove_address(cx, IpAddr::V4(addr)).unwrap();
+		assert!(n.remove_address(cx, IpAddr::V4(addr)).is_err());
+	});
+
+	assert_eq!(send(&mut s, addr), None);
+	assert_eq!(send(&mut s, prefix), Some(IpAddr::V4(prefix)));
+}
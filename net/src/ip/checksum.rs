@@ -15,10 +15,33 @@ impl Checksum {
 		csum
 	}
 
-	/// Create a new checksum calculation state initialised with a value.
+	/// Resumes the calculation of an existing checksum, so that it can be updated incrementally as specified by [RFC 1624].
+	///
+	/// [RFC 1624]: [https://datatracker.ietf.org/doc/html/rfc1624]
 	#[inline]
-	pub fn with(word: &[u8; 4]) -> Self {
-		Self { acc: u32::from_ne_bytes(*word) as u64 }
+	pub fn resume(csum: [u8; 2]) -> Self {
+		Self::of(&[!csum[0], !csum[1]])
+	}
+
+	/// Removes bytes from the checksum calculation. The bytes must have been covered by the checksum at the same alignment.
+	#[inline]
+	pub fn pull(&mut self, buffer: &[u8]) {
+		let (chunks, rem) = buffer.as_chunks();
+
+		for word in chunks {
+			self.push_chunk(&word.map(|b: u8| !b));
+		}
+
+		if rem.len() != 0 {
+			// The padding is complemented along with the bytes.
+			let mut buf = [0xff; 4];
+
+			for (b, r) in buf.iter_mut().zip(rem) {
+				*b = !r;
+			}
+
+			self.push_chunk(&buf);
+		}
 	}
 
 	/// Add bytes to the checksum calcuation.
@@ -0,0 +1,648 @@
+//! Connection tracking, which classifies packets by their relation to the flows previously accepted by the packet filter.
+//!
+//! A connection is recorded when the first packet of a flow is accepted, and expires once no packet of the flow has been seen for the
+//! timeout of its protocol and state. TCP connections, UDP flows and ICMP echo queries are identified by their ports, or by the query
+//! identifier in place of the port of the querying endpoint. Packets of other protocols are tracked by their addresses alone.
+//!
+//! Tracking is only done while a rule of the packet filter matches on the connection tracking state, so packets are not classified or
+//! recorded otherwise. Connections tracked before such rules are removed expire as usual.
+
+use core::fmt::Display;
+use core::net::IpAddr;
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use log::{debug, warn};
+use stakker::{FixedTimerKey, CX};
+use utils::error::*;
+
+use super::filter::{self, Action, Chain};
+use super::{Protocol, SocketAddr};
+use crate::icmp;
+
+/// The timeouts of TCP connections in each state.
+const TCP_SYN_SENT: Duration = Duration::from_secs(120);
+const TCP_SYN_RECEIVED: Duration = Duration::from_secs(60);
+const TCP_ESTABLISHED: Duration = Duration::from_secs(432000);
+const TCP_CLOSING: Duration = Duration::from_secs(120);
+const TCP_CLOSED: Duration = Duration::from_secs(10);
+/// The timeout of UDP flows to which no reply has been seen.
+const UDP_UNREPLIED: Duration = Duration::from_secs(30);
+/// The timeout of UDP flows to which a reply has been seen.
+const UDP_REPLIED: Duration = Duration::from_secs(120);
+/// The timeout of ICMP echo queries.
+const ICMP: Duration = Duration::from_secs(30);
+/// The timeout of flows of other protocols.
+const OTHER: Duration = Duration::from_secs(600);
+
+/// The maximum number of tracked connections.
+const MAX_CONNECTIONS: usize = 16384;
+
+/// The TCP control flags.
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// The relation of a packet to the tracked connections.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+	/// The packet starts a connection, or belongs to one to which no reply has been seen.
+	New,
+	/// The packet belongs to a connection to which a reply has been seen, or is itself a reply.
+	Established,
+	/// The packet is an ICMP error message about a tracked connection.
+	Related,
+	/// The packet cannot be tracked, such as a truncated packet, a TCP segment other than a SYN which belongs to no connection, or an ICMP
+	/// error message about an unknown connection.
+	Invalid,
+	/// The packet is a fragment other than the first, which does not carry the transport header, so is not tracked.
+	Untracked,
+}
+
+impl State {
+	const ALL: [Self; 5] = [Self::New, Self::Established, Self::Related, Self::Invalid, Self::Untracked];
+
+	fn name(self) -> &'static str {
+		match self {
+			Self::New => "new",
+			Self::Established => "established",
+			Self::Related => "related",
+			Self::Invalid => "invalid",
+			Self::Untracked => "untracked",
+		}
+	}
+}
+
+impl FromStr for State {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::ALL.into_iter().find(|state| state.name() == s).ok_or_else(|| warn!("Unknown connection state {s}"))
+	}
+}
+
+impl Display for State {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// The state of a tracked TCP connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tcp {
+	/// A SYN segment has been seen from the initiator.
+	SynSent,
+	/// A SYN-ACK segment has been seen from the responder.
+	SynReceived,
+	/// The initiator has acknowledged the SYN of the responder.
+	Established,
+	/// A FIN segment has been seen.
+	Closing,
+	/// A RST segment has been seen.
+	Closed,
+}
+
+impl Tcp {
+	/// Advances the state with a segment sent by the initiator, or by the responder if `reply` is set.
+	fn next(self, flags: u8, reply: bool) -> Self {
+		if flags & TCP_RST != 0 {
+			return Self::Closed;
+		}
+
+		match self {
+			Self::SynSent if reply && flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK => Self::SynReceived,
+			Self::SynReceived if !reply && flags & (TCP_SYN | TCP_ACK) == TCP_ACK => Self::Established,
+			Self::SynReceived | Self::Established if flags & TCP_FIN != 0 => Self::Closing,
+			state => state,
+		}
+	}
+
+	fn timeout(self) -> Duration {
+		match self {
+			Self::SynSent => TCP_SYN_SENT,
+			Self::SynReceived => TCP_SYN_RECEIVED,
+			Self::Established => TCP_ESTABLISHED,
+			Self::Closing => TCP_CLOSING,
+			Self::Closed => TCP_CLOSED,
+		}
+	}
+}
+
+impl Display for Tcp {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::SynSent => "SYN_SENT",
+			Self::SynReceived => "SYN_RECV",
+			Self::Established => "ESTABLISHED",
+			Self::Closing => "CLOSING",
+			Self::Closed => "CLOSE",
+		})
+	}
+}
+
+/// The endpoints of one direction of a flow.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Tuple {
+	/// The protocol number.
+	pub proto: u8,
+	pub src: SocketAddr,
+	pub dst: SocketAddr,
+}
+
+impl Tuple {
+	/// Returns the tuple of the other direction of the flow.
+	fn reverse(self) -> Self {
+		Self { proto: self.proto, src: self.dst, dst: self.src }
+	}
+}
+
+/// A tracked connection.
+pub struct Connection {
+	/// The tuple of the direction of the first packet.
+	tuple: Tuple,
+	/// Whether a packet has been seen in the reply direction.
+	replied: bool,
+	/// The state of the connection, if it is a TCP connection.
+	tcp: Option<Tcp>,
+	/// The time after which the connection expires if it is not used.
+	timeout: Duration,
+	/// The time at which the connection was last used.
+	used: Instant,
+	/// The timer which expires the connection.
+	timer: FixedTimerKey,
+	/// The position of the connection in the order of use of unreplied connections, if no reply has been seen.
+	order: u64,
+}
+
+impl Connection {
+	/// Returns the tuple of the direction of the first packet of the connection.
+	pub fn tuple(&self) -> Tuple {
+		self.tuple
+	}
+
+	/// Returns whether a packet has been seen in the reply direction.
+	pub fn replied(&self) -> bool {
+		self.replied
+	}
+
+	/// Returns the state of the connection, if it is a TCP connection.
+	pub fn tcp(&self) -> Option<Tcp> {
+		self.tcp
+	}
+
+	/// Returns the time at which the connection expires, unless another packet is seen.
+	pub fn expires(&self) -> Instant {
+		self.used + self.timeout
+	}
+}
+
+impl Display for Connection {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let Tuple { proto, src, dst } = self.tuple;
+
+		match filter::protocol_name(proto) {
+			Some(name) => f.write_str(name)?,
+			None => f.write_fmt(format_args!("{proto}"))?,
+		}
+
+		if let Some(tcp) = self.tcp {
+			f.write_fmt(format_args!(" {tcp}"))?;
+		}
+
+		f.write_fmt(format_args!(" src={src} dst={dst}"))?;
+
+		if !self.replied {
+			f.write_str(" [UNREPLIED]")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// What a packet is, as far as tracking is concerned.
+#[derive(Clone, Copy)]
+enum Kind {
+	/// A TCP segment with its control flags.
+	Tcp(u8),
+	/// An ICMP error message, with the tuple of the packet it quotes.
+	Error(Tuple),
+	/// Any other packet.
+	Other,
+}
+
+/// A packet located within the tracked flows.
+#[derive(Clone, Copy)]
+pub(crate) struct Segment {
+	tuple: Tuple,
+	kind: Kind,
+}
+
+impl Segment {
+	/// Locates a packet with an IP payload, which begins with the transport header. Returns `None` if the packet is truncated.
+	pub fn of(proto: u8, src: IpAddr, dst: IpAddr, payload: &[u8]) -> Option<Self> {
+		let [sport, dport] = ports(proto, payload)?;
+		let tuple = Tuple { proto, src: SocketAddr { addr: src, port: sport }, dst: SocketAddr { addr: dst, port: dport } };
+
+		let kind = match (Protocol::from(proto), payload.first()) {
+			(Protocol::Tcp, _) => Kind::Tcp(*payload.get(13)?),
+			(Protocol::Icmp, Some(&ty)) if icmp::is_error_v4(ty) => Kind::Error(quoted(payload)?),
+			// Informational messages have the high-order bit of their type set.
+			(Protocol::Icmpv6, Some(&ty)) if ty & 0x80 == 0 => Kind::Error(quoted(payload)?),
+			_ => Kind::Other,
+		};
+
+		Some(Self { tuple, kind })
+	}
+}
+
+/// Returns the ports of a packet, which are zero for protocols without ports. ICMP echo queries have the query identifier in place of the
+/// port of the querying endpoint.
+pub(crate) fn ports(proto: u8, payload: &[u8]) -> Option<[u16; 2]> {
+	let port = |at: usize| payload.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+	Some(match (Protocol::from(proto), payload.first()) {
+		(Protocol::Tcp | Protocol::Udp, _) => [port(0)?, port(2)?],
+		(Protocol::Icmp, Some(&icmp::V4_ECHO_REQUEST)) | (Protocol::Icmpv6, Some(&icmp::V6_ECHO_REQUEST)) => [port(4)?, 0],
+		(Protocol::Icmp, Some(&icmp::V4_ECHO_REPLY)) | (Protocol::Icmpv6, Some(&icmp::V6_ECHO_REPLY)) => [0, port(4)?],
+		_ => [0, 0],
+	})
+}
+
+/// Returns the tuple of the packet quoted by an ICMP error message.
+fn quoted(message: &[u8]) -> Option<Tuple> {
+	let icmp::Quoted { proto, src, dst } = icmp::Quoted::parse(message.get(8..)?)?;
+	Some(Tuple { proto, src, dst })
+}
+
+/// The tracked connections.
+#[derive(Default)]
+pub struct Table {
+	/// The connections, keyed by the tuple of the direction of their first packet.
+	connections: HashMap<Tuple, Connection>,
+	/// The connections to which no reply has been seen, from the least to the most recently used, keyed by their position.
+	unreplied: BTreeMap<u64, Tuple>,
+	/// The position of the next connection to be used.
+	order: u64,
+}
+
+impl Table {
+	/// Returns the tuple a connection is keyed by, and whether the tuple is of the reply direction of the connection.
+	fn find(&self, tuple: Tuple) -> Option<(Tuple, bool)> {
+		if self.connections.contains_key(&tuple) {
+			Some((tuple, false))
+		} else {
+			let reverse = tuple.reverse();
+			self.connections.contains_key(&reverse).then_some((reverse, true))
+		}
+	}
+
+	/// Classifies a packet by its relation to the tracked connections, without recording it.
+	pub(crate) fn state(&self, segment: Option<&Segment>) -> State {
+		let Some(segment) = segment else { return State::Invalid };
+
+		if let Kind::Error(tuple) = segment.kind {
+			return match self.find(tuple) {
+				Some(_) => State::Related,
+				None => State::Invalid,
+			};
+		}
+
+		match self.find(segment.tuple) {
+			Some((_, true)) => State::Established,
+			Some((tuple, false)) if self.connections[&tuple].replied => State::Established,
+			Some(_) => State::New,
+			// Only a SYN segment may start a TCP connection.
+			None if matches!(segment.kind, Kind::Tcp(flags) if flags & (TCP_SYN | TCP_ACK | TCP_RST) != TCP_SYN) => State::Invalid,
+			None => State::New,
+		}
+	}
+
+	/// Records a new connection.
+	fn insert(&mut self, mut conn: Connection) {
+		conn.order = self.order;
+		self.order += 1;
+
+		self.unreplied.insert(conn.order, conn.tuple);
+		self.connections.insert(conn.tuple, conn);
+	}
+
+	/// Removes a connection, returning it so that its timer can be cancelled.
+	fn remove(&mut self, tuple: &Tuple) -> Option<Connection> {
+		let conn = self.connections.remove(tuple)?;
+
+		if !conn.replied {
+			self.unreplied.remove(&conn.order);
+		}
+
+		Some(conn)
+	}
+
+	/// Removes the least recently used connection to which no reply has been seen, making room for a new connection. Returns `None` if a
+	/// reply has been seen to every connection.
+	fn early_drop(&mut self) -> Option<Connection> {
+		let (_, tuple) = self.unreplied.pop_first()?;
+		let conn = self.connections.remove(&tuple).unwrap();
+
+		debug!("Dropping connection {conn} to make room for a new connection");
+		Some(conn)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Connection> {
+		self.connections.values()
+	}
+
+	pub fn len(&self) -> usize {
+		self.connections.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.connections.is_empty()
+	}
+}
+
+impl crate::Interface {
+	/// Returns the tracked connections, like `conntrack -L`.
+	pub fn connections(&self) -> impl Iterator<Item = &Connection> {
+		self.conntrack.iter()
+	}
+
+	/// Classifies a packet with connection tracking and runs it through a filter chain, recording it if it is accepted.
+	pub(crate) fn inspect(&mut self, cx: CX![], chain: Chain, mut packet: filter::Packet) -> Action {
+		if !self.filter.tracks() {
+			return self.filter.check(chain, &packet);
+		}
+
+		// Packets which cannot be tracked at all, such as fragments after the first, are not classified.
+		if packet.state != State::Untracked {
+			packet.state = self.conntrack.state(packet.segment.as_ref());
+		}
+
+		let action = self.filter.check(chain, &packet);
+
+		if let (Action::Accept, Some(segment)) = (action, packet.segment) {
+			if packet.state != State::Invalid {
+				self.track(cx, segment);
+			}
+		}
+
+		action
+	}
+
+	/// Records a packet, creating its connection if there is none.
+	fn track(&mut self, cx: CX![], segment: Segment) {
+		// Error messages are related to a connection, but do not refresh it.
+		if let Kind::Error(_) = segment.kind {
+			return;
+		}
+
+		let now = cx.now();
+
+		if let Some((tuple, reply)) = self.conntrack.find(segment.tuple) {
+			let table = &mut self.conntrack;
+			let conn = table.connections.get_mut(&tuple).unwrap();
+
+			conn.used = now;
+
+			// Move the connection to the end of the order of use, or out of it once a reply is seen.
+			if !conn.replied {
+				table.unreplied.remove(&conn.order);
+
+				if reply {
+					conn.replied = true;
+				} else {
+					conn.order = table.order;
+					table.order += 1;
+					table.unreplied.insert(conn.order, tuple);
+				}
+			}
+
+			let timeout = match (conn.tcp, segment.kind) {
+				(Some(tcp), Kind::Tcp(flags)) => {
+					let tcp = tcp.next(flags, reply);
+					conn.tcp = Some(tcp);
+					tcp.timeout()
+				}
+				_ if Protocol::from(tuple.proto) == Protocol::Udp && conn.replied => UDP_REPLIED,
+				_ => conn.timeout,
+			};
+
+			// Rearm the timer for the new timeout, which may be shorter than the remaining delay.
+			if timeout != conn.timeout {
+				conn.timeout = timeout;
+
+				cx.timer_del(conn.timer);
+				conn.timer = expire_after(cx, tuple, timeout);
+			}
+
+			return;
+		}
+
+		if self.conntrack.connections.len() >= MAX_CONNECTIONS {
+			let Some(conn) = self.conntrack.early_drop() else {
+				return warn!("Not tracking flow from {} to {}, as the connection table is full", segment.tuple.src, segment.tuple.dst);
+			};
+
+			cx.timer_del(conn.timer);
+		}
+
+		let (tcp, timeout) = match Protocol::from(segment.tuple.proto) {
+			Protocol::Tcp => (Some(Tcp::SynSent), TCP_SYN_SENT),
+			Protocol::Udp => (None, UDP_UNREPLIED),
+			Protocol::Icmp | Protocol::Icmpv6 => (None, ICMP),
+			_ => (None, OTHER),
+		};
+
+		let tuple = segment.tuple;
+		debug!("Tracking flow from {} to {}", tuple.src, tuple.dst);
+
+		let timer = expire_after(cx, tuple, timeout);
+		self.conntrack.insert(Connection { tuple, replied: false, tcp, timeout, used: now, timer, order: 0 });
+	}
+
+	/// Removes a connection if it has expired, or rearms its timer if it has been used since the timer was armed.
+	fn expire_connection(&mut self, cx: CX![], tuple: Tuple) {
+		let Some(conn) = self.conntrack.connections.get_mut(&tuple) else { return };
+		let expires = conn.expires();
+
+		if expires <= cx.now() {
+			debug!("Connection {conn} expired");
+			self.conntrack.remove(&tuple);
+		} else {
+			conn.timer = expire_after(cx, tuple, expires - cx.now());
+		}
+	}
+}
+
+/// Arms the timer which expires a connection.
+fn expire_after(cx: CX![crate::Interface], tuple: Tuple, delay: Duration) -> FixedTimerKey {
+	let actor = cx.access_actor().clone();
+	cx.after(delay, move |s| actor.apply(s, move |this, cx| this.expire_connection(cx, tuple)))
+}
+
+/// Describes a TCP segment with control flags, or a UDP datagram, between endpoints such as `10.0.0.1:80`.
+#[cfg(test)]
+fn packet(proto: Protocol, src: &str, dst: &str, flags: u8) -> filter::Packet {
+	let (src, dst): (std::net::SocketAddr, std::net::SocketAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+
+	let mut payload = [&src.port().to_be_bytes()[..], &dst.port().to_be_bytes(), &[0; 16]].concat();
+	payload[13] = flags;
+
+	if proto == Protocol::Udp {
+		payload.truncate(8);
+	}
+
+	filter::Packet::new(proto.into(), src.ip(), dst.ip(), super::ToS::from(0), &payload)
+}
+
+/// Runs a packet through the forward chain of an interface, returning its connection tracking state.
+#[cfg(test)]
+fn inspect(s: &mut stakker::Stakker, n: &stakker::ActorOwn<crate::Interface>, packet: filter::Packet) -> State {
+	n.query(s, |n, cx| {
+		let state = n.conntrack.state(packet.segment.as_ref());
+		n.inspect(cx, Chain::Forward, packet);
+		state
+	})
+	.unwrap()
+}
+
+#[test]
+fn test_early_drop() {
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+	a.query(&mut s, |n, cx| n.load_filter(cx, "forward drop state invalid")).unwrap().unwrap();
+
+	let udp = |port: usize| packet(Protocol::Udp, &format!("10.0.0.2:{port}"), "192.0.2.1:53", 0);
+	let reply = |port: usize| packet(Protocol::Udp, "192.0.2.1:53", &format!("10.0.0.2:{port}"), 0);
+
+	for port in 0..MAX_CONNECTIONS {
+		assert_eq!(inspect(&mut s, &a, udp(port)), State::New);
+	}
+
+	// Using the first connection makes the second the least recently used, and a reply exempts the second from being dropped.
+	inspect(&mut s, &a, udp(0));
+	assert_eq!(inspect(&mut s, &a, reply(1)), State::Established);
+
+	assert_eq!(inspect(&mut s, &a, udp(MAX_CONNECTIONS)), State::New);
+
+	let tracked = |s: &mut stakker::Stakker, port: usize| a.query(s, |n, _| n.conntrack.find(udp(port).segment.unwrap().tuple).is_some()).unwrap();
+
+	assert!(tracked(&mut s, 0) && tracked(&mut s, 1) && !tracked(&mut s, 2) && tracked(&mut s, MAX_CONNECTIONS));
+	assert_eq!(a.query(&mut s, |n, _| (n.conntrack.len(), n.conntrack.unreplied.len())), Some((MAX_CONNECTIONS, MAX_CONNECTIONS - 1)));
+}
+
+#[test]
+fn test_states() {
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+
+	// Packets are not tracked unless a rule matches on their state.
+	inspect(&mut s, &a, packet(Protocol::Udp, "10.0.0.2:5000", "192.0.2.1:53", 0));
+	assert!(a.query(&mut s, |n, _| n.conntrack.is_empty()).unwrap());
+
+	a.query(&mut s, |n, cx| n.load_filter(cx, "forward drop state invalid")).unwrap().unwrap();
+
+	// A flow is new until a reply is seen.
+	let out = || packet(Protocol::Udp, "10.0.0.2:5000", "192.0.2.1:53", 0);
+	let back = || packet(Protocol::Udp, "192.0.2.1:53", "10.0.0.2:5000", 0);
+
+	assert_eq!([out(), out(), back(), out()].map(|p| inspect(&mut s, &a, p)), [State::New, State::New, State::Established, State::Established]);
+
+	// ICMP error messages quoting a tracked flow are related to it.
+	let error = |quoted: &filter::Packet| {
+		let tuple = quoted.segment.unwrap().tuple;
+		let (IpAddr::V4(src), IpAddr::V4(dst)) = (tuple.src.addr, tuple.dst.addr) else { unreachable!() };
+
+		let header = [&[0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0][..], &src.octets(), &dst.octets()].concat();
+		let udp = [&tuple.src.port.to_be_bytes()[..], &tuple.dst.port.to_be_bytes(), &[0, 8, 0, 0]].concat();
+		let message = [&[3, 3, 0, 0, 0, 0, 0, 0][..], &header, &udp].concat();
+
+		filter::Packet::new(Protocol::Icmp.into(), "192.0.2.254".parse().unwrap(), tuple.src.addr, super::ToS::from(0), &message)
+	};
+
+	assert_eq!(inspect(&mut s, &a, error(&out())), State::Related);
+	assert_eq!(inspect(&mut s, &a, error(&packet(Protocol::Udp, "10.0.0.2:5001", "192.0.2.1:53", 0))), State::Invalid);
+
+	// Only a SYN segment starts a TCP connection, and the connection is established once the responder replies.
+	let tcp = |flags, reply: bool| match reply {
+		false => packet(Protocol::Tcp, "10.0.0.2:40000", "192.0.2.1:80", flags),
+		true => packet(Protocol::Tcp, "192.0.2.1:80", "10.0.0.2:40000", flags),
+	};
+
+	let conn = |s: &mut stakker::Stakker| a.query(s, |n, _| n.connections().find(|c| c.tuple().proto == 6).and_then(Connection::tcp)).unwrap();
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_ACK, false)), State::Invalid);
+	assert_eq!(conn(&mut s), None);
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_SYN, false)), State::New);
+	assert_eq!(conn(&mut s), Some(Tcp::SynSent));
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_SYN | TCP_ACK, true)), State::Established);
+	assert_eq!(conn(&mut s), Some(Tcp::SynReceived));
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_ACK, false)), State::Established);
+	assert_eq!(conn(&mut s), Some(Tcp::Established));
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_FIN | TCP_ACK, true)), State::Established);
+	assert_eq!(conn(&mut s), Some(Tcp::Closing));
+
+	assert_eq!(inspect(&mut s, &a, tcp(TCP_RST, false)), State::Established);
+	assert_eq!(conn(&mut s), Some(Tcp::Closed));
+
+	// Invalid packets are dropped by the rule, and not recorded.
+	assert_eq!(a.query(&mut s, |n, _| n.filter().rules(Chain::Forward)[0].hits()), Some(2));
+	assert_eq!(a.query(&mut s, |n, _| n.conntrack.len()), Some(2));
+}
+
+#[test]
+fn test_timeouts() {
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+	a.query(&mut s, |n, cx| n.load_filter(cx, "forward drop state invalid")).unwrap().unwrap();
+
+	let second = Duration::from_secs(1);
+
+	let advance = |s: &mut stakker::Stakker, by| {
+		let now = s.now() + by;
+		s.run(now, false);
+	};
+
+	let tracked = |s: &mut stakker::Stakker, proto: u8| a.query(s, |n, _| n.connections().any(|c| c.tuple().proto == proto)).unwrap();
+
+	// Unreplied UDP flows expire sooner than those to which a reply has been seen.
+	inspect(&mut s, &a, packet(Protocol::Udp, "10.0.0.2:5000", "192.0.2.1:53", 0));
+	inspect(&mut s, &a, packet(Protocol::Udp, "10.0.0.2:5001", "192.0.2.1:53", 0));
+	inspect(&mut s, &a, packet(Protocol::Udp, "192.0.2.1:53", "10.0.0.2:5001", 0));
+
+	advance(&mut s, UDP_UNREPLIED + second);
+	assert_eq!(a.query(&mut s, |n, _| n.connections().map(|c| c.tuple().src.port).collect::<Vec<_>>()), Some(vec![5001]));
+
+	// Using a flow defers its expiry.
+	advance(&mut s, UDP_REPLIED - UDP_UNREPLIED - 2 * second);
+	inspect(&mut s, &a, packet(Protocol::Udp, "10.0.0.2:5001", "192.0.2.1:53", 0));
+
+	advance(&mut s, UDP_REPLIED - second);
+	assert!(tracked(&mut s, 17));
+
+	advance(&mut s, 2 * second);
+	assert!(!tracked(&mut s, 17));
+
+	// A TCP connection expires soon after it is reset, although it was established with a much longer timeout.
+	inspect(&mut s, &a, packet(Protocol::Tcp, "10.0.0.2:40000", "192.0.2.1:80", TCP_SYN));
+	inspect(&mut s, &a, packet(Protocol::Tcp, "192.0.2.1:80", "10.0.0.2:40000", TCP_SYN | TCP_ACK));
+	inspect(&mut s, &a, packet(Protocol::Tcp, "10.0.0.2:40000", "192.0.2.1:80", TCP_ACK));
+
+	advance(&mut s, TCP_SYN_SENT + second);
+	assert!(tracked(&mut s, 6));
+
+	inspect(&mut s, &a, packet(Protocol::Tcp, "192.0.2.1:80", "10.0.0.2:40000", TCP_RST));
+
+	advance(&mut s, TCP_CLOSED + second);
+	assert!(!tracked(&mut s, 6));
+	assert!(a.query(&mut s, |n, _| n.conntrack.unreplied.is_empty()).unwrap());
+}
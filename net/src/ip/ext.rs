@@ -0,0 +1,333 @@
+//! IPv6 extension headers.
+
+use core::mem::size_of;
+
+use bilge::prelude::*;
+use collections::bytes::Slice;
+use log::{debug, warn};
+use utils::bytes::{self, Cast};
+use utils::endian::{u32be, BigEndian};
+use utils::error::*;
+
+use super::Protocol;
+
+/// The fixed prefix of the Hop-by-Hop Options, Routing, and Destination Options headers.
+#[derive(Cast)]
+#[repr(C)]
+struct Prefix {
+	/// The type of the header following this one.
+	nxt: BigEndian<Protocol>,
+	/// The length of the header in 8-octet units, not including the first 8 octets.
+	len: u8,
+}
+
+#[bitsize(16)]
+#[derive(FromBits)]
+pub struct FragmentMeta {
+	/// Whether there are more fragments.
+	pub more: bool,
+	reserved: u2,
+	/// The offset of the fragment data, in 8-octet units.
+	pub ofst: u13,
+}
+
+/// The Fragment header.
+#[derive(Cast)]
+#[repr(C)]
+pub struct Fragment {
+	/// The type of the header following this one.
+	pub nxt: BigEndian<Protocol>,
+	reserved: u8,
+	/// The fragment offset and more-fragments flag.
+	pub frg: BigEndian<FragmentMeta>,
+	/// The identification value of the fragmented packet.
+	pub idnt: u32be,
+}
+
+impl Fragment {
+	pub fn new(nxt: Protocol, frg: FragmentMeta, idnt: u32) -> Self {
+		Self { nxt: nxt.into(), reserved: 0, frg: frg.into(), idnt: idnt.into() }
+	}
+}
+
+/// The action to take when an option type is not recognised, encoded in the highest-order two bits of the option type.
+#[bitsize(2)]
+#[derive(FromBits)]
+enum Action {
+	/// Skip over the option and continue processing the header.
+	Skip = 0,
+	/// Discard the packet.
+	Discard = 1,
+	/// Discard the packet and send an ICMP Parameter Problem message to the source.
+	Report = 2,
+	/// Discard the packet and, if the destination was not a multicast address, send an ICMP Parameter Problem message to the source.
+	ReportUnicast = 3,
+}
+
+#[bitsize(8)]
+#[derive(FromBits)]
+struct OptType {
+	kind: u5,
+	/// Whether the option data may change en route to the destination.
+	change: bool,
+	action: Action,
+}
+
+/// A single octet of padding.
+const PAD1: u8 = 0;
+/// Two or more octets of padding.
+const PADN: u8 = 1;
+/// The Router Alert option.
+const ROUTER_ALERT: u8 = 5;
+
+/// The Parameter Problem code for an erroneous header field.
+pub const ERRONEOUS_FIELD: u8 = 0;
+/// The Parameter Problem code for an unrecognised IPv6 option.
+pub const UNRECOGNISED_OPTION: u8 = 2;
+/// The Parameter Problem code for a first fragment which does not contain the entire header chain, as specified by RFC 7112.
+pub const INCOMPLETE_CHAIN: u8 = 3;
+
+/// A problem with an extension header which is reported to the source of the packet with a Parameter Problem message.
+#[derive(Clone, Copy)]
+pub struct Problem {
+	/// The Parameter Problem code.
+	pub code: u8,
+	/// The offset of the offending octet within the header data.
+	pub at: usize,
+}
+
+/// Splits a Hop-by-Hop Options, Routing, or Destination Options header off of the buffer, returning the type of the following header and the
+/// header data after the prefix.
+pub fn split(buf: &Slice) -> Result<(Protocol, &[u8])> {
+	if buf.len() < size_of::<Prefix>() {
+		warn!("IPv6 extension header is truncated");
+		return Err(());
+	}
+
+	let len = 8 * (bytes::cast::<Prefix, _>(&**buf).len as usize + 1);
+
+	if buf.len() < len {
+		warn!("IPv6 extension header length ({len}) exceeds the packet length ({})", buf.len());
+		return Err(());
+	}
+
+	let prefix: &Prefix = buf.split();
+	let data = buf.split_bytes(len - size_of::<Prefix>());
+
+	Ok((prefix.nxt.get(), data))
+}
+
+/// Splits a Fragment header off of the buffer.
+pub fn split_fragment(buf: &Slice) -> Result<&Fragment> {
+	buf.try_split().ok_or_else(|| warn!("IPv6 Fragment header is truncated"))
+}
+
+/// Processes the options of a Hop-by-Hop Options or Destination Options header, failing if the packet should be discarded, with the problem
+/// to report if it should be reported.
+pub fn options(header: &[u8]) -> Result<(), Option<Problem>> {
+	let mut buf = header;
+
+	while let Some((&ty, rest)) = buf.split_first() {
+		if ty == PAD1 {
+			buf = rest;
+			continue;
+		}
+
+		let Some((&len, rest)) = rest.split_first().filter(|(&len, rest)| len as usize <= rest.len()) else {
+			warn!("IPv6 option of type {ty} has invalid length");
+			return Err(None);
+		};
+
+		let (data, rest) = rest.split_at(len as usize);
+
+		match ty {
+			PADN => {}
+			ROUTER_ALERT => debug!("Recieved packet with Router Alert option ({:?})", data),
+			_ => match OptType::from(ty).action() {
+				Action::Skip => {}
+				Action::Discard => {
+					warn!("Discarding packet with unrecognised IPv6 option of type {ty}");
+					return Err(None);
+				}
+				Action::Report | Action::ReportUnicast => {
+					warn!("Discarding packet with unrecognised IPv6 option of type {ty}");
+					return Err(Some(Problem { code: UNRECOGNISED_OPTION, at: header.len() - buf.len() }));
+				}
+			},
+		}
+
+		buf = rest;
+	}
+
+	Ok(())
+}
+
+/// Processes the data of a Routing header, failing if the packet should be discarded, with the problem to report if it should be reported.
+pub fn routing(data: &[u8]) -> Result<(), Option<Problem>> {
+	let [ty, left, ..] = *data else { return Err(None) };
+
+	// Routing headers are not followed, so this host must be the final segment of the route.
+	if left != 0 {
+		warn!("Discarding packet with Routing header of type {ty} with {left} segments left");
+		// No routing type is recognised, so the offending field is the Routing Type field.
+		return Err(Some(Problem { code: ERRONEOUS_FIELD, at: 0 }));
+	}
+
+	Ok(())
+}
+
+/// The upper-layer header of an IPv6 packet.
+pub struct Upper {
+	/// The upper-layer protocol.
+	pub proto: Protocol,
+	/// The offset of the Next Header field which identifies the upper-layer protocol, in the IPv6 header or the last extension header.
+	pub field: usize,
+	/// The offset of the upper-layer header, which is `None` for fragments other than the first, which do not carry it.
+	pub at: Option<usize>,
+}
+
+/// Locates the upper-layer header of an IPv6 packet whose IPv6 header has been validated, by walking its extension header chain. Returns
+/// `None` if an extension header is truncated.
+pub fn upper(packet: &[u8]) -> Option<Upper> {
+	let mut proto = Protocol::from(packet[6]);
+	let (mut field, mut at) = (6, 40);
+
+	loop {
+		let len = match proto {
+			Protocol::HopByHop | Protocol::Ipv6Opts | Protocol::Ipv6Route => 8 * (*packet.get(at + 1)? as usize + 1),
+			Protocol::Ipv6Frag => {
+				let header: &Fragment = bytes::try_cast(packet.get(at..)?)?;
+
+				if header.frg.get().ofst().value() != 0 {
+					return Some(Upper { proto: header.nxt.get(), field: at, at: None });
+				}
+
+				size_of::<Fragment>()
+			}
+			_ => return Some(Upper { proto, field, at: Some(at) }),
+		};
+
+		if packet.len() < at + len {
+			return None;
+		}
+
+		proto = Protocol::from(packet[at]);
+		field = at;
+		at += len;
+	}
+}
+
+/// Verifies that the first fragment of a packet contains the entire header chain, up to and including the upper-layer header, as required
+/// by RFC 7112.
+pub fn check_chain(mut proto: Protocol, buf: &Slice) -> Result {
+	let buf = buf.clone();
+
+	loop {
+		proto = match proto {
+			Protocol::Ipv6Opts | Protocol::Ipv6Route => split(&buf)?.0,
+			Protocol::HopByHop | Protocol::Ipv6Frag => {
+				warn!("Invalid extension header in the fragmentable part of an IPv6 packet");
+				return Err(());
+			}
+			_ => break,
+		};
+	}
+
+	let min = match proto {
+		Protocol::Tcp => 20,
+		Protocol::Udp => 8,
+		_ => 0,
+	};
+
+	if buf.len() < min {
+		warn!("First IPv6 fragment does not contain the entire header chain");
+		return Err(());
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+fn slice(data: &[u8]) -> Slice {
+	let mut buf = Slice::new(data.len());
+	buf.copy_from_slice(data);
+	buf
+}
+
+#[test]
+fn test_split() {
+	let buf = slice(&[17, 0, 1, 4, 0, 0, 0, 0, 9]);
+
+	let (nxt, data) = split(&buf).unwrap();
+	assert!(nxt == Protocol::Udp);
+	assert_eq!(data, &[1, 4, 0, 0, 0, 0]);
+	assert_eq!(&*buf, &[9]);
+
+	// The length field counts 8-octet units beyond the first 8 octets.
+	assert!(split(&slice(&[17, 1, 0, 0, 0, 0, 0, 0, 0, 0])).is_err());
+	assert!(split(&slice(&[17])).is_err());
+}
+
+#[test]
+fn test_options() {
+	// Pad1, PadN, and an unrecognised option whose action is to skip it.
+	assert!(options(&[0, 1, 1, 0, 0x1e, 0, 0, 0]).is_ok());
+
+	assert!(matches!(options(&[0, 0x5e, 0, 0, 0, 0, 0]), Err(None)));
+	assert!(matches!(options(&[1, 0, 0x9e, 1, 0, 0]), Err(Some(Problem { code: UNRECOGNISED_OPTION, at: 2 }))));
+	assert!(matches!(options(&[0xde, 0, 0, 0]), Err(Some(Problem { code: UNRECOGNISED_OPTION, at: 0 }))));
+
+	// The option length exceeds the header.
+	assert!(matches!(options(&[1, 4, 0, 0]), Err(None)));
+}
+
+#[test]
+fn test_routing() {
+	assert!(routing(&[0, 0, 0, 0, 0, 0]).is_ok());
+	assert!(matches!(routing(&[0, 1, 0, 0, 0, 0]), Err(Some(Problem { code: ERRONEOUS_FIELD, at: 0 }))));
+	assert!(matches!(routing(&[0]), Err(None)));
+}
+
+#[test]
+fn test_upper() {
+	let mut packet = vec![0; 40];
+	packet[6] = Protocol::Tcp.into();
+
+	let found = upper(&packet).unwrap();
+	assert!(found.proto == Protocol::Tcp);
+	assert_eq!((found.field, found.at), (6, Some(40)));
+
+	// A Hop-by-Hop Options header followed by a first fragment.
+	packet[6] = Protocol::HopByHop.into();
+	packet.extend_from_slice(&[44, 0, 1, 4, 0, 0, 0, 0]);
+	packet.extend_from_slice(&[17, 0, 0, 1, 0, 0, 0, 1]);
+
+	let found = upper(&packet).unwrap();
+	assert!(found.proto == Protocol::Udp);
+	assert_eq!((found.field, found.at), (48, Some(56)));
+
+	// Later fragments do not carry the upper-layer header.
+	packet[50..52].copy_from_slice(&[0, 8]);
+
+	let found = upper(&packet).unwrap();
+	assert!(found.proto == Protocol::Udp);
+	assert_eq!((found.field, found.at), (48, None));
+
+	// The chain is truncated.
+	assert!(upper(&packet[..52]).is_none());
+	assert!(upper(&packet[..44]).is_none());
+}
+
+#[test]
+fn test_check_chain() {
+	let buf = slice(&[17, 0, 1, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+	assert!(check_chain(Protocol::Ipv6Opts, &buf).is_ok());
+
+	// The chain is not consumed.
+	assert_eq!(buf.len(), 16);
+
+	assert!(check_chain(Protocol::Ipv6Opts, &slice(&buf[..12])).is_err());
+	assert!(check_chain(Protocol::Tcp, &buf).is_err());
+	assert!(check_chain(Protocol::HopByHop, &buf).is_err());
+	assert!(check_chain(Protocol::Icmpv6, &slice(&[])).is_ok());
+}
@@ -0,0 +1,533 @@
+//! Packet filtering, with chains of rules applied to packets delivered to the interface, written by it, and forwarded by it.
+//!
+//! Rules can be loaded from a text format with one rule per line:
+//!
+//! ```text
+//! # Lines starting with '#' are comments.
+//! policy input drop
+//! input accept proto udp dport 53
+//! input accept src 10.0.0.0/8 proto tcp dport 1024-65535
+//! forward reject dst 192.0.2.0/24
+//! output drop dscp 46 len 1000-65535
+//! forward drop ecn ce
+//! input accept state established,related
+//! ```
+//!
+//! Each rule names a chain and an action, followed by any number of matches, all of which must match a packet for the rule to apply. The
+//! matches are `src` and `dst` with an address or prefix, `proto` with a protocol name or number, `sport` and `dport` with a port or range
+//! of ports, `dscp` with a Differentiated Services code point, `ecn` with an Explicit Congestion Notification codepoint (`not-ect`,
+//! `ect1`, `ect0` or `ce`), and `len` with a length or range of lengths of the IP payload. Port matches
+//! only match TCP and UDP packets, and never match fragments after the first. The `state` match takes a comma-separated list of connection
+//! tracking states, such as `state established,related`. Connections are only tracked while a rule of the filter has a `state` match.
+//!
+//! The first matching rule of a chain decides the fate of a packet. Packets which match no rule are handled by the policy of the chain,
+//! which is to accept them unless set otherwise with a `policy` line.
+
+use core::fmt::Display;
+use core::net::IpAddr;
+use core::ops::RangeInclusive;
+use core::str::FromStr;
+
+use bilge::prelude::*;
+use log::{debug, warn};
+use stakker::CX;
+use utils::error::*;
+
+use super::conntrack::{Segment, State};
+use super::{Cidr, Protocol, ToS, ECN};
+
+/// The named protocols of the text format.
+const PROTOCOLS: [(&str, u8); 4] = [("icmp", 1), ("tcp", 6), ("udp", 17), ("icmpv6", 58)];
+
+/// The named Explicit Congestion Notification codepoints of the text format.
+const ECNS: [(&str, ECN); 4] = [("not-ect", ECN::NotECT), ("ect1", ECN::ECT1), ("ect0", ECN::ECT0), ("ce", ECN::CE)];
+
+/// Returns the name of a protocol number in the text format, if it has one.
+pub(super) fn protocol_name(proto: u8) -> Option<&'static str> {
+	PROTOCOLS.iter().find(|(_, p)| *p == proto).map(|(name, _)| *name)
+}
+
+/// A point of the stack at which packets are filtered.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Chain {
+	/// Packets addressed to the interface, after reassembly.
+	Input,
+	/// Packets written by the interface, before fragmentation.
+	Output,
+	/// Packets forwarded by the interface.
+	Forward,
+}
+
+impl Chain {
+	const ALL: [Self; 3] = [Self::Input, Self::Output, Self::Forward];
+
+	fn name(self) -> &'static str {
+		match self {
+			Self::Input => "input",
+			Self::Output => "output",
+			Self::Forward => "forward",
+		}
+	}
+}
+
+impl FromStr for Chain {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::ALL.into_iter().find(|c| c.name() == s).ok_or_else(|| warn!("Unknown chain {s}"))
+	}
+}
+
+impl Display for Chain {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// The fate of a filtered packet.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Action {
+	#[default]
+	Accept,
+	/// Discard the packet silently.
+	Drop,
+	/// Discard the packet, and report it to its source with an ICMP Destination Unreachable message. Packets rejected by the output chain
+	/// are discarded silently.
+	Reject,
+}
+
+impl FromStr for Action {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self> {
+		match s {
+			"accept" => Ok(Self::Accept),
+			"drop" => Ok(Self::Drop),
+			"reject" => Ok(Self::Reject),
+			_ => Err(warn!("Unknown action {s}")),
+		}
+	}
+}
+
+impl Display for Action {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Accept => "accept",
+			Self::Drop => "drop",
+			Self::Reject => "reject",
+		})
+	}
+}
+
+/// The fields of a packet which rules match on.
+pub(crate) struct Packet {
+	pub proto: u8,
+	pub src: IpAddr,
+	pub dst: IpAddr,
+	pub tos: ToS,
+	/// The source and destination ports of TCP and UDP packets.
+	pub ports: Option<[u16; 2]>,
+	/// The length of the IP payload.
+	pub len: usize,
+	/// The location of the packet within the tracked flows, if it could be located.
+	pub segment: Option<Segment>,
+	/// The connection tracking state of the packet, which is classified before it is filtered.
+	pub state: State,
+}
+
+impl Packet {
+	/// Describes a packet with an IP payload, which begins with the transport header.
+	pub fn new(proto: u8, src: IpAddr, dst: IpAddr, tos: ToS, payload: &[u8]) -> Self {
+		let ports = match (Protocol::from(proto), payload) {
+			(Protocol::Tcp | Protocol::Udp, [s0, s1, d0, d1, ..]) => Some([u16::from_be_bytes([*s0, *s1]), u16::from_be_bytes([*d0, *d1])]),
+			_ => None,
+		};
+
+		Self { proto, src, dst, tos, ports, len: payload.len(), segment: Segment::of(proto, src, dst, payload), state: State::Invalid }
+	}
+
+	/// Describes a fragment other than the first, given the length of its IP payload. It does not carry the transport header, so is neither
+	/// matched by port nor tracked.
+	pub fn fragment(proto: u8, src: IpAddr, dst: IpAddr, tos: ToS, len: usize) -> Self {
+		Self { proto, src, dst, tos, ports: None, len, segment: None, state: State::Untracked }
+	}
+}
+
+/// A filter rule, which applies an action to the packets matching all of its matches.
+#[derive(Clone, Default, Debug)]
+pub struct Rule {
+	pub src: Option<Cidr>,
+	pub dst: Option<Cidr>,
+	/// The protocol number.
+	pub proto: Option<u8>,
+	pub sport: Option<RangeInclusive<u16>>,
+	pub dport: Option<RangeInclusive<u16>>,
+	/// The Differentiated Services code point.
+	pub dscp: Option<u8>,
+	/// The Explicit Congestion Notification codepoint.
+	pub ecn: Option<ECN>,
+	/// The length of the IP payload.
+	pub len: Option<RangeInclusive<usize>>,
+	/// The connection tracking states.
+	pub state: Option<Vec<State>>,
+	pub action: Action,
+	/// The number of packets the rule has applied to.
+	hits: u64,
+}
+
+impl Rule {
+	/// Creates a rule applying an action to every packet.
+	pub fn new(action: Action) -> Self {
+		Self { action, ..Default::default() }
+	}
+
+	/// Returns the number of packets the rule has applied to.
+	pub fn hits(&self) -> u64 {
+		self.hits
+	}
+
+	fn matches(&self, p: &Packet) -> bool {
+		let port = |range: &Option<RangeInclusive<u16>>, idx: usize| {
+			range.as_ref().is_none_or(|r| p.ports.is_some_and(|ports| r.contains(&ports[idx])))
+		};
+
+		self.src.is_none_or(|c| c.contains(p.src))
+			&& self.dst.is_none_or(|c| c.contains(p.dst))
+			&& self.proto.is_none_or(|proto| proto == p.proto)
+			&& port(&self.sport, 0)
+			&& port(&self.dport, 1)
+			&& self.dscp.is_none_or(|dscp| dscp == u6::from(p.tos.ds()).value())
+			&& self.ecn.is_none_or(|ecn| ecn == p.tos.ecn())
+			&& self.len.as_ref().is_none_or(|r| r.contains(&p.len))
+			&& self.state.as_ref().is_none_or(|states| states.contains(&p.state))
+	}
+
+	/// Parses the action and matches of a rule.
+	fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self> {
+		let mut rule = Self::new(words.next().ok_or_else(|| warn!("Missing action"))?.parse()?);
+
+		while let Some(key) = words.next() {
+			let value = words.next().ok_or_else(|| warn!("Missing value of {key}"))?;
+
+			match key {
+				"src" => rule.src = Some(value.parse()?),
+				"dst" => rule.dst = Some(value.parse()?),
+				"proto" => {
+					let proto = PROTOCOLS.iter().find(|(name, _)| *name == value).map(|(_, proto)| *proto);
+					rule.proto = Some(proto.or_else(|| value.parse().ok()).ok_or_else(|| warn!("Unknown protocol {value}"))?);
+				}
+				"sport" => rule.sport = Some(range(value)?),
+				"dport" => rule.dport = Some(range(value)?),
+				"dscp" => rule.dscp = Some(value.parse().ok().filter(|&dscp: &u8| dscp < 64).ok_or_else(|| warn!("Invalid code point {value}"))?),
+				"ecn" => {
+					let ecn = ECNS.iter().find(|(name, _)| *name == value).map(|(_, ecn)| *ecn);
+					rule.ecn = Some(ecn.ok_or_else(|| warn!("Unknown ECN codepoint {value}"))?);
+				}
+				"len" => rule.len = Some(range(value)?),
+				"state" => rule.state = Some(value.split(',').map(str::parse).collect::<Result<_>>()?),
+				_ => return Err(warn!("Unknown match {key}")),
+			}
+		}
+
+		Ok(rule)
+	}
+}
+
+/// Parses a single value or an inclusive range of values separated by a hyphen.
+fn range<T: FromStr + PartialOrd + Copy>(s: &str) -> Result<RangeInclusive<T>> {
+	let (start, end) = s.split_once('-').unwrap_or((s, s));
+
+	match (start.parse(), end.parse()) {
+		(Ok(start), Ok(end)) if start <= end => Ok(start..=end),
+		_ => Err(warn!("Invalid range {s}")),
+	}
+}
+
+impl Display for Rule {
+	/// Formats the rule in the text format, without its chain.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		fn range<T: Display + PartialEq>(f: &mut std::fmt::Formatter<'_>, key: &str, r: &Option<RangeInclusive<T>>) -> std::fmt::Result {
+			match r {
+				Some(r) if r.start() == r.end() => f.write_fmt(format_args!(" {key} {}", r.start())),
+				Some(r) => f.write_fmt(format_args!(" {key} {}-{}", r.start(), r.end())),
+				None => Ok(()),
+			}
+		}
+
+		f.write_fmt(format_args!("{}", self.action))?;
+
+		if let Some(src) = self.src {
+			f.write_fmt(format_args!(" src {src}"))?;
+		}
+
+		if let Some(dst) = self.dst {
+			f.write_fmt(format_args!(" dst {dst}"))?;
+		}
+
+		if let Some(proto) = self.proto {
+			match protocol_name(proto) {
+				Some(name) => f.write_fmt(format_args!(" proto {name}"))?,
+				None => f.write_fmt(format_args!(" proto {proto}"))?,
+			}
+		}
+
+		range(f, "sport", &self.sport)?;
+		range(f, "dport", &self.dport)?;
+
+		if let Some(dscp) = self.dscp {
+			f.write_fmt(format_args!(" dscp {dscp}"))?;
+		}
+
+		if let Some(ecn) = self.ecn {
+			let (name, _) = ECNS.iter().find(|(_, e)| *e == ecn).unwrap();
+			f.write_fmt(format_args!(" ecn {name}"))?;
+		}
+
+		range(f, "len", &self.len)?;
+
+		if let Some(states) = &self.state {
+			let states: Vec<_> = states.iter().map(State::to_string).collect();
+			f.write_fmt(format_args!(" state {}", states.join(",")))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// The filter rules of every chain.
+#[derive(Clone, Default, Debug)]
+pub struct Filter {
+	chains: [Vec<Rule>; 3],
+	policies: [Action; 3],
+	/// Whether a rule matches on the connection tracking state.
+	tracks: bool,
+}
+
+impl Filter {
+	/// Parses a filter from the text format, failing if any line is invalid.
+	pub fn parse(text: &str) -> Result<Self> {
+		let mut filter = Self::default();
+
+		for (idx, line) in text.lines().enumerate() {
+			let line = line.trim();
+
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			filter.parse_line(line).map_err(|_| warn!("Invalid filter rule on line {}: {line}", idx + 1))?;
+		}
+
+		Ok(filter)
+	}
+
+	/// Parses a line of the text format which is not a comment into the filter.
+	fn parse_line(&mut self, line: &str) -> Result {
+		let mut words = line.split_whitespace();
+
+		match words.next() {
+			Some("policy") => {
+				let chain = words.next().ok_or_else(|| warn!("Missing chain"))?.parse()?;
+				let action = words.next().ok_or_else(|| warn!("Missing action"))?.parse()?;
+
+				if let Some(word) = words.next() {
+					return Err(warn!("Unexpected {word} after policy"));
+				}
+
+				self.set_policy(chain, action);
+			}
+			Some(chain) => {
+				let chain = chain.parse()?;
+				self.push(chain, Rule::parse(words)?);
+			}
+			None => {}
+		}
+
+		Ok(())
+	}
+
+	/// Appends a rule to a chain.
+	pub fn push(&mut self, chain: Chain, rule: Rule) {
+		self.tracks |= rule.state.is_some();
+		self.chains[chain as usize].push(rule);
+	}
+
+	/// Sets the action applied to packets which match no rule of a chain.
+	pub fn set_policy(&mut self, chain: Chain, action: Action) {
+		self.policies[chain as usize] = action;
+	}
+
+	pub fn policy(&self, chain: Chain) -> Action {
+		self.policies[chain as usize]
+	}
+
+	pub fn rules(&self, chain: Chain) -> &[Rule] {
+		&self.chains[chain as usize]
+	}
+
+	/// Returns whether a rule matches on the connection tracking state, so that packets must be tracked.
+	pub fn tracks(&self) -> bool {
+		self.tracks
+	}
+
+	/// Returns the action applied to a packet by a chain, counting the hit of the matching rule.
+	pub(crate) fn check(&mut self, chain: Chain, packet: &Packet) -> Action {
+		let action = match self.chains[chain as usize].iter_mut().find(|rule| rule.matches(packet)) {
+			Some(rule) => {
+				rule.hits += 1;
+				rule.action
+			}
+			None => self.policies[chain as usize],
+		};
+
+		if action != Action::Accept {
+			debug!("Packet from {} to {} matched {action} in the {chain} chain", packet.src, packet.dst);
+		}
+
+		action
+	}
+}
+
+impl Display for Filter {
+	/// Formats the filter in the text format.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for chain in Chain::ALL {
+			if self.policy(chain) != Action::Accept {
+				f.write_fmt(format_args!("policy {chain} {}\n", self.policy(chain)))?;
+			}
+		}
+
+		for chain in Chain::ALL {
+			for rule in self.rules(chain) {
+				f.write_fmt(format_args!("{chain} {rule}\n"))?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl crate::Interface {
+	/// Replaces the packet filter.
+	pub fn set_filter(&mut self, _: CX![], filter: Filter) {
+		self.filter = filter;
+	}
+
+	/// Replaces the packet filter with one parsed from the text format, keeping the current filter if the text is invalid.
+	pub fn load_filter(&mut self, _: CX![], text: &str) -> Result {
+		self.filter = Filter::parse(text)?;
+		Ok(())
+	}
+
+	/// Returns the packet filter, whose rules count the packets they have applied to.
+	pub fn filter(&self) -> &Filter {
+		&self.filter
+	}
+}
+
+#[test]
+fn test_parse() {
+	let text = "\
+policy input drop
+policy forward reject
+input accept src 10.0.0.0/8 proto tcp dport 1024-65535
+input accept proto 132 sport 53
+output drop dscp 46 ecn ce len 1000-65535
+forward reject dst fd00::/16 state established,related
+";
+
+	let filter = Filter::parse(text).unwrap();
+
+	assert_eq!((filter.policy(Chain::Input), filter.policy(Chain::Output), filter.policy(Chain::Forward)), (Action::Drop, Action::Accept, Action::Reject));
+	assert_eq!(filter.rules(Chain::Input).len(), 2);
+
+	let rule = &filter.rules(Chain::Output)[0];
+	assert_eq!((rule.action, rule.dscp, rule.ecn, rule.len.clone()), (Action::Drop, Some(46), Some(ECN::CE), Some(1000..=65535)));
+
+	// Formatting the filter yields its text, without comments.
+	assert_eq!(filter.to_string(), text);
+	assert_eq!(Filter::parse(&format!("# A comment.\n\n{text}")).unwrap().to_string(), text);
+
+	for line in [
+		"input",
+		"input allow",
+		"inbound accept",
+		"input accept src",
+		"input accept src 10.0.0.0/33",
+		"input accept proto sctp",
+		"input accept proto 256",
+		"input accept dport 80-22",
+		"input accept dport 65536",
+		"input accept dscp 64",
+		"input accept ecn ect",
+		"input accept len -1",
+		"input accept state new,closed",
+		"input accept color red",
+		"policy input",
+		"policy input drop now",
+		"policy ingress drop",
+	] {
+		assert!(Filter::parse(line).is_err(), "{line}");
+	}
+}
+
+#[test]
+fn test_matches() {
+	use super::DiffServ;
+
+	let packet = |proto: Protocol, src: &str, dst: &str, tos: ToS, payload: &[u8]| Packet::new(proto.into(), src.parse().unwrap(), dst.parse().unwrap(), tos, payload);
+	let rule = |text: &str| Rule::parse(text.split_whitespace()).unwrap();
+
+	let tos = ToS::new(ECN::ECT0, DiffServ::Ef);
+
+	// A UDP datagram from port 5000 to port 53.
+	let udp = packet(Protocol::Udp, "10.0.0.1", "192.0.2.1", tos, &[0x13, 0x88, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4]);
+	let icmp = packet(Protocol::Icmpv6, "fd00::1", "fd01::1", ToS::new(ECN::NotECT, DiffServ::Default), &[128, 0, 0, 0]);
+	let fragment = Packet::fragment(Protocol::Udp.into(), "10.0.0.1".parse().unwrap(), "192.0.2.1".parse().unwrap(), tos, 100);
+
+	for (text, matches) in [
+		("accept", [true, true, true]),
+		("accept src 10.0.0.0/8", [true, false, true]),
+		("accept src 10.0.0.2", [false, false, false]),
+		("accept dst fd00::/15", [false, true, false]),
+		("accept proto udp", [true, false, true]),
+		("accept proto 58", [false, true, false]),
+		("accept proto icmp", [false, false, false]),
+		("accept sport 5000", [true, false, false]),
+		("accept dport 1-52", [false, false, false]),
+		("accept dport 53-53", [true, false, false]),
+		("accept dscp 46", [true, false, true]),
+		("accept dscp 0", [false, true, false]),
+		("accept ecn ect0", [true, false, true]),
+		("accept ecn not-ect", [false, true, false]),
+		("accept dscp 46 ecn ce", [false, false, false]),
+		("accept len 12", [true, false, false]),
+		("accept len 4-100", [true, true, true]),
+		("accept state invalid", [true, true, false]),
+		("accept state untracked", [false, false, true]),
+	] {
+		let rule = rule(text);
+		assert_eq!([&udp, &icmp, &fragment].map(|p| rule.matches(p)), matches, "{text}");
+	}
+}
+
+#[test]
+fn test_check() {
+	let mut filter = Filter::parse("policy input drop\ninput accept proto udp dport 53\ninput reject proto udp\noutput drop len 0").unwrap();
+
+	let packet = |port: u16| Packet::new(Protocol::Udp.into(), "10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), ToS::from(0), &[0, 1, (port >> 8) as u8, port as u8]);
+	let tcp = Packet::new(Protocol::Tcp.into(), "10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap(), ToS::from(0), &[0; 20]);
+
+	// The first matching rule applies, and packets which match none are handled by the policy of the chain.
+	assert_eq!(filter.check(Chain::Input, &packet(53)), Action::Accept);
+	assert_eq!(filter.check(Chain::Input, &packet(53)), Action::Accept);
+	assert_eq!(filter.check(Chain::Input, &packet(54)), Action::Reject);
+	assert_eq!(filter.check(Chain::Input, &tcp), Action::Drop);
+
+	assert_eq!(filter.check(Chain::Output, &tcp), Action::Accept);
+	assert_eq!(filter.check(Chain::Forward, &tcp), Action::Accept);
+
+	// Only packets matched by a rule are counted.
+	assert_eq!(filter.rules(Chain::Input).iter().map(Rule::hits).collect::<Vec<_>>(), [2, 1]);
+	assert_eq!(filter.rules(Chain::Output)[0].hits(), 0);
+}
@@ -1,13 +1,39 @@
 //! Packet fragmentation and reassembly.
 
+use core::mem::size_of;
 use core::net::IpAddr;
-use std::collections::hash_map::Entry;
+use core::time::Duration;
+use std::collections::hash_map::{Entry, RandomState};
 use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::time::Instant;
 
 use collections::bytes::Slice;
+use log::{debug, warn};
+use stakker::{FixedTimerKey, CX};
 use utils::error::*;
 
-use super::Protocol;
+use super::{Info, Protocol, ECN};
+use crate::trace::{self, Event, Headers, Reason};
+
+/// The time after which an incomplete IPv4 packet is discarded.
+const TIMEOUT_V4: Duration = Duration::from_secs(30);
+/// The time after which an incomplete IPv6 packet is discarded, as specified by RFC 8200.
+const TIMEOUT_V6: Duration = Duration::from_secs(60);
+
+/// The maximum number of bytes charged to all reassemblies, for their fragment data, headers and bookkeeping.
+const MAX_BYTES: usize = 1 << 20;
+/// The maximum number of in-flight reassemblies from a single source address.
+const MAX_PER_SOURCE: usize = 64;
+/// The maximum number of in-flight reassemblies, which bounds the number of sources.
+const MAX_REASSEMBLIES: usize = 1024;
+
+/// The bytes charged to a reassembly for its state, and to each of its fragments.
+const STATE_COST: usize = size_of::<(Key, State)>();
+const FRAGMENT_COST: usize = size_of::<Fragment>();
+
+/// The number of counters used to generate identification values.
+const IDENT_COUNTERS: usize = 1024;
 
 /// The identifying attributes of a fragmented packet.
 /// TODO: consider whether the type-of-service and IPv6 flow fields should be included as well.
@@ -41,6 +67,16 @@ impl Fragment {
 /// A partially-reassembled packed.
 struct State {
 	fragments: Vec<Fragment>,
+	/// The bytes charged to the reassembly, which are its fragment data and headers, and the cost of its bookkeeping.
+	len: usize,
+	/// The time at which the reassembly was started.
+	created: Instant,
+	/// The timer which discards the reassembly once it has expired.
+	timer: FixedTimerKey,
+	/// The IP header fields of the first received fragment, with the congestion experienced mark of any fragment.
+	info: Info,
+	/// The headers preceding the data of the first fragment, which begin the reassembled packet.
+	header: Vec<u8>,
 }
 
 impl State {
@@ -64,7 +100,7 @@ impl State {
 		// Check for overlap with the following fragment.
 		if let Some(f) = self.fragments.get(idx) {
 			// Check if this fragment is marked as the final fragment, but there is another one following it.
-			if fragment.more {
+			if !fragment.more {
 				return Err(fragment);
 			}
 
@@ -80,7 +116,7 @@ impl State {
 		Ok(())
 	}
 
-	// Try to assemble the fragments into a full packet.
+	// Try to assemble the fragments into a full packet, beginning with the headers of the first fragment.
 	fn assemble(&self) -> Option<Slice> {
 		// If the last fragment in the packet has the `more` flag set, then the packet is not done.
 		if self.fragments.last()?.more {
@@ -105,16 +141,38 @@ impl State {
 		}
 
 		// If the packet is complete, then create a new allocation to hold it.
-		let mut alloc = Slice::new(total_len);
+		let mut alloc = Slice::new(self.header.len() + total_len);
+		alloc[..self.header.len()].copy_from_slice(&self.header);
 
 		for f in &self.fragments {
 			// Write the byte slices from each packet into the buffer.
-			alloc[f.start as usize..][..f.buf.len()].copy_from_slice(&f.buf);
+			alloc[self.header.len() + f.start as usize..][..f.buf.len()].copy_from_slice(&f.buf);
 		}
 
 		// Return the reassembled buffer.
 		Some(alloc)
 	}
+
+	/// Returns the headers of the packet being reassembled, for tracing.
+	fn headers(&self, key: &Key) -> Headers {
+		Headers { proto: key.proto.into(), src: key.addr, dst: self.info.dst }
+	}
+}
+
+/// Reassembly counters.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+	/// The number of fragments received.
+	pub received: u64,
+	/// The number of packets reassembled.
+	pub reassembled: u64,
+	/// The number of fragments discarded because they overlapped other fragments, exceeded the maximum packet length, or were empty but
+	/// not the last.
+	pub invalid: u64,
+	/// The number of reassemblies discarded because they were not completed in time.
+	pub expired: u64,
+	/// The number of reassemblies discarded to stay within the memory limits.
+	pub evicted: u64,
 }
 
 /// Stores IP packet fragments for reassembly.
@@ -122,28 +180,290 @@ impl State {
 pub struct Store {
 	/// Maps fragmentated packet identifiers to reassembly states.
 	map: HashMap<Key, State>,
+	/// The total bytes charged to the reassemblies.
+	bytes: usize,
+	/// The number of in-flight reassemblies from each source address.
+	sources: HashMap<IpAddr, usize>,
+	stats: Stats,
+}
+
+impl Store {
+	/// Returns the reassembly counters.
+	pub fn stats(&self) -> Stats {
+		self.stats
+	}
+
+	/// Removes a reassembly state, releasing its share of the limits.
+	fn remove(&mut self, key: &Key) -> Option<State> {
+		let state = self.map.remove(key)?;
+
+		self.bytes -= state.len;
+
+		if let Entry::Occupied(mut slot) = self.sources.entry(key.addr) {
+			*slot.get_mut() -= 1;
+
+			if *slot.get() == 0 {
+				slot.remove();
+			}
+		}
+
+		Some(state)
+	}
+
+	/// Discards a reassembly state, cancelling its expiry timer.
+	fn discard(&mut self, cx: CX![crate::Interface], key: &Key) {
+		if let Some(state) = self.remove(key) {
+			cx.timer_del(state.timer);
+		}
+	}
+
+	/// Evicts the oldest reassembly for which `f` returns true. Returns false if there was none.
+	fn evict(&mut self, cx: CX![crate::Interface], trace: &trace::Hook, f: impl Fn(&Key) -> bool) -> bool {
+		let Some(key) = self.map.iter().filter(|(k, _)| f(k)).min_by_key(|(_, s)| s.created).map(|(k, _)| *k) else {
+			return false;
+		};
+
+		let Some(state) = self.remove(&key) else { return false };
+
+		cx.timer_del(state.timer);
+		self.stats.evicted += 1;
+
+		trace.emit(|| Event::Drop { headers: Some(state.headers(&key)), reason: Reason::FragmentEvicted });
+
+		true
+	}
+
+	/// Discards a reassembly which was not completed in time.
+	fn expire(&mut self, trace: &trace::Hook, key: Key) {
+		if let Some(state) = self.remove(&key) {
+			debug!("Reassembly of packet {} from {} timed out", key.ident, key.addr);
+			self.stats.expired += 1;
+
+			trace.emit(|| Event::Drop { headers: Some(state.headers(&key)), reason: Reason::FragmentExpired });
+		}
+	}
 }
 
 impl crate::Interface {
-	/// Consume a packet fragment, passing completed packets to upper-layer protocols.
-	pub(super) fn handle_fragment(&mut self, key: Key, fragment: Fragment) -> Result {
-		match self.fragment.map.entry(key) {
-			Entry::Occupied(mut slot) => {
-				let state = slot.get_mut();
-
-				state.try_insert(fragment).map_err(|_| ())?;
-
-				if let Some(buf) = state.assemble() {
-					slot.remove();
-					return self.handle(key.proto, key.addr, buf);
-				}
+	/// Consume a packet fragment, given the headers preceding its data, passing completed packets to upper-layer protocols.
+	pub(super) fn handle_fragment(&mut self, cx: CX![], key: Key, info: Info, header: &[u8], fragment: Fragment) -> Result {
+		let len = fragment.buf.len();
+		let first = fragment.start == 0;
+		let store = &mut self.fragment;
+		let trace = &self.trace;
+
+		let headers = Headers { proto: key.proto.into(), src: key.addr, dst: info.dst };
+
+		store.stats.received += 1;
+
+		// Empty fragments other than the last add nothing to the packet, but would each hold a reassembly.
+		if fragment.start as usize + len > u16::MAX as usize || (len == 0 && fragment.more) {
+			warn!("Fragment is empty or exceeds the maximum packet length");
+			store.stats.invalid += 1;
+			trace.emit(|| Event::Drop { headers: Some(headers), reason: Reason::FragmentLength });
+			return Err(());
+		}
+
+		// If there are no fragments associated with the key yet, then start a new reassembly.
+		if !store.map.contains_key(&key) {
+			// Make room for the reassembly by evicting the oldest one from the same source.
+			if store.sources.get(&key.addr).is_some_and(|&n| n >= MAX_PER_SOURCE) {
+				warn!("Too many in-flight reassemblies from {}", key.addr);
+				store.evict(cx, trace, |k| k.addr == key.addr);
+			}
+
+			// Bound the number of sources, which may be spoofed, by evicting the oldest reassembly from any source.
+			if store.map.len() >= MAX_REASSEMBLIES {
+				warn!("Too many in-flight reassemblies");
+				store.evict(cx, trace, |_| true);
 			}
-			// If there are no fragments associated with the key yet, then insert a new slot.
-			Entry::Vacant(slot) => {
-				slot.insert(State { fragments: vec![fragment] });
+
+			let timeout = if key.addr.is_ipv4() { TIMEOUT_V4 } else { TIMEOUT_V6 };
+
+			let actor = cx.access_actor().clone();
+			let timer = cx.after(timeout, move |s| actor.apply(s, move |this, _| this.fragment.expire(&this.trace, key)));
+
+			store.map.insert(key, State { fragments: Vec::new(), len: STATE_COST, created: cx.now(), timer, info, header: Vec::new() });
+			store.bytes += STATE_COST;
+			*store.sources.entry(key.addr).or_default() += 1;
+		}
+
+		// The first fragment also holds the headers which begin the reassembled packet.
+		let cost = len + FRAGMENT_COST + if first { header.len() } else { 0 };
+
+		// Evict the oldest reassemblies until the fragment fits within the memory limit.
+		while store.bytes + cost > MAX_BYTES && store.evict(cx, trace, |k| *k != key) {}
+
+		let Some(state) = store.map.get_mut(&key) else { return Err(()) };
+
+		if state.try_insert(fragment).is_err() {
+			// Overlapping IPv6 fragments must cause the whole packet to be discarded.
+			if key.addr.is_ipv6() {
+				store.discard(cx, &key);
 			}
+
+			warn!("Discarding overlapping fragment");
+			store.stats.invalid += 1;
+			trace.emit(|| Event::Drop { headers: Some(headers), reason: Reason::FragmentOverlap });
+			return Err(());
+		}
+
+		state.len += cost;
+		store.bytes += cost;
+
+		if first {
+			state.header = header.to_vec();
+		}
+
+		// Congestion experienced by any fragment is propagated to the reassembled packet, as specified by RFC 3168.
+		if info.tos.ecn() == ECN::CE {
+			state.info.tos.set_ecn(ECN::CE);
+		}
+
+		if let Some(packet) = state.assemble() {
+			let info = state.info;
+			let buf = packet.clone();
+			buf.split_bytes(state.header.len());
+
+			store.discard(cx, &key);
+			store.stats.reassembled += 1;
+
+			return match key.addr {
+				IpAddr::V4(_) => self.handle(cx, key.proto, key.addr, info, buf, &packet),
+				// The fragmentable part of an IPv6 packet may begin with extension headers.
+				IpAddr::V6(_) => self.handle_v6(cx, key.proto, key.addr, info, buf, &packet),
+			};
 		}
 
 		Ok(())
 	}
 }
+
+/// Returns the length of the data of each fragment but the last, which must be a multiple of 8 octets, when packets with headers of the
+/// given length are fragmented to fit within an MTU. Returns `None` if the MTU leaves no room for fragment data.
+pub(super) fn data_len(mtu: usize, header_len: usize) -> Option<usize> {
+	mtu.checked_sub(header_len).map(|len| len & !7).filter(|&len| len >= 8)
+}
+
+/// Generates identification values for outgoing fragmented packets, using the double-hash algorithm from RFC 7739. Values are sequential
+/// for each destination, but start from a secret offset so that they cannot be predicted by other hosts.
+pub struct Ident {
+	/// The secret key of the offset and counter index hashes.
+	key: RandomState,
+	/// The counters, shared between destinations which hash to the same index.
+	counters: Box<[u32; IDENT_COUNTERS]>,
+}
+
+impl Default for Ident {
+	fn default() -> Self {
+		Self { key: RandomState::new(), counters: Box::new([0; IDENT_COUNTERS]) }
+	}
+}
+
+impl Ident {
+	/// Returns the identification value for the next fragmented packet sent between two addresses.
+	pub fn next(&mut self, proto: Protocol, src: IpAddr, dst: IpAddr) -> u32 {
+		let idx = self.key.hash_one((0u8, src, dst)) as usize % IDENT_COUNTERS;
+		let offset = self.key.hash_one((1u8, src, dst, proto)) as u32;
+
+		self.counters[idx] = self.counters[idx].wrapping_add(1);
+
+		offset.wrapping_add(self.counters[idx])
+	}
+}
+
+#[test]
+fn test_reassembly() {
+	use core::net::Ipv4Addr;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use stakker::Fwd;
+
+	use crate::link;
+	use crate::{udp, SocketAddr};
+
+	let (mut s, a, b) = link::connect();
+
+	let got = Rc::new(RefCell::new(Vec::new()));
+	let g = got.clone();
+
+	let sock_a = a.query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+	let _sock_b = b.query(&mut s, |n, cx| udp::Socket::bind(n, cx, 7, Fwd::new(move |(_, buf, _): (SocketAddr, Slice, Info)| g.borrow_mut().push(buf.to_vec())))).unwrap().unwrap();
+
+	// The datagram exceeds the MTU of the link, so it is split into three fragments.
+	let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+	for addr in [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), "fd00::2".parse().unwrap()] {
+		let data = data.clone();
+
+		sock_a.write(SocketAddr { addr, port: 7 }, move |buf| {
+			buf.push(&data[..]);
+		});
+	}
+
+	link::run(&mut s);
+
+	assert_eq!(*got.borrow(), [data.clone(), data]);
+
+	let stats = b.query(&mut s, |n, _| n.stats().fragment).unwrap();
+	assert_eq!((stats.received, stats.reassembled, stats.invalid), (6, 2, 0));
+
+	assert_eq!(a.query(&mut s, |n, _| n.stats().ip.frag_creates), Some(6));
+}
+
+#[test]
+fn test_limits() {
+	use core::net::Ipv6Addr;
+
+	use crate::link;
+
+	let (mut s, a, _b) = link::connect();
+
+	let info = Info { dst: "fd00::1".parse().unwrap(), tos: 0.into(), ttl: 64 };
+	let key = |n: u32| Key { addr: IpAddr::V6(Ipv6Addr::from(n as u128)), proto: Protocol::Udp, ident: n };
+
+	let insert = |s: &mut stakker::Stakker, key: Key, header: &[u8], start: u16, len: usize| {
+		let header = header.to_vec();
+
+		a.query(s, move |n, cx| {
+			let mut buf = Slice::new(len);
+			buf.fill(0);
+
+			n.handle_fragment(cx, key, info, &header, Fragment { more: true, start, buf }).is_ok()
+		})
+		.unwrap()
+	};
+
+	// Empty fragments other than the last are discarded, rather than starting a reassembly.
+	assert!(!insert(&mut s, key(0), &[], 8, 0));
+
+	// Reassemblies from many sources are bounded.
+	for n in 0..MAX_REASSEMBLIES as u32 + 10 {
+		assert!(insert(&mut s, key(n), &[], 8, 8));
+	}
+
+	let store = |n: &crate::Interface| (n.fragment.map.len(), n.fragment.bytes, n.fragment.stats);
+
+	let (len, bytes, stats) = a.query(&mut s, |n, _| store(n)).unwrap();
+	assert_eq!((len, stats.evicted, stats.invalid), (MAX_REASSEMBLIES, 10, 1));
+	assert_eq!(bytes, MAX_REASSEMBLIES * (STATE_COST + FRAGMENT_COST + 8));
+
+	// The headers of first fragments are charged to the memory limit.
+	for n in 0..MAX_REASSEMBLIES as u32 {
+		insert(&mut s, key(n + MAX_REASSEMBLIES as u32), &[0; 1024], 0, 8);
+	}
+
+	let (len, bytes, _) = a.query(&mut s, |n, _| store(n)).unwrap();
+	assert!(bytes > len * (STATE_COST + FRAGMENT_COST + 8));
+	assert!(bytes <= MAX_BYTES);
+}
+
+#[test]
+fn test_data_len() {
+	assert_eq!(data_len(1500, 20), Some(1480));
+	assert_eq!(data_len(1280, 48), Some(1232));
+	assert_eq!(data_len(68, 60), Some(8));
+	assert_eq!(data_len(68, 61), None);
+	assert_eq!(data_len(60, 68), None);
+}
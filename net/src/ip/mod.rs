@@ -5,87 +5,230 @@ use std::net::SocketAddrV4;
 use bilge::prelude::*;
 use collections::bytes::{Cursor, Slice};
 use log::warn;
-use stakker::{call, CX};
+use stakker::CX;
 use utils::bytes::{self, Cast};
 use utils::error::*;
 
+use self::filter::{Action, Chain, Packet};
+use crate::trace::{Event, Headers, Reason};
+use crate::{icmp, link, stats};
+
 mod checksum;
+pub(crate) mod ext;
 
+pub mod addr;
+pub mod options;
 pub mod v4;
 pub mod v6;
 
+pub mod conntrack;
+pub mod filter;
 pub mod fragment;
+pub mod nat;
+pub mod pmtu;
+pub(crate) mod port;
+pub mod route;
 
+pub use addr::{Addresses, Cidr, Kind};
 pub use checksum::Checksum;
+pub use options::Options;
+pub use route::{Route, Table};
 
-#[derive(Clone, Copy)]
 pub struct Interface {
-	v4: Ipv4Addr,
-	v6: Ipv6Addr,
+	/// The addresses and prefixes assigned to the interface.
+	pub addrs: Addresses,
+	/// The routing table.
+	pub routes: Table,
+	/// Whether packets which are not addressed to the interface are forwarded.
+	pub forwarding: bool,
+	pub stats: stats::Ip,
+	/// The buffer payloads are written to before they are filtered and fragmented, which is large enough for the largest payload. It is
+	/// zeroed, as writers may skip over fields they leave unset.
+	scratch: Box<[u8]>,
 }
 
 impl Interface {
-	pub fn new(v4: Ipv4Addr, v6: Ipv6Addr) -> Self {
-		Self { v4, v6 }
+	/// Creates an interface with a single address of each family, and default routes of both families over `link`.
+	pub fn new(v4: Ipv4Addr, v6: Ipv6Addr, link: link::Id) -> Self {
+		let mut addrs = Addresses::default();
+
+		// The initial addresses are assigned without a subnet.
+		let _ = addrs.add(Cidr::host(IpAddr::V4(v4)), Kind::Address);
+		let _ = addrs.add(Cidr::host(IpAddr::V6(v6)), Kind::Address);
+
+		let mut routes = Table::default();
+
+		let _ = routes.add(Route::new(Cidr::ALL_V4, link));
+		let _ = routes.add(Route::new(Cidr::ALL_V6, link));
+
+		Self { addrs, routes, forwarding: false, stats: stats::Ip::default(), scratch: vec![0; u16::MAX as usize].into_boxed_slice() }
 	}
+}
+
+/// Computes the checksum of the pseudo-header of an upper-layer protocol, not including the upper-layer packet length.
+#[inline]
+pub(crate) fn pseudo_checksum(proto: Protocol, src: IpAddr, dst: IpAddr) -> Checksum {
+	let mut csum = Checksum::default();
 
-	#[inline]
-	pub(crate) fn pseudo_checksum(&self, proto: Protocol, addr: IpAddr) -> Checksum {
+	for addr in [src, dst] {
 		match addr {
-			IpAddr::V4(addr) => {
-				let mut csum = Checksum::with(bytes::cast(&addr));
-				csum.push_chunk(bytes::cast(&self.v4));
-				csum.push_chunk(&[0, 0, 0, proto.into()]);
-				csum
-			}
-			IpAddr::V6(addr) => {
-				let mut csum = Checksum::with(bytes::cast(&addr));
-				csum.push_chunk(bytes::cast(&self.v6));
-				csum.push_chunk(&[0, 0, 0, proto.into()]);
-				csum
-			}
+			IpAddr::V4(addr) => csum.push_chunk(bytes::cast(&addr)),
+			IpAddr::V6(addr) => csum.push(bytes::as_slice(&addr)),
 		}
 	}
+
+	csum.push_chunk(&[0, 0, 0, proto.into()]);
+	csum
 }
 
 impl crate::Interface {
-	pub fn recv(&mut self, _: CX![], buf: Slice) {
+	pub fn recv(&mut self, cx: CX![], buf: Slice) {
 		#[cfg(feature = "pcap")]
 		let _ = self.pcap.log(&buf);
 
-		let ver = bytes::cast::<Prefix, _>(&*buf).ver();
+		self.ip.stats.in_receives += 1;
+		self.trace.emit_packet(&buf, Event::Recv);
+
+		let Some(prefix) = bytes::try_cast::<Prefix, _>(&*buf) else {
+			self.drop_packet(Reason::Truncated, || None);
+			return warn!("Received empty IP packet");
+		};
+
+		let _ = match prefix.ver() {
+			Version::V4 => self.recv_v4(cx, buf),
+			Version::V6 => self.recv_v6(cx, buf),
+			Version::Unknown => {
+				self.drop_packet(Reason::Version, || None);
+				return warn!("Invalid IP packet version");
+			}
+		};
+	}
+
+	/// Enables or disables forwarding of packets which are not addressed to the interface, so that it acts as a router between its links.
+	pub fn set_forwarding(&mut self, _: CX![], enabled: bool) {
+		self.ip.forwarding = enabled;
+	}
+
+	/// Writes a packet to the link selected by the routing table, fragmenting it if it exceeds the MTU. The source address should be chosen
+	/// with [`Addresses::source`].
+	pub(crate) fn write(&mut self, cx: CX![], protocol: Protocol, src: IpAddr, dst: IpAddr, params: Params, f: impl FnOnce(Cursor)) {
+		self.ip.stats.out_requests += 1;
+
+		let headers = Headers { proto: protocol.into(), src, dst };
+
+		let Some(link) = self.egress(dst) else {
+			self.drop_packet(Reason::NoRoute, || Some(headers));
+			return warn!("No route to {dst}");
+		};
+
+		// Probes are only limited by the link, as they are used to discover the path MTU.
+		let mtu = if params.probe { link.mtu } else { self.path_mtu(cx, dst) };
 
-		let _ = match ver {
-			Version::V4 => self.ip.recv_v4(self, buf),
-			Version::V6 => self.ip.recv_v6(self, buf),
-			Version::Unknown => return warn!("Invalid IP packet version"),
+		let max = match dst {
+			IpAddr::V4(_) => v4::max_payload(&params.options),
+			IpAddr::V6(_) => u16::MAX as usize,
 		};
+
+		// Write the payload up front, so that it can be filtered and split into fragments. Only the written bytes are copied out, and then
+		// cleared for the next payload.
+		let ((), len) = Cursor::slice(&mut self.ip.scratch[..max], f);
+		let payload = self.ip.scratch[..len].to_vec();
+		self.ip.scratch[..len].fill(0);
+
+		// Packets rejected by the output chain are dropped, as there is no remote source to report them to.
+		if self.inspect(cx, Chain::Output, Packet::new(protocol.into(), src, dst, params.tos, &payload)) != Action::Accept {
+			self.drop_packet(Reason::Filtered(Chain::Output), || Some(headers));
+			return;
+		}
+
+		match (src, dst) {
+			(IpAddr::V4(src), IpAddr::V4(dst)) => self.send_v4(protocol, src, dst, params, mtu, payload),
+			(IpAddr::V6(src), IpAddr::V6(dst)) => self.send_v6(protocol, src, dst, params, mtu, payload),
+			_ => warn!("Source address {src} and destination address {dst} are of different families"),
+		}
 	}
 
-	pub(crate) fn write(&mut self, _: CX![], protocol: Protocol, addr: IpAddr, tos: ToS, f: impl FnOnce(Cursor) + 'static) {
-		let ip = self.ip;
+	/// Queues a single packet write on the link selected by the routing table for a destination address.
+	fn emit(&self, dst: IpAddr, f: impl FnOnce(Cursor) + 'static) {
+		let Some(link) = self.egress(dst) else {
+			return warn!("No route to {dst}");
+		};
+
 		#[cfg(feature = "pcap")]
 		let pcap = self.pcap.clone();
 
-		call!(
-			[self.link],
-			write(move |mut buf: Cursor<'_>| {
-				match addr {
-					IpAddr::V4(addr) => ip.write_v4(buf.fork(), protocol, addr, tos, f),
-					IpAddr::V6(addr) => ip.write_v6(buf.fork(), protocol, addr, tos, f),
-				}
+		let trace = self.trace.clone();
 
-				#[cfg(feature = "pcap")]
-				let _ = pcap.log(&buf[..buf.pivot()]);
-			})
-		)
+		link.write(move |mut buf: Cursor<'_>| {
+			f(buf.fork());
+
+			trace.emit_packet(&buf[..buf.pivot()], Event::Send);
+
+			#[cfg(feature = "pcap")]
+			let _ = pcap.log(&buf[..buf.pivot()]);
+		})
 	}
 
-	pub(crate) fn handle<'a>(&'a mut self, proto: Protocol, addr: IpAddr, buf: Slice) -> Result {
+	/// Passes the payload of a received packet to its upper-layer protocol. Errors are reported to the source quoting the packet, which is the
+	/// received packet, or the reassembled packet beginning with the headers of its first fragment.
+	pub(crate) fn handle<'a>(&'a mut self, cx: CX![], proto: Protocol, addr: IpAddr, info: Info, buf: Slice, packet: &[u8]) -> Result {
+		let headers = Headers { proto: proto.into(), src: addr, dst: info.dst };
+
+		match self.inspect(cx, Chain::Input, Packet::new(proto.into(), addr, info.dst, info.tos, &buf)) {
+			Action::Accept => {}
+			Action::Drop => {
+				self.drop_packet(Reason::Filtered(Chain::Input), || Some(headers));
+				return Err(());
+			}
+			Action::Reject => {
+				self.drop_packet(Reason::Filtered(Chain::Input), || Some(headers));
+				self.send_icmp_error(cx, icmp::Error::Prohibited, packet);
+				return Err(());
+			}
+		}
+
 		match proto {
-			Protocol::Udp => self.udp.recv(&self.ip, addr, buf),
-			Protocol::Tcp => self.tcp.recv(&self.ip, addr, buf),
-			Protocol::Unknown => Err(log::debug!("Unimplemented IP protocol")),
+			Protocol::Icmp if addr.is_ipv4() => {
+				self.deliver(headers);
+				self.recv_icmp_v4(cx, addr, info, buf)
+			}
+			Protocol::Icmpv6 if addr.is_ipv6() => {
+				self.deliver(headers);
+				self.recv_icmp_v6(cx, addr, info, buf)
+			}
+			Protocol::Udp => {
+				self.deliver(headers);
+				match self.udp.recv(addr, info, buf) {
+					Ok(port) => Ok(self.trace.emit(|| Event::DeliverUdp { headers, port })),
+					Err(reason) => {
+						if reason == Reason::NoPort {
+							self.send_icmp_error(cx, icmp::Error::PortUnreachable, packet);
+						}
+
+						self.drop_packet(reason, || Some(headers));
+						Err(())
+					}
+				}
+			}
+			Protocol::Tcp => {
+				self.deliver(headers);
+				self.tcp.recv(&self.ip, addr, buf)
+			}
+			_ => {
+				if let Some(result) = self.decapsulate(proto.into(), addr, buf.clone()) {
+					self.deliver(headers);
+					return result;
+				}
+
+				if !self.raw.recv(proto.into(), addr, info, buf.clone()) {
+					self.drop_packet(Reason::UnknownProtocol, || Some(headers));
+					self.send_icmp_error(cx, icmp::Error::ProtocolUnreachable, packet);
+					return Err(log::debug!("Unimplemented IP protocol"));
+				}
+
+				self.deliver(headers);
+				Ok(())
+			}
 		}
 	}
 }
@@ -151,24 +294,126 @@ pub struct Prefix {
 	ver: Version,
 }
 
+/// The default time-to-live of outgoing packets.
+const DEFAULT_TTL: u8 = 64;
+
+/// The parameters of the IP headers of outgoing packets.
+#[derive(Clone, Copy)]
+pub(crate) struct Params {
+	pub tos: ToS,
+	/// The time-to-live, or hop limit for IPv6.
+	pub ttl: u8,
+	/// The IPv4 header options. Ignored for IPv6.
+	pub options: Options,
+	/// Whether the Don't Fragment flag is set. Ignored for IPv6.
+	pub df: Df,
+	/// Whether the packet is a path MTU probe, which is never fragmented and is not limited by the estimated path MTU.
+	pub probe: bool,
+}
+
+impl Default for Params {
+	fn default() -> Self {
+		Self {
+			tos: ToS::new(ECN::NotECT, DiffServ::Default),
+			ttl: DEFAULT_TTL,
+			options: Options::default(),
+			df: Df::default(),
+			probe: false,
+		}
+	}
+}
+
+/// Whether the Don't Fragment flag is set on outgoing IPv4 packets.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Df {
+	/// Set the flag, unless the packet exceeds the MTU, in which case it is fragmented without the flag.
+	#[default]
+	Want,
+	/// Always set the flag, discarding packets which exceed the MTU.
+	Do,
+	/// Never set the flag.
+	Dont,
+}
+
+/// The IP header fields of a received packet.
+#[derive(Clone, Copy)]
+pub struct Info {
+	/// The local address the packet was sent to.
+	pub dst: IpAddr,
+	/// The type-of-service, or traffic class for IPv6.
+	pub tos: ToS,
+	/// The remaining time-to-live, or hop limit for IPv6.
+	pub ttl: u8,
+}
+
+/// The type-of-service field of the IPv4 header, and the traffic class field of the IPv6 header.
 #[bitsize(8)]
 #[derive(Clone, Copy, FromBits, Cast)]
 #[repr(C)]
 pub struct ToS {
-	ecn: ECN,
-	ds: DiffServ,
+	pub ecn: ECN,
+	pub ds: DiffServ,
 }
 
+/// The Differentiated Services code point, which selects the forwarding behaviour of a packet.
+#[repr(u8)]
 #[bitsize(6)]
-#[derive(FromBits)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, FromBits)]
 pub enum DiffServ {
+	/// Default forwarding, also known as Class Selector 0.
 	Default = 0,
+	/// Lower-Effort, as specified by RFC 8622.
+	Le = 1,
+	/// Class Selector 1.
+	Cs1 = 8,
+	/// Assured Forwarding class 1, low drop precedence.
+	Af11 = 10,
+	/// Assured Forwarding class 1, medium drop precedence.
+	Af12 = 12,
+	/// Assured Forwarding class 1, high drop precedence.
+	Af13 = 14,
+	/// Class Selector 2.
+	Cs2 = 16,
+	/// Assured Forwarding class 2, low drop precedence.
+	Af21 = 18,
+	/// Assured Forwarding class 2, medium drop precedence.
+	Af22 = 20,
+	/// Assured Forwarding class 2, high drop precedence.
+	Af23 = 22,
+	/// Class Selector 3.
+	Cs3 = 24,
+	/// Assured Forwarding class 3, low drop precedence.
+	Af31 = 26,
+	/// Assured Forwarding class 3, medium drop precedence.
+	Af32 = 28,
+	/// Assured Forwarding class 3, high drop precedence.
+	Af33 = 30,
+	/// Class Selector 4.
+	Cs4 = 32,
+	/// Assured Forwarding class 4, low drop precedence.
+	Af41 = 34,
+	/// Assured Forwarding class 4, medium drop precedence.
+	Af42 = 36,
+	/// Assured Forwarding class 4, high drop precedence.
+	Af43 = 38,
+	/// Class Selector 5.
+	Cs5 = 40,
+	/// Voice-Admit, as specified by RFC 5865.
+	VoiceAdmit = 44,
+	/// Expedited Forwarding.
+	Ef = 46,
+	/// Class Selector 6, used for network control traffic.
+	Cs6 = 48,
+	/// Class Selector 7.
+	Cs7 = 56,
+	/// A code point without a standardised behaviour.
 	#[fallback]
-	Unknown,
+	Unknown(u6),
 }
 
+/// The Explicit Congestion Notification codepoint, as specified by RFC 3168.
 #[bitsize(2)]
-#[derive(FromBits)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, FromBits)]
 pub enum ECN {
 	NotECT = 0b00,
 	ECT1 = 0b01,
@@ -180,8 +425,218 @@ pub enum ECN {
 #[bitsize(8)]
 #[derive(Hash, PartialEq, Eq, Clone, Copy, FromBits)]
 pub enum Protocol {
+	/// IPv6 Hop-by-Hop Options.
+	HopByHop = 0,
+	Icmp = 1,
 	Tcp = 6,
 	Udp = 17,
+	/// Routing Header for IPv6.
+	Ipv6Route = 43,
+	/// Fragment Header for IPv6.
+	Ipv6Frag = 44,
+	/// ICMP for IPv6.
+	Icmpv6 = 58,
+	/// No Next Header for IPv6.
+	Ipv6NoNxt = 59,
+	/// Destination Options for IPv6.
+	Ipv6Opts = 60,
+	/// Any other protocol, with its number.
 	#[fallback]
-	Unknown,
+	Unknown(u8),
+}
+
+/// Builds a packet with a transport header, filling in the TCP and UDP ports, or the identifier of ICMP echo messages, and computing its
+/// checksums.
+#[cfg(test)]
+pub(crate) fn packet(proto: Protocol, src: SocketAddr, dst: SocketAddr, mut transport: Vec<u8>) -> Vec<u8> {
+	match proto {
+		Protocol::Udp | Protocol::Tcp => {
+			transport[0..2].copy_from_slice(&src.port.to_be_bytes());
+			transport[2..4].copy_from_slice(&dst.port.to_be_bytes());
+
+			let len = transport.len() as u16;
+
+			match proto {
+				Protocol::Udp => transport[4..6].copy_from_slice(&len.to_be_bytes()),
+				_ => transport[12] = 0x50,
+			}
+		}
+		Protocol::Icmp | Protocol::Icmpv6 => match transport[0] {
+			icmp::V4_ECHO_REQUEST | icmp::V6_ECHO_REQUEST => transport[4..6].copy_from_slice(&src.port.to_be_bytes()),
+			icmp::V4_ECHO_REPLY | icmp::V6_ECHO_REPLY => transport[4..6].copy_from_slice(&dst.port.to_be_bytes()),
+			_ => {}
+		},
+		_ => {}
+	}
+
+	let mut packet = match (src.addr, dst.addr) {
+		(IpAddr::V4(s), IpAddr::V4(d)) => {
+			let len = (20 + transport.len()) as u16;
+			[&[0x45, 0][..], &len.to_be_bytes(), &[0, 0, 0x40, 0, 64, proto.into(), 0, 0], &s.octets(), &d.octets()].concat()
+		}
+		(IpAddr::V6(s), IpAddr::V6(d)) => {
+			let len = transport.len() as u16;
+			[&[0x60, 0, 0, 0][..], &len.to_be_bytes(), &[proto.into(), 64], &s.octets(), &d.octets()].concat()
+		}
+		_ => unreachable!(),
+	};
+
+	packet.extend(transport);
+	checksum(&mut packet);
+	packet
+}
+
+/// Computes the checksums of a packet from scratch, and those of a complete packet quoted by an ICMP error message.
+#[cfg(test)]
+pub(crate) fn checksum(packet: &mut [u8]) {
+	let (l4, len, proto, src, dst) = match packet[0] >> 4 {
+		4 => {
+			let l4 = 4 * (packet[0] & 0xf) as usize;
+
+			packet[10..12].fill(0);
+			let csum = Checksum::of(&packet[..l4]).end();
+			packet[10..12].copy_from_slice(&csum);
+
+			let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+			(l4, len, Protocol::from(packet[9]), IpAddr::V4(*bytes::cast(&packet[12..])), IpAddr::V4(*bytes::cast(&packet[16..])))
+		}
+		_ => {
+			let len = 40 + u16::from_be_bytes([packet[4], packet[5]]) as usize;
+			(40, len, Protocol::from(packet[6]), IpAddr::V6(*bytes::cast(&packet[8..])), IpAddr::V6(*bytes::cast(&packet[24..])))
+		}
+	};
+
+	// Truncated packets, such as those quoted by ICMPv4 error messages, and fragments other than the first cannot be checksummed.
+	if len != packet.len() || (src.is_ipv4() && u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0) {
+		return;
+	}
+
+	let at = l4 + match proto {
+		Protocol::Udp => 6,
+		Protocol::Tcp => 16,
+		Protocol::Icmp | Protocol::Icmpv6 => 2,
+		_ => return,
+	};
+
+	if proto == Protocol::Udp && src.is_ipv4() && packet[at..at + 2] == [0, 0] {
+		return;
+	}
+
+	let error = match proto {
+		Protocol::Icmp => icmp::is_error_v4(packet[l4]),
+		Protocol::Icmpv6 => packet[l4] & 0x80 == 0,
+		_ => false,
+	};
+
+	if error {
+		checksum(&mut packet[l4 + 8..]);
+	}
+
+	let mut csum = match proto {
+		Protocol::Icmp => Checksum::default(),
+		_ => {
+			let mut csum = pseudo_checksum(proto, src, dst);
+			csum.push(&((packet.len() - l4) as u16).to_be_bytes());
+			csum
+		}
+	};
+
+	packet[at..at + 2].fill(0);
+	csum.push(&packet[l4..]);
+
+	let csum = match csum.end() {
+		[0, 0] if proto == Protocol::Udp => [0xff, 0xff],
+		csum => csum,
+	};
+
+	packet[at..at + 2].copy_from_slice(&csum);
+}
+
+/// Delivers a packet to an interface as if it was received from its link, and runs the operations it queues.
+#[cfg(test)]
+pub(crate) fn deliver(s: &mut stakker::Stakker, n: &stakker::ActorOwn<crate::Interface>, packet: &[u8]) {
+	let mut buf = Slice::new(packet.len());
+	buf.copy_from_slice(packet);
+
+	n.query(s, |n, cx| n.recv(cx, buf));
+	link::run(s);
+}
+
+#[test]
+fn test_forward() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use collections::bytes::Slice;
+	use stakker::Fwd;
+
+	use crate::icmp::{Kind, Report, Unreachable};
+	use crate::link;
+	use crate::udp::Socket;
+
+	let (mut s, a, r, c) = link::router(1280);
+
+	let received = Rc::new(RefCell::new(Vec::new()));
+	let errors = Rc::new(RefCell::new(Vec::new()));
+
+	let (rx, errs) = (received.clone(), errors.clone());
+
+	let mut sock = a.query(&mut s, |n, cx| Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+	let _sock = c.query(&mut s, |n, cx| Socket::bind(n, cx, 7, Fwd::new(move |(src, buf, info): (SocketAddr, Slice, Info)| rx.borrow_mut().push((src.addr, buf.len(), info.ttl))))).unwrap().unwrap();
+	sock.set_error_callback(Fwd::new(move |report: Report| errs.borrow_mut().push(report)));
+
+	let write = |s: &mut stakker::Stakker, sock: &Socket, dst: IpAddr, len: usize| {
+		sock.write(SocketAddr { addr: dst, port: 7 }, move |buf| {
+			buf.push(&vec![0u8; len][..]);
+		});
+
+		link::run(s);
+	};
+
+	for (src, dst, router) in [("10.0.0.1", "10.1.0.1", "10.0.0.254"), ("fd00::1", "fd01::1", "fd00::fe")] {
+		let (src, dst, router): (IpAddr, IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap(), router.parse().unwrap());
+
+		// The TTL is decremented, and the checksum recomputed, or the receiver would discard the packet.
+		write(&mut s, &sock, dst, 100);
+		assert_eq!(received.borrow_mut().pop(), Some((src, 100, DEFAULT_TTL - 1)));
+
+		// Packets whose TTL expires at the router are answered with Time Exceeded.
+		sock.set_ttl(1);
+		write(&mut s, &sock, dst, 100);
+		sock.set_ttl(DEFAULT_TTL);
+
+		assert!(received.borrow().is_empty());
+
+		let report = errors.borrow_mut().pop().unwrap();
+		assert_eq!((report.from, report.kind, report.quoted.dst.addr), (router, Kind::TimeExceeded { reassembly: false }, dst));
+	}
+
+	assert_eq!(r.query(&mut s, |n, _| n.stats().ip.forw_datagrams), Some(2));
+
+	let mtu = |s: &mut stakker::Stakker, dst: &str| a.query(s, |n, cx| n.path_mtu(cx, dst.parse().unwrap())).unwrap();
+
+	// Packets without the Don't Fragment flag which exceed the MTU of the egress link are fragmented by the router.
+	sock.set_df(Df::Dont);
+	write(&mut s, &sock, "10.1.0.1".parse().unwrap(), 1400);
+	sock.set_df(Df::Want);
+
+	assert_eq!(received.borrow_mut().pop(), Some(("10.0.0.1".parse().unwrap(), 1400, DEFAULT_TTL - 1)));
+	assert_eq!(r.query(&mut s, |n, _| n.stats().ip.frag_creates), Some(2));
+	assert_eq!(mtu(&mut s, "10.1.0.1"), 1500);
+
+	// Packets with the flag, and all IPv6 packets, are discarded, and the path MTU is lowered to that of the egress link. Resent packets
+	// are fragmented by the source.
+	for (dst, kind) in [("10.1.0.1", Kind::Unreachable(Unreachable::FragmentationNeeded(1280))), ("fd01::1", Kind::PacketTooBig(1280))] {
+		write(&mut s, &sock, dst.parse().unwrap(), 1400);
+
+		assert!(received.borrow().is_empty());
+		assert_eq!(errors.borrow_mut().pop().map(|report| report.kind), Some(kind));
+		assert_eq!(mtu(&mut s, dst), 1280);
+
+		write(&mut s, &sock, dst.parse().unwrap(), 1400);
+		assert_eq!(received.borrow_mut().pop().map(|(_, len, _)| len), Some(1400));
+	}
+
+	assert!(errors.borrow().is_empty());
+	assert_eq!(r.query(&mut s, |n, _| n.stats().ip.frag_creates), Some(2));
 }
@@ -0,0 +1,557 @@
+//! Source network address translation (masquerading) of forwarded packets, as specified by RFC 4787, RFC 5382 and RFC 5508.
+//!
+//! Fragmented packets are not translated in either direction, as only the first fragment carries the transport header. IPv6 packets with
+//! extension headers are not translated either.
+
+use core::net::IpAddr;
+use core::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use log::{debug, warn};
+use stakker::CX;
+use utils::bytes;
+use utils::error::*;
+
+use super::port::Ephemeral;
+use super::{Checksum, Protocol, SocketAddr};
+use crate::icmp;
+use crate::link;
+
+/// The time after which an idle UDP binding expires, as recommended by RFC 4787.
+const UDP_TIMEOUT: Duration = Duration::from_secs(300);
+/// The time after which an idle TCP binding expires, as required by RFC 5382.
+const TCP_TIMEOUT: Duration = Duration::from_secs(7440);
+/// The time after which a TCP binding expires once a FIN or RST segment is seen, as recommended by RFC 5382.
+const TCP_TRANSITORY: Duration = Duration::from_secs(240);
+/// The time after which an idle ICMP query binding expires, as recommended by RFC 5508.
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum number of bindings.
+const MAX_BINDINGS: usize = 4096;
+
+/// The TCP FIN and RST flags.
+const TCP_FIN_RST: u8 = 0x05;
+
+/// Indices of the source and destination of a packet.
+const SRC: usize = 0;
+const DST: usize = 1;
+
+/// A transport-layer flow. ICMP query messages use the query identifier as the port of the querying endpoint, and port zero for the
+/// other endpoint.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct Flow {
+	proto: Protocol,
+	src: SocketAddr,
+	dst: SocketAddr,
+}
+
+struct Binding {
+	/// The translated source of the flow.
+	outside: SocketAddr,
+	/// The time after which the binding expires if it is not used.
+	timeout: Duration,
+	/// The time at which the binding was last used.
+	used: Instant,
+}
+
+impl Binding {
+	/// Returns the time at which the binding expires, unless it is used again.
+	fn expires(&self) -> Instant {
+		self.used + self.timeout
+	}
+
+	fn expired(&self, now: Instant) -> bool {
+		self.expires() <= now
+	}
+}
+
+/// The translation table.
+#[derive(Default)]
+pub struct Table {
+	/// The links whose forwarded packets are masqueraded.
+	links: Vec<link::Id>,
+	/// The bindings, keyed by the untranslated outbound flow.
+	bindings: HashMap<Flow, Binding>,
+	/// The untranslated outbound flows, keyed by the translated inbound flow.
+	reverse: HashMap<Flow, Flow>,
+	/// The translated source ports in use.
+	ports: HashSet<(Protocol, u16)>,
+	/// The allocator of TCP ports. UDP ports are allocated alongside the ports of ephemeral sockets.
+	tcp: Ephemeral,
+	/// The allocator of ICMP query identifiers.
+	icmp: Ephemeral,
+}
+
+impl Table {
+	/// Returns whether a port is the translated source port of a binding.
+	pub fn in_use(&self, proto: Protocol, port: u16) -> bool {
+		self.ports.contains(&(proto, port))
+	}
+
+	pub fn masquerades(&self, link: link::Id) -> bool {
+		self.links.contains(&link)
+	}
+
+	/// Stops masquerading packets forwarded to a link.
+	pub(crate) fn remove_link(&mut self, link: link::Id) {
+		self.links.retain(|&l| l != link);
+	}
+
+	fn remove(&mut self, flow: &Flow) {
+		if let Some(binding) = self.bindings.remove(flow) {
+			self.reverse.remove(&Flow { proto: flow.proto, src: flow.dst, dst: binding.outside });
+			self.ports.remove(&(flow.proto, binding.outside.port));
+		}
+	}
+}
+
+/// The offsets of the fields of a packet which are rewritten by translation.
+struct Fields {
+	proto: Protocol,
+	/// The offsets of the source and destination addresses.
+	addrs: [usize; 2],
+	/// The offsets of the source and destination ports. ICMP query messages have the query identifier in place of the port of the querying
+	/// endpoint.
+	ports: [Option<usize>; 2],
+	/// The offset of the IPv4 header checksum.
+	ip_csum: Option<usize>,
+	/// The offset of the transport checksum, if it is present.
+	csum: Option<usize>,
+	/// Whether the transport checksum covers the addresses.
+	pseudo: bool,
+	/// The offset of the TCP flags.
+	flags: Option<usize>,
+}
+
+impl Fields {
+	/// Locates the fields of a packet whose IP header has been validated. Returns `None` if the packet cannot be translated, such as
+	/// fragments, which do not all carry the transport header. A packet quoted by an ICMP error message may be truncated after the first
+	/// eight bytes of its transport header, so its transport checksum is only located if it was quoted.
+	fn of(packet: &[u8], quoted: bool) -> Option<Self> {
+		let v4 = packet[0] >> 4 == 4;
+
+		let (proto, addrs, l4, ip_csum) = if v4 {
+			if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+				return None;
+			}
+
+			(Protocol::from(packet[9]), [12, 16], 4 * (packet[0] & 0xf) as usize, Some(10))
+		} else {
+			(Protocol::from(packet[6]), [8, 24], 40, None)
+		};
+
+		let transport = packet.get(l4..)?;
+
+		let (ports, csum, flags) = match proto {
+			Protocol::Udp if transport.len() >= 8 => {
+				// A zero IPv4 UDP checksum indicates that no checksum was computed.
+				let csum = (!v4 || transport[6..8] != [0, 0]).then_some(l4 + 6);
+				([Some(l4), Some(l4 + 2)], csum, None)
+			}
+			Protocol::Tcp if transport.len() >= if quoted { 8 } else { 20 } => ([Some(l4), Some(l4 + 2)], Some(l4 + 16), Some(l4 + 13)),
+			Protocol::Icmp | Protocol::Icmpv6 if transport.len() >= 8 && v4 == (proto == Protocol::Icmp) => {
+				let ports = match transport[0] {
+					icmp::V4_ECHO_REQUEST | icmp::V6_ECHO_REQUEST => [Some(l4 + 4), None],
+					icmp::V4_ECHO_REPLY | icmp::V6_ECHO_REPLY => [None, Some(l4 + 4)],
+					_ => return None,
+				};
+
+				(ports, Some(l4 + 2), None)
+			}
+			_ => return None,
+		};
+
+		let csum = csum.filter(|&at| at + 2 <= packet.len());
+		let flags = flags.filter(|&at| at < packet.len());
+
+		Some(Self { proto, addrs, ports, ip_csum, csum, pseudo: proto != Protocol::Icmp, flags })
+	}
+
+	fn endpoint(&self, packet: &[u8], side: usize) -> SocketAddr {
+		let at = self.addrs[side];
+
+		let addr = match self.ip_csum {
+			Some(_) => IpAddr::V4(*bytes::cast(&packet[at..])),
+			None => IpAddr::V6(*bytes::cast(&packet[at..])),
+		};
+
+		let port = self.ports[side].map_or(0, |at| u16::from_be_bytes([packet[at], packet[at + 1]]));
+
+		SocketAddr { addr, port }
+	}
+
+	fn flow(&self, packet: &[u8]) -> Flow {
+		Flow { proto: self.proto, src: self.endpoint(packet, SRC), dst: self.endpoint(packet, DST) }
+	}
+
+	/// Returns whether the packet is a TCP segment with the FIN or RST flag set.
+	fn closing(&self, packet: &[u8]) -> bool {
+		self.flags.is_some_and(|at| packet[at] & TCP_FIN_RST != 0)
+	}
+
+	/// Rewrites the source or destination of a packet, updating the checksums which cover it.
+	fn rewrite(&self, packet: &mut [u8], side: usize, to: SocketAddr) {
+		let csums = [self.ip_csum, self.csum.filter(|_| self.pseudo)];
+
+		match to.addr {
+			IpAddr::V4(addr) => replace(packet, self.addrs[side], &addr.octets(), csums),
+			IpAddr::V6(addr) => replace(packet, self.addrs[side], &addr.octets(), csums),
+		}
+
+		if let Some(at) = self.ports[side] {
+			replace(packet, at, &to.port.to_be_bytes(), [self.csum, None]);
+		}
+
+		// A computed UDP checksum of zero is transmitted as all ones.
+		if let Some(at) = self.csum.filter(|_| self.proto == Protocol::Udp) {
+			if packet[at..at + 2] == [0, 0] {
+				packet[at..at + 2].copy_from_slice(&[0xff, 0xff]);
+			}
+		}
+	}
+}
+
+/// Replaces bytes at an offset of a packet, incrementally updating the checksums at the given offsets.
+fn replace(packet: &mut [u8], at: usize, new: &[u8], csums: [Option<usize>; 2]) {
+	for c in csums.into_iter().flatten() {
+		let mut csum = Checksum::resume([packet[c], packet[c + 1]]);
+		csum.pull(&packet[at..at + new.len()]);
+		csum.push(new);
+
+		packet[c..c + 2].copy_from_slice(&csum.end());
+	}
+
+	packet[at..at + new.len()].copy_from_slice(new);
+}
+
+/// Returns the time after which an idle binding of a protocol expires.
+fn timeout(proto: Protocol) -> Duration {
+	match proto {
+		Protocol::Tcp => TCP_TIMEOUT,
+		Protocol::Udp => UDP_TIMEOUT,
+		_ => ICMP_TIMEOUT,
+	}
+}
+
+impl crate::Interface {
+	/// Enables or disables masquerading of packets forwarded to a link. The source of masqueraded TCP, UDP and ICMP query packets is
+	/// translated to an address of the interface and an ephemeral port, and replies are translated back.
+	pub fn set_masquerade(&mut self, _: CX![], link: link::Id, enabled: bool) {
+		self.nat.remove_link(link);
+
+		if enabled {
+			self.nat.links.push(link);
+		}
+	}
+
+	/// Translates the source of a packet forwarded to a masqueraded link, creating a binding for its flow if there is none.
+	pub(super) fn masquerade(&mut self, cx: CX![], packet: &mut [u8]) -> Result {
+		let Some(fields) = Fields::of(packet, false) else {
+			warn!("Cannot masquerade fragment, or packet of an unsupported protocol");
+			return Err(());
+		};
+
+		let flow = fields.flow(packet);
+		let now = cx.now();
+
+		if self.nat.bindings.get(&flow).is_some_and(|b| b.expired(now)) {
+			self.nat.remove(&flow);
+		}
+
+		if !self.nat.bindings.contains_key(&flow) {
+			if self.nat.bindings.len() >= MAX_BINDINGS {
+				warn!("Cannot masquerade packet from {}, as the translation table is full", flow.src);
+				return Err(());
+			}
+
+			let Some(addr) = self.ip.addrs.source(flow.dst.addr) else {
+				warn!("No source address to masquerade packets to {}", flow.dst.addr);
+				return Err(());
+			};
+
+			let nat = &mut self.nat;
+			let proto = flow.proto;
+
+			let port = match proto {
+				Protocol::Udp => self.udp.next_port(|port| nat.ports.contains(&(proto, port))),
+				Protocol::Tcp => nat.tcp.next(|port| nat.ports.contains(&(proto, port))),
+				_ => nat.icmp.next(|port| nat.ports.contains(&(proto, port))),
+			};
+
+			let Some(port) = port else {
+				warn!("Cannot masquerade packet from {}, as every port is in use", flow.src);
+				return Err(());
+			};
+
+			let outside = SocketAddr { addr, port };
+			debug!("Masquerading flow from {} to {} as {outside}", flow.src, flow.dst);
+
+			nat.ports.insert((proto, port));
+			nat.reverse.insert(Flow { proto, src: flow.dst, dst: outside }, flow);
+			nat.bindings.insert(flow, Binding { outside, timeout: timeout(proto), used: now });
+			self.expire_binding_after(cx, flow, timeout(proto));
+		}
+
+		let binding = self.nat.bindings.get_mut(&flow).unwrap();
+		binding.used = now;
+
+		let outside = binding.outside;
+
+		if fields.closing(packet) {
+			self.close_binding(cx, flow);
+		}
+
+		fields.rewrite(packet, SRC, outside);
+
+		Ok(())
+	}
+
+	/// Translates the destination of a packet received on a masqueraded flow back to the host which sent the flow. Returns false if the
+	/// packet does not belong to a masqueraded flow.
+	pub(super) fn unmasquerade(&mut self, cx: CX![], packet: &mut [u8]) -> bool {
+		if self.nat.bindings.is_empty() {
+			return false;
+		}
+
+		let Some(fields) = Fields::of(packet, false) else { return self.unmasquerade_error(cx, packet) };
+		let Some(&flow) = self.nat.reverse.get(&fields.flow(packet)) else { return false };
+
+		let now = cx.now();
+		let binding = self.nat.bindings.get_mut(&flow).unwrap();
+
+		if binding.expired(now) {
+			self.nat.remove(&flow);
+			return false;
+		}
+
+		binding.used = now;
+
+		if fields.closing(packet) {
+			self.close_binding(cx, flow);
+		}
+
+		fields.rewrite(packet, DST, flow.src);
+
+		true
+	}
+
+	/// Translates an ICMP error message quoting a packet of a masqueraded flow back to the host which sent the flow, as required by RFC 5508.
+	/// Both the destination of the message and the source of the quoted packet are translated. Returns false if the packet is not such a
+	/// message.
+	fn unmasquerade_error(&mut self, cx: CX![], packet: &mut [u8]) -> bool {
+		let v4 = packet[0] >> 4 == 4;
+
+		let (l4, addrs, ip_csum) = if v4 {
+			if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 || packet[9] != Protocol::Icmp.into() {
+				return false;
+			}
+
+			(4 * (packet[0] & 0xf) as usize, [12, 16], Some(10))
+		} else {
+			if packet[6] != Protocol::Icmpv6.into() {
+				return false;
+			}
+
+			(40, [8, 24], None)
+		};
+
+		// Informational ICMPv6 messages have the high-order bit of their type set.
+		if !packet.get(l4).is_some_and(|&ty| if v4 { icmp::is_error_v4(ty) } else { ty & 0x80 == 0 }) {
+			return false;
+		}
+
+		let at = l4 + 8;
+
+		// The quoted packet must have a complete IP header of the same version as the message.
+		if packet.len() < at + if v4 { 20 } else { 40 } || packet[at] >> 4 != packet[0] >> 4 {
+			return false;
+		}
+
+		let Some(quoted) = Fields::of(&packet[at..], true) else { return false };
+		let Flow { proto, src, dst } = quoted.flow(&packet[at..]);
+		let Some(&flow) = self.nat.reverse.get(&Flow { proto, src: dst, dst: src }) else { return false };
+
+		if self.nat.bindings[&flow].expired(cx.now()) {
+			self.nat.remove(&flow);
+			return false;
+		}
+
+		debug!("Translating ICMP error for flow from {} to {}", flow.src, flow.dst);
+
+		// The ICMP checksum covers the quoted packet, whose checksums are also updated by its translation.
+		let before = packet[at..].to_vec();
+		quoted.rewrite(&mut packet[at..], SRC, flow.src);
+
+		let mut csum = Checksum::resume([packet[l4 + 2], packet[l4 + 3]]);
+		csum.pull(&before);
+		csum.push(&packet[at..]);
+		packet[l4 + 2..l4 + 4].copy_from_slice(&csum.end());
+
+		// Only the ICMPv6 checksum covers the addresses of the message.
+		let csums = [ip_csum, (!v4).then_some(l4 + 2)];
+
+		match flow.src.addr {
+			IpAddr::V4(addr) => replace(packet, addrs[DST], &addr.octets(), csums),
+			IpAddr::V6(addr) => replace(packet, addrs[DST], &addr.octets(), csums),
+		}
+
+		true
+	}
+
+	/// Shortens the timeout of a binding whose TCP connection is closing, arming a timer for the earlier expiry.
+	fn close_binding(&mut self, cx: CX![], flow: Flow) {
+		let Some(binding) = self.nat.bindings.get_mut(&flow) else { return };
+
+		if binding.timeout != TCP_TRANSITORY {
+			binding.timeout = TCP_TRANSITORY;
+			self.expire_binding_after(cx, flow, TCP_TRANSITORY);
+		}
+	}
+
+	/// Arms the timer which expires a binding.
+	fn expire_binding_after(&mut self, cx: CX![], flow: Flow, delay: Duration) {
+		let actor = cx.access_actor().clone();
+		cx.after(delay, move |s| actor.apply(s, move |this, cx| this.expire_binding(cx, flow)));
+	}
+
+	/// Removes a binding if it has expired, releasing its port, or rearms its timer if it has been used since the timer was armed.
+	fn expire_binding(&mut self, cx: CX![], flow: Flow) {
+		let Some(binding) = self.nat.bindings.get(&flow) else { return };
+		let expires = binding.expires();
+
+		if expires <= cx.now() {
+			debug!("Binding of flow from {} to {} as {} expired", flow.src, flow.dst, binding.outside);
+			self.nat.remove(&flow);
+		} else {
+			self.expire_binding_after(cx, flow, expires - cx.now());
+		}
+	}
+}
+
+/// Asserts that the checksums of a translated packet are those computed from scratch.
+#[cfg(test)]
+fn assert_checksums(packet: &[u8]) {
+	let mut expected = packet.to_vec();
+	super::checksum(&mut expected);
+	assert_eq!(packet, expected);
+}
+
+#[test]
+fn test_translate() {
+	use super::packet;
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+
+	let v4 = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let echo = |ty| [&[ty][..], &[0; 7], b"ping"].concat();
+
+	// Each flow has an inside and an outside endpoint, and a transport header and payload.
+	let flows = [
+		(Protocol::Udp, v4("192.168.0.2", 5000), v4("8.8.8.8", 53), [&[0; 8][..], b"odd"].concat()),
+		(Protocol::Udp, v4("fd02::2", 5000), v4("fd01::53", 53), [&[0; 8][..], b"query"].concat()),
+		(Protocol::Tcp, v4("192.168.0.2", 40000), v4("8.8.8.8", 80), [&[0; 20][..], b"GET /"].concat()),
+		(Protocol::Tcp, v4("fd02::2", 40000), v4("fd01::80", 80), [&[0; 20][..], b"GET /"].concat()),
+		(Protocol::Icmp, v4("192.168.0.2", 1234), v4("8.8.8.8", 0), echo(icmp::V4_ECHO_REQUEST)),
+		(Protocol::Icmpv6, v4("fd02::2", 1234), v4("fd01::53", 0), echo(icmp::V6_ECHO_REQUEST)),
+	];
+
+	for (proto, inside, server, transport) in flows {
+		let mut out = packet(proto, inside, server, transport.clone());
+		a.query(&mut s, |n, cx| n.masquerade(cx, &mut out)).unwrap().unwrap();
+		assert_checksums(&out);
+
+		// The source is translated to the address of the interface and an unused port.
+		let flow = Fields::of(&out, false).unwrap().flow(&out);
+		let outside = flow.src;
+
+		assert_eq!(outside.addr, if inside.addr.is_ipv4() { "10.0.0.1" } else { "fd00::1" }.parse::<IpAddr>().unwrap());
+		assert!(flow.dst == server && a.query(&mut s, |n, _| n.nat.in_use(proto, outside.port)).unwrap());
+
+		// Replies are translated back to the inside endpoint.
+		let reply = match proto {
+			Protocol::Icmp => echo(icmp::V4_ECHO_REPLY),
+			Protocol::Icmpv6 => echo(icmp::V6_ECHO_REPLY),
+			_ => transport,
+		};
+
+		let mut back = packet(proto, server, outside, reply);
+		assert!(a.query(&mut s, |n, cx| n.unmasquerade(cx, &mut back)).unwrap());
+		assert_checksums(&back);
+		assert!(Fields::of(&back, false).unwrap().flow(&back) == Flow { proto, src: server, dst: inside });
+
+		// The destination of an ICMP error message quoting a translated packet, and the source of the quoted packet, are translated back.
+		let router = v4(if inside.addr.is_ipv4() { "10.0.0.254" } else { "fd00::fe" }, 0);
+
+		let (icmp, error) = match inside.addr {
+			IpAddr::V4(_) => (Protocol::Icmp, [3, 3]),
+			IpAddr::V6(_) => (Protocol::Icmpv6, [1, 4]),
+		};
+
+		let mut error = packet(icmp, router, outside, [&error[..], &[0; 6], &out].concat());
+		assert!(a.query(&mut s, |n, cx| n.unmasquerade(cx, &mut error)).unwrap());
+		assert_checksums(&error);
+
+		let (dst, at) = match inside.addr {
+			IpAddr::V4(_) => (IpAddr::V4(*bytes::cast(&error[16..])), 28),
+			IpAddr::V6(_) => (IpAddr::V6(*bytes::cast(&error[24..])), 48),
+		};
+
+		assert_eq!(dst, inside.addr);
+		assert!(Fields::of(&error[at..], true).unwrap().flow(&error[at..]) == Flow { proto, src: inside, dst: server });
+	}
+}
+
+#[test]
+fn test_expiry() {
+	use super::packet;
+	use crate::link;
+
+	let (mut s, a, _) = link::capture();
+
+	let inside = SocketAddr { addr: "192.168.0.2".parse().unwrap(), port: 5000 };
+	let server = SocketAddr { addr: "8.8.8.8".parse().unwrap(), port: 80 };
+
+	let bind = |s: &mut stakker::Stakker, proto, flags| {
+		let mut transport = vec![0; 20];
+		transport[13] = flags;
+
+		let mut out = packet(proto, inside, server, transport);
+		a.query(s, |n, cx| n.masquerade(cx, &mut out)).unwrap().unwrap();
+
+		Fields::of(&out, false).unwrap().flow(&out).src
+	};
+
+	let advance = |s: &mut stakker::Stakker, by| {
+		let now = s.now() + by;
+		s.run(now, false);
+	};
+
+	let second = Duration::from_secs(1);
+
+	// Using a binding defers its expiry.
+	let udp = bind(&mut s, Protocol::Udp, 0);
+	advance(&mut s, UDP_TIMEOUT - second);
+	assert_eq!(bind(&mut s, Protocol::Udp, 0), udp);
+
+	advance(&mut s, 2 * second);
+	assert!(a.query(&mut s, |n, _| n.nat.in_use(Protocol::Udp, udp.port)).unwrap());
+
+	// The port is released once the binding has been idle for the timeout, and replies are no longer translated.
+	advance(&mut s, UDP_TIMEOUT);
+	assert!(!a.query(&mut s, |n, _| n.nat.in_use(Protocol::Udp, udp.port)).unwrap());
+
+	let mut reply = packet(Protocol::Udp, server, udp, vec![0; 8]);
+	assert!(!a.query(&mut s, |n, cx| n.unmasquerade(cx, &mut reply)).unwrap());
+
+	// TCP bindings expire sooner once a FIN segment is seen.
+	let tcp = bind(&mut s, Protocol::Tcp, 0);
+	advance(&mut s, TCP_TRANSITORY);
+	assert!(a.query(&mut s, |n, _| n.nat.in_use(Protocol::Tcp, tcp.port)).unwrap());
+
+	assert_eq!(bind(&mut s, Protocol::Tcp, 0x01), tcp);
+	advance(&mut s, TCP_TRANSITORY + second);
+	assert!(!a.query(&mut s, |n, _| n.nat.in_use(Protocol::Tcp, tcp.port)).unwrap());
+	assert!(a.query(&mut s, |n, _| n.nat.bindings.is_empty()).unwrap());
+}
@@ -0,0 +1,396 @@
+//! IPv4 header options.
+
+use core::mem;
+use core::net::Ipv4Addr;
+use core::ops::Deref;
+
+use bilge::prelude::*;
+use log::warn;
+use utils::bytes;
+use utils::error::*;
+
+/// The maximum length of the options of an IPv4 header.
+pub const MAX_LEN: usize = 40;
+
+/// The type octet of an option.
+#[bitsize(8)]
+#[derive(PartialEq, Eq, Clone, Copy, FromBits)]
+pub enum Kind {
+	/// End of Option List. Marks the end of all options, and is used as padding at the end of the header.
+	Eol = 0,
+	/// No Operation. May be used between options to align the beginning of a subsequent option.
+	Nop = 1,
+	/// Record Route. Records the route of a datagram.
+	RecordRoute = 7,
+	/// Internet Timestamp. Records the times at which a datagram reaches each hop.
+	Timestamp = 68,
+	/// Security. Carries the security classification of a datagram, as specified by RFC 1108.
+	Security = 130,
+	/// Loose Source and Record Route. Specifies hops which a datagram must pass through, in order.
+	Lsrr = 131,
+	/// Strict Source and Record Route. Specifies the exact hops which a datagram must pass through.
+	Ssrr = 137,
+	/// Router Alert. Requests that routers more closely examine the contents of a datagram.
+	RouterAlert = 148,
+	#[fallback]
+	Unknown,
+}
+
+/// The contents of the flag field of a timestamp option.
+#[bitsize(4)]
+#[derive(PartialEq, Eq, Clone, Copy, FromBits)]
+pub enum TimestampFlag {
+	/// Only timestamps are recorded.
+	Timestamps = 0,
+	/// Each timestamp is preceded by the address of the recording host.
+	Addresses = 1,
+	/// The addresses of the hosts which record timestamps are prespecified.
+	Prespecified = 3,
+	#[fallback]
+	Unknown,
+}
+
+#[bitsize(8)]
+#[derive(FromBits)]
+struct TimestampMeta {
+	flag: TimestampFlag,
+	overflow: u4,
+}
+
+/// A parsed option.
+pub enum Opt<'a> {
+	/// A Record Route option, where `next` is the index of the next address slot to fill.
+	RecordRoute { next: usize, route: &'a [Ipv4Addr] },
+	/// A source route option, where `next` is the index of the next hop in the route.
+	SourceRoute { strict: bool, next: usize, route: &'a [Ipv4Addr] },
+	/// A Timestamp option, where `next` is the byte offset into `data` of the next slot to fill.
+	Timestamp {
+		flag: TimestampFlag,
+		overflow: u8,
+		next: usize,
+		data: &'a [u8],
+	},
+	/// A Security option, containing its raw data.
+	Security(&'a [u8]),
+	/// A Router Alert option, containing its value.
+	RouterAlert(u16),
+	/// An option with an unrecognised type, and its raw data.
+	Unknown(u8, &'a [u8]),
+}
+
+/// Validates the length and pointer fields of a route option, returning the index of the next address and the address slots.
+fn route(data: &[u8]) -> Result<(usize, &[Ipv4Addr])> {
+	let (&ptr, addrs) = data.split_first().ok_or_else(|| warn!("Route option is missing a pointer"))?;
+
+	if addrs.len() % 4 != 0 {
+		warn!("Route option length is not a multiple of the address length");
+		return Err(());
+	}
+
+	// The pointer is relative to the start of the option, and indexes the next address slot.
+	if ptr < 4 || ptr % 4 != 0 || ptr as usize > addrs.len() + 4 {
+		warn!("Route option has invalid pointer {ptr}");
+		return Err(());
+	}
+
+	Ok((ptr as usize / 4 - 1, bytes::as_slice(addrs)))
+}
+
+/// Parses and validates the data of a single option.
+fn parse(ty: u8, data: &[u8]) -> Result<Opt<'_>> {
+	let opt = match Kind::from(ty) {
+		Kind::RecordRoute => {
+			let (next, route) = route(data)?;
+			Opt::RecordRoute { next, route }
+		}
+		kind @ (Kind::Lsrr | Kind::Ssrr) => {
+			let (next, route) = route(data)?;
+			Opt::SourceRoute { strict: kind == Kind::Ssrr, next, route }
+		}
+		Kind::Timestamp => {
+			let [ptr, meta, data @ ..] = data else {
+				warn!("Timestamp option is too short");
+				return Err(());
+			};
+
+			let meta = TimestampMeta::from(*meta);
+			let flag = meta.flag();
+
+			let slot = match flag {
+				TimestampFlag::Timestamps => 4,
+				TimestampFlag::Addresses | TimestampFlag::Prespecified => 8,
+				TimestampFlag::Unknown => {
+					warn!("Timestamp option has invalid flag");
+					return Err(());
+				}
+			};
+
+			if data.len() % slot != 0 {
+				warn!("Timestamp option length is not a multiple of its slot length");
+				return Err(());
+			}
+
+			// The pointer is relative to the start of the option, and indexes the next timestamp slot.
+			if *ptr < 5 || !(*ptr as usize - 5).is_multiple_of(slot) || *ptr as usize > data.len() + 5 {
+				warn!("Timestamp option has invalid pointer {ptr}");
+				return Err(());
+			}
+
+			Opt::Timestamp {
+				flag,
+				overflow: meta.overflow().value(),
+				next: *ptr as usize - 5,
+				data,
+			}
+		}
+		Kind::Security => {
+			if data.is_empty() {
+				warn!("Security option is too short");
+				return Err(());
+			}
+
+			Opt::Security(data)
+		}
+		Kind::RouterAlert => {
+			let &[hi, lo] = data else {
+				warn!("Router Alert option has invalid length");
+				return Err(());
+			};
+
+			Opt::RouterAlert(u16::from_be_bytes([hi, lo]))
+		}
+		_ => Opt::Unknown(ty, data),
+	};
+
+	Ok(opt)
+}
+
+/// Parses and validates a sequence of options, calling `f` for each option. Fails if any option is malformed, or if a source route or
+/// record route option appears more than once.
+pub fn visit(mut buf: &[u8], mut f: impl FnMut(Opt<'_>) -> Result) -> Result {
+	let mut seen_route = false;
+	let mut seen_record = false;
+
+	while let Some((&ty, rest)) = buf.split_first() {
+		match Kind::from(ty) {
+			// The rest of the options are padding.
+			Kind::Eol => break,
+			Kind::Nop => {
+				buf = rest;
+				continue;
+			}
+			Kind::Lsrr | Kind::Ssrr if mem::replace(&mut seen_route, true) => {
+				warn!("Packet has more than one source route option");
+				return Err(());
+			}
+			Kind::RecordRoute if mem::replace(&mut seen_record, true) => {
+				warn!("Packet has more than one record route option");
+				return Err(());
+			}
+			_ => {}
+		}
+
+		// The length octet counts the type and length octets.
+		let len = match rest.first() {
+			Some(&len) if len >= 2 && len as usize <= buf.len() => len as usize,
+			_ => {
+				warn!("Option of type {ty} has invalid length");
+				return Err(());
+			}
+		};
+
+		f(parse(ty, &buf[2..len])?)?;
+
+		buf = &buf[len..];
+	}
+
+	Ok(())
+}
+
+/// Encoded options to be written to outgoing IPv4 headers.
+#[derive(Clone, Copy)]
+pub struct Options {
+	/// The encoded options, followed by zeroed padding.
+	buf: [u8; MAX_LEN],
+	/// The length of the encoded options.
+	len: usize,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self { buf: [0; MAX_LEN], len: 0 }
+	}
+}
+
+impl Options {
+	/// Appends an option with a type and data, followed by `slots` zeroed bytes.
+	fn push(mut self, kind: Kind, data: &[u8], slots: usize) -> Result<Self> {
+		let len = 2 + data.len() + slots;
+
+		if self.len + len > MAX_LEN {
+			warn!("IPv4 options exceed {MAX_LEN} bytes");
+			return Err(());
+		}
+
+		let buf = &mut self.buf[self.len..];
+
+		buf[0] = kind.into();
+		buf[1] = len as u8;
+		buf[2..][..data.len()].copy_from_slice(data);
+
+		// The slots following the data are already zeroed.
+		self.len += len;
+
+		Ok(self)
+	}
+
+	/// Creates options from the encoded options of a received header, failing if they exceed the maximum length.
+	pub fn decode(buf: &[u8]) -> Result<Self> {
+		let mut options = Self::default();
+
+		let Some(dst) = options.buf.get_mut(..buf.len()) else {
+			warn!("IPv4 options exceed {MAX_LEN} bytes");
+			return Err(());
+		};
+
+		dst.copy_from_slice(buf);
+		options.len = buf.len();
+
+		Ok(options)
+	}
+
+	/// Appends an empty Record Route option with room for `n` addresses.
+	pub fn record_route(self, n: usize) -> Result<Self> {
+		self.push(Kind::RecordRoute, &[4], n.saturating_mul(4))
+	}
+
+	/// Appends an empty Timestamp option with room for `n` timestamps.
+	pub fn timestamp(self, n: usize) -> Result<Self> {
+		let meta = TimestampMeta::new(TimestampFlag::Timestamps, u4::new(0));
+		self.push(Kind::Timestamp, &[5, meta.into()], n.saturating_mul(4))
+	}
+
+	/// Appends a Security option with the given data.
+	pub fn security(self, data: &[u8]) -> Result<Self> {
+		self.push(Kind::Security, data, 0)
+	}
+
+	/// Appends a Router Alert option with the given value.
+	pub fn router_alert(self, value: u16) -> Result<Self> {
+		self.push(Kind::RouterAlert, &value.to_be_bytes(), 0)
+	}
+
+	/// Returns the options which must be copied into every fragment of a datagram, as indicated by the highest-order bit of their type.
+	pub fn copied(&self) -> Self {
+		let mut copied = Self::default();
+		let mut buf = &self.buf[..self.len];
+
+		while let [ty, ..] = *buf {
+			// Every option but End of Option List and No Operation has a length octet.
+			let len = match Kind::from(ty) {
+				Kind::Eol => break,
+				Kind::Nop => 1,
+				_ => buf.get(1).map_or(buf.len(), |&len| (len as usize).clamp(2, buf.len())),
+			};
+
+			let (opt, rest) = buf.split_at(len);
+
+			if ty & 0x80 != 0 {
+				copied.buf[copied.len..][..opt.len()].copy_from_slice(opt);
+				copied.len += opt.len();
+			}
+
+			buf = rest;
+		}
+
+		copied
+	}
+}
+
+impl Deref for Options {
+	type Target = [u8];
+
+	/// Returns the encoded options, padded to a multiple of 4 bytes with End of Option List octets.
+	fn deref(&self) -> &Self::Target {
+		&self.buf[..self.len.next_multiple_of(4)]
+	}
+}
+
+#[test]
+fn test_visit() {
+	let buf = [1, 7, 11, 8, 10, 0, 0, 1, 0, 0, 0, 0, 148, 4, 0, 0, 68, 12, 5, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff];
+	let mut count = 0;
+
+	visit(&buf, |opt| {
+		match opt {
+			Opt::RecordRoute { next, route } => {
+				assert_eq!(next, 1);
+				assert_eq!(route, &[Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::UNSPECIFIED]);
+			}
+			Opt::Timestamp { flag, overflow, next, data } => {
+				assert!(flag == TimestampFlag::Addresses);
+				assert_eq!((overflow, next, data.len()), (0, 0, 8));
+			}
+			Opt::RouterAlert(value) => assert_eq!(value, 0),
+			_ => panic!("unexpected option"),
+		}
+
+		count += 1;
+		Ok(())
+	})
+	.unwrap();
+
+	// The options following End of Option List are ignored.
+	assert_eq!(count, 3);
+}
+
+#[test]
+fn test_visit_invalid() {
+	let ok = |_: Opt<'_>| Ok(());
+
+	// Invalid lengths.
+	assert!(visit(&[7, 1], ok).is_err());
+	assert!(visit(&[7, 7, 4, 0], ok).is_err());
+	assert!(visit(&[148], ok).is_err());
+	assert!(visit(&[148, 3, 0], ok).is_err());
+
+	// Invalid pointers.
+	assert!(visit(&[7, 7, 3, 0, 0, 0, 0], ok).is_err());
+	assert!(visit(&[7, 7, 12, 0, 0, 0, 0], ok).is_err());
+	assert!(visit(&[68, 8, 6, 0, 0, 0, 0, 0], ok).is_err());
+
+	// An invalid timestamp flag.
+	assert!(visit(&[68, 8, 5, 2, 0, 0, 0, 0], ok).is_err());
+
+	// Repeated route options.
+	assert!(visit(&[7, 3, 4, 7, 3, 4], ok).is_err());
+	assert!(visit(&[131, 3, 4, 137, 3, 4], ok).is_err());
+	assert!(visit(&[7, 3, 4, 131, 3, 4], ok).is_ok());
+}
+
+#[test]
+fn test_options() {
+	let options = Options::default().record_route(2).unwrap().router_alert(1).unwrap();
+	assert_eq!(&*options, &[7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, 148, 4, 0, 1, 0]);
+
+	let mut count = 0;
+
+	visit(&options, |_| {
+		count += 1;
+		Ok(())
+	})
+	.unwrap();
+
+	assert_eq!(count, 2);
+
+	// The Record Route option is not copied into fragments.
+	let options = options.security(&[1, 2]).unwrap();
+	assert_eq!(&*options.copied(), &[148, 4, 0, 1, 130, 4, 1, 2]);
+
+	assert!(options.timestamp(5).is_err());
+	assert!(Options::default().record_route(10).is_err());
+	assert!(Options::default().record_route(9).is_ok());
+
+	assert_eq!(&*Options::decode(&[1, 1, 1]).unwrap(), &[1, 1, 1, 0]);
+	assert!(Options::decode(&[1; MAX_LEN + 1]).is_err());
+}
@@ -0,0 +1,356 @@
+//! Path MTU discovery.
+
+use core::net::IpAddr;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::time::Instant;
+
+use stakker::CX;
+
+/// The minimum MTU of an IPv4 path, as specified by RFC 791.
+pub const MIN_V4: usize = 68;
+/// The minimum MTU of an IPv6 path, as specified by RFC 8200.
+pub const MIN_V6: usize = 1280;
+
+/// The time after which a path MTU estimate is discarded, allowing larger packets to be sent again, as recommended by RFC 8201.
+const AGE: Duration = Duration::from_secs(600);
+/// The maximum number of destinations with a path MTU estimate.
+const MAX_ENTRIES: usize = 1024;
+
+/// The plateau values of RFC 1191, used to estimate the path MTU when a router does not report the MTU of the next hop.
+const PLATEAUS: [usize; 10] = [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+/// Returns the minimum MTU of the path to an address.
+pub fn min(addr: IpAddr) -> usize {
+	match addr {
+		IpAddr::V4(_) => MIN_V4,
+		IpAddr::V6(_) => MIN_V6,
+	}
+}
+
+/// Returns the largest plateau value smaller than the length of a packet which was too big for the path.
+pub fn plateau(len: usize) -> usize {
+	PLATEAUS.into_iter().find(|&mtu| mtu < len).unwrap_or(MIN_V4)
+}
+
+struct Entry {
+	/// The estimated path MTU.
+	mtu: usize,
+	/// The time at which the estimate was last lowered.
+	updated: Instant,
+}
+
+/// Caches path MTU estimates learned from ICMP messages.
+#[derive(Default)]
+pub struct Cache {
+	map: HashMap<IpAddr, Entry>,
+}
+
+impl Cache {
+	/// Returns the estimated path MTU to an address, if it has not aged out.
+	pub fn get(&self, now: Instant, addr: IpAddr) -> Option<usize> {
+		self.map.get(&addr).filter(|e| now - e.updated < AGE).map(|e| e.mtu)
+	}
+
+	/// Lowers the estimated path MTU to an address. Returns false if the estimate was not lowered.
+	pub fn lower(&mut self, now: Instant, addr: IpAddr, mtu: usize) -> bool {
+		if self.get(now, addr).is_some_and(|cur| cur <= mtu) {
+			return false;
+		}
+
+		if self.map.len() >= MAX_ENTRIES && !self.map.contains_key(&addr) {
+			// Discard the aged entries, or the oldest entry if there are none.
+			self.map.retain(|_, e| now - e.updated < AGE);
+
+			if self.map.len() >= MAX_ENTRIES {
+				if let Some(oldest) = self.map.iter().min_by_key(|(_, e)| e.updated).map(|(addr, _)| *addr) {
+					self.map.remove(&oldest);
+				}
+			}
+		}
+
+		self.map.insert(addr, Entry { mtu, updated: now });
+
+		true
+	}
+}
+
+/// The default probe timeout, after which an unacknowledged probe is considered lost, as specified by RFC 8899.
+pub const PROBE_TIMER: Duration = Duration::from_secs(15);
+/// The default time after which a completed search should be restarted with [`Search::raise`], as specified by RFC 8899.
+pub const RAISE_TIMER: Duration = Duration::from_secs(600);
+
+/// The number of times a probe of a given size is sent before the size is considered unusable.
+const MAX_PROBES: u8 = 3;
+/// The packet size which is confirmed before searching for a larger path MTU.
+const BASE_V4: usize = 1200;
+
+/// The state of a packetization-layer path MTU search.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+	/// The base size is being confirmed.
+	Base,
+	/// Probes of increasing size are being sent.
+	Searching,
+	/// The largest usable size has been found.
+	Complete,
+	/// The base size could not be confirmed, and the path MTU is assumed to be the minimum.
+	Error,
+}
+
+/// A packetization-layer path MTU search, as specified by RFC 8899.
+///
+/// The search does not send packets itself. Upper-layer protocols send probes of the size returned by [`Search::probe`], report
+/// acknowledged probes with [`Search::confirm`], and report probes which were not acknowledged within [`PROBE_TIMER`] with
+/// [`Search::lost`]. All sizes are IP packet lengths.
+#[derive(Clone)]
+pub struct Search {
+	state: State,
+	/// The smallest packet size the path must support.
+	min: usize,
+	/// The size which is confirmed before searching.
+	base: usize,
+	/// The largest confirmed packet size.
+	plpmtu: usize,
+	/// The largest packet size which may be usable.
+	max: usize,
+	/// The number of lost probes of the current size.
+	count: u8,
+}
+
+impl Search {
+	/// Creates a search for the path to `addr`, where `max` is the MTU of the link. Sizes never exceed the MTU of the link, even if it is
+	/// below the minimum MTU of the path.
+	pub fn new(addr: IpAddr, max: usize) -> Self {
+		let min = min(addr).min(max);
+		let base = if addr.is_ipv4() { BASE_V4 } else { MIN_V6 }.min(max);
+
+		Self { state: State::Base, min, base, plpmtu: min, max, count: 0 }
+	}
+
+	pub fn state(&self) -> State {
+		self.state
+	}
+
+	/// Returns the largest packet size which is known to be usable.
+	pub fn plpmtu(&self) -> usize {
+		self.plpmtu
+	}
+
+	/// Returns the size of the next probe, or `None` if the search is complete.
+	pub fn probe(&self) -> Option<usize> {
+		match self.state {
+			State::Base | State::Error => Some(self.base),
+			// Probe the midpoint of the sizes which may be usable.
+			State::Searching => Some(self.plpmtu + (self.max - self.plpmtu).div_ceil(2)),
+			State::Complete => None,
+		}
+	}
+
+	/// Reports that a probe of the given size was acknowledged.
+	pub fn confirm(&mut self, size: usize) {
+		if Some(size) != self.probe() {
+			return;
+		}
+
+		self.plpmtu = size;
+		self.count = 0;
+		self.state = if self.plpmtu >= self.max { State::Complete } else { State::Searching };
+	}
+
+	/// Reports that the current probe was not acknowledged in time.
+	pub fn lost(&mut self) {
+		let Some(size) = self.probe() else { return };
+
+		self.count += 1;
+
+		if self.count < MAX_PROBES {
+			return;
+		}
+
+		self.count = 0;
+
+		match self.state {
+			State::Base => self.state = State::Error,
+			State::Searching => {
+				self.max = size - 1;
+
+				if self.plpmtu >= self.max {
+					self.state = State::Complete;
+				}
+			}
+			State::Error | State::Complete => {}
+		}
+	}
+
+	/// Reports the MTU of a Packet Too Big or Fragmentation Needed message for the path.
+	pub fn ptb(&mut self, mtu: usize) {
+		if mtu < self.min {
+			return;
+		}
+
+		self.max = self.max.min(mtu);
+
+		if mtu < self.plpmtu {
+			// The confirmed size is no longer usable, so confirm the base size again.
+			self.plpmtu = self.min;
+			self.base = self.base.min(mtu);
+			self.state = State::Base;
+			self.count = 0;
+		}
+	}
+
+	/// Restarts a completed search, to find out whether the path now supports larger packets.
+	pub fn raise(&mut self, max: usize) {
+		if self.state == State::Complete {
+			self.max = max;
+			self.state = if self.plpmtu >= self.max { State::Complete } else { State::Searching };
+		}
+	}
+}
+
+impl crate::Interface {
+	/// Returns the estimated MTU of the path to an address, which is the largest IP packet that can be sent to it without fragmentation. The
+	/// estimate is limited by the MTU of the link selected by the routing table, or is the minimum if there is no route to the address.
+	pub fn path_mtu(&self, cx: CX![], addr: IpAddr) -> usize {
+		let Some(link) = self.egress(addr) else {
+			return min(addr);
+		};
+
+		self.pmtu.get(cx.now(), addr).map_or(link.mtu, |mtu| mtu.min(link.mtu))
+	}
+}
+
+#[test]
+fn test_plateau() {
+	assert_eq!(plateau(1500), 1492);
+	assert_eq!(plateau(1492), 1006);
+	assert_eq!(plateau(40000), 32000);
+	assert_eq!(plateau(68), MIN_V4);
+}
+
+#[test]
+fn test_cache() {
+	let now = Instant::now();
+	let addr = |n: u32| IpAddr::from(n.to_be_bytes());
+
+	let mut cache = Cache::default();
+
+	assert!(cache.lower(now, addr(1), 1400));
+	assert!(!cache.lower(now, addr(1), 1450));
+	assert!(cache.lower(now, addr(1), 1300));
+	assert_eq!(cache.get(now, addr(1)), Some(1300));
+
+	// Estimates age out, after which they may be raised.
+	assert_eq!(cache.get(now + AGE, addr(1)), None);
+	assert!(cache.lower(now + AGE, addr(1), 1450));
+
+	// The oldest estimate is evicted when the cache is full.
+	let mut cache = Cache::default();
+
+	for n in 0..MAX_ENTRIES as u32 {
+		cache.lower(now + Duration::from_secs(n as u64 % 2), addr(n), 1400);
+	}
+
+	assert!(cache.lower(now + Duration::from_secs(2), addr(MAX_ENTRIES as u32), 1400));
+	assert_eq!(cache.map.len(), MAX_ENTRIES);
+	assert_eq!((0..MAX_ENTRIES as u32).filter(|&n| cache.get(now, addr(n)).is_none()).count(), 1);
+
+	// Aged estimates are discarded before the oldest.
+	let later = now + AGE + Duration::from_secs(1);
+	assert!(cache.lower(later, addr(u32::MAX), 1400));
+	assert!(cache.map.len() < MAX_ENTRIES);
+}
+
+/// Runs a search over a path with the given MTU, returning the number of probes sent.
+#[cfg(test)]
+fn search(search: &mut Search, mtu: usize) -> usize {
+	let mut probes = 0;
+
+	while let Some(size) = search.probe() {
+		probes += 1;
+		assert!(probes < 64);
+
+		if search.state() == State::Error {
+			break;
+		}
+
+		if size <= mtu {
+			search.confirm(size);
+		} else {
+			search.lost();
+		}
+	}
+
+	probes
+}
+
+#[test]
+fn test_search() {
+	let v4 = IpAddr::from([10, 0, 0, 2]);
+
+	let mut s = Search::new(v4, 1500);
+	assert_eq!((s.state(), s.probe(), s.plpmtu()), (State::Base, Some(BASE_V4), MIN_V4));
+
+	// Sizes other than the current probe are not confirmed.
+	s.confirm(1000);
+	assert_eq!(s.state(), State::Base);
+
+	s.confirm(BASE_V4);
+	assert_eq!((s.state(), s.probe()), (State::Searching, Some(1350)));
+
+	search(&mut s, 1400);
+	assert_eq!((s.state(), s.plpmtu(), s.probe()), (State::Complete, 1400, None));
+
+	// A smaller MTU reported by the path restarts the search from the base.
+	s.ptb(MIN_V4 - 1);
+	assert_eq!(s.state(), State::Complete);
+
+	s.ptb(1300);
+	assert_eq!((s.state(), s.plpmtu(), s.probe()), (State::Base, MIN_V4, Some(BASE_V4)));
+
+	search(&mut s, 1300);
+	assert_eq!((s.state(), s.plpmtu()), (State::Complete, 1300));
+
+	// Raising the maximum searches for a larger MTU.
+	s.raise(1500);
+	assert_eq!(s.state(), State::Searching);
+
+	search(&mut s, 1500);
+	assert_eq!((s.state(), s.plpmtu()), (State::Complete, 1500));
+}
+
+#[test]
+fn test_search_lost() {
+	let mut s = Search::new(IpAddr::from([10, 0, 0, 2]), 1500);
+
+	for _ in 0..MAX_PROBES - 1 {
+		s.lost();
+		assert_eq!(s.state(), State::Base);
+	}
+
+	// The base is still probed after it could not be confirmed.
+	s.lost();
+	assert_eq!((s.state(), s.probe(), s.plpmtu()), (State::Error, Some(BASE_V4), MIN_V4));
+
+	s.confirm(BASE_V4);
+	assert_eq!(s.state(), State::Searching);
+}
+
+#[test]
+fn test_search_small_link() {
+	let v6: IpAddr = "fd00::2".parse().unwrap();
+
+	assert_eq!(Search::new(v6, 1500).probe(), Some(MIN_V6));
+
+	// Links below the minimum MTU are not probed beyond their MTU.
+	let mut s = Search::new(v6, 1000);
+	assert_eq!((s.probe(), s.plpmtu()), (Some(1000), 1000));
+
+	assert_eq!(search(&mut s, 1000), 1);
+	assert_eq!((s.state(), s.plpmtu()), (State::Complete, 1000));
+
+	let mut s = Search::new(IpAddr::from([10, 0, 0, 2]), 576);
+	assert_eq!(search(&mut s, 576), 1);
+	assert_eq!((s.state(), s.plpmtu()), (State::Complete, 576));
+}
@@ -0,0 +1,32 @@
+//! Allocation of ports from the ephemeral range.
+
+/// The first port of the dynamic range, as specified by RFC 6335.
+pub const EPHEMERAL: u16 = 49152;
+
+/// Allocates ports from the ephemeral range in sequence, wrapping to the start of the range.
+pub(crate) struct Ephemeral {
+	/// The last allocated port.
+	nxt: u16,
+}
+
+impl Default for Ephemeral {
+	fn default() -> Self {
+		Self { nxt: EPHEMERAL }
+	}
+}
+
+impl Ephemeral {
+	/// Returns the next port for which `in_use` returns false, or `None` if every port in the range is in use.
+	pub fn next(&mut self, mut in_use: impl FnMut(u16) -> bool) -> Option<u16> {
+		for _ in EPHEMERAL..=u16::MAX {
+			// Increment, wrapping to the ephemeral port starting index
+			self.nxt = self.nxt.checked_add(1).unwrap_or(EPHEMERAL);
+
+			if !in_use(self.nxt) {
+				return Some(self.nxt);
+			}
+		}
+
+		None
+	}
+}
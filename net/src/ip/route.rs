@@ -0,0 +1,194 @@
+//! The routing table, which selects the link each packet is written to.
+
+use core::cmp::Reverse;
+use core::fmt::Display;
+use core::net::IpAddr;
+
+use log::warn;
+use stakker::CX;
+use utils::error::*;
+
+use super::{pmtu, Cidr};
+use crate::link;
+
+/// A route to the addresses within a prefix.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Route {
+	/// The prefix of the destinations reached through the route.
+	pub dst: Cidr,
+	/// The link packets are written to.
+	pub link: link::Id,
+	/// The address of the next hop, if the destinations are not directly reachable over the link. Links deliver packets to their peer
+	/// without resolving neighbours, so the next hop is only recorded.
+	pub via: Option<IpAddr>,
+	/// The preference of the route among matching routes with the same prefix length. Lower metrics are preferred.
+	pub metric: u32,
+}
+
+impl Route {
+	/// Creates a route to the addresses within a prefix over a link, with no next hop and a metric of zero.
+	pub fn new(dst: Cidr, link: link::Id) -> Self {
+		Self { dst, link, via: None, metric: 0 }
+	}
+}
+
+impl Display for Route {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_fmt(format_args!("{} dev {}", self.dst, self.link))?;
+
+		if let Some(via) = self.via {
+			f.write_fmt(format_args!(" via {via}"))?;
+		}
+
+		f.write_fmt(format_args!(" metric {}", self.metric))
+	}
+}
+
+/// A set of routes, looked up by longest prefix match.
+#[derive(Default)]
+pub struct Table {
+	routes: Vec<Route>,
+}
+
+impl Table {
+	/// Adds a route to the table, failing if a route to the same prefix over the same link is present, or the next hop is not of the family
+	/// of the prefix. The bits of the prefix address beyond its length are cleared.
+	pub fn add(&mut self, route: Route) -> Result {
+		let route = Route { dst: route.dst.network(), ..route };
+
+		if route.via.is_some_and(|via| via.is_ipv4() != route.dst.addr().is_ipv4()) {
+			warn!("Next hop of route to {} is not of the same family", route.dst);
+			return Err(());
+		}
+
+		if self.routes.iter().any(|r| r.dst == route.dst && r.link == route.link) {
+			warn!("Route to {} over {} already exists", route.dst, route.link);
+			return Err(());
+		}
+
+		self.routes.push(route);
+
+		Ok(())
+	}
+
+	/// Removes the route to a prefix over a link, failing if it is not present.
+	pub fn remove(&mut self, dst: Cidr, link: link::Id) -> Result {
+		let dst = dst.network();
+		let idx = self.routes.iter().position(|r| r.dst == dst && r.link == link).ok_or_else(|| warn!("No route to {dst} over {link}"))?;
+		self.routes.remove(idx);
+
+		Ok(())
+	}
+
+	/// Removes every route over a link.
+	pub(crate) fn remove_link(&mut self, link: link::Id) {
+		self.routes.retain(|r| r.link != link);
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &Route> {
+		self.routes.iter()
+	}
+
+	/// Returns the route to a destination address, which is the matching route with the longest prefix, then the lowest metric.
+	pub fn lookup(&self, dst: IpAddr) -> Option<&Route> {
+		// Routes are reversed so that the earliest added route is selected when several are equally preferred.
+		self.routes.iter().rev().filter(|r| r.dst.contains(dst)).max_by_key(|r| (r.dst.prefix_len(), Reverse(r.metric)))
+	}
+}
+
+impl crate::Interface {
+	/// Adds a route to the routing table, failing if its link is not owned by the interface, or the prefix is IPv6 and the MTU of the link
+	/// is below the minimum MTU of IPv6.
+	pub fn add_route(&mut self, _: CX![], route: Route) -> Result {
+		let Some(link) = self.link(route.link) else {
+			warn!("Cannot add route to {} over unknown {}", route.dst, route.link);
+			return Err(());
+		};
+
+		if route.dst.addr().is_ipv6() && link.mtu < pmtu::MIN_V6 {
+			warn!("Cannot add route to {} over {} with MTU ({}) below the minimum IPv6 MTU ({})", route.dst, route.link, link.mtu, pmtu::MIN_V6);
+			return Err(());
+		}
+
+		self.ip.routes.add(route)
+	}
+
+	/// Removes the route to a prefix over a link from the routing table.
+	pub fn remove_route(&mut self, _: CX![], dst: Cidr, link: link::Id) -> Result {
+		self.ip.routes.remove(dst, link)
+	}
+
+	/// Returns the routes in the routing table.
+	pub fn routes(&self) -> impl Iterator<Item = &Route> {
+		self.ip.routes.iter()
+	}
+
+	/// Returns the route packets sent to an address would take.
+	pub fn route(&self, dst: IpAddr) -> Option<&Route> {
+		self.ip.routes.lookup(dst)
+	}
+
+	/// Returns the link selected by the routing table for a destination address.
+	pub(crate) fn egress(&self, dst: IpAddr) -> Option<&link::Handle> {
+		self.link(self.ip.routes.lookup(dst)?.link)
+	}
+}
+
+#[test]
+fn test_lookup() {
+	let mut table = Table::default();
+	let route = |dst: &str, link| Route::new(dst.parse().unwrap(), link::Id(link));
+	let lookup = |table: &Table, dst: &str| table.lookup(dst.parse().unwrap()).map(|r| r.link.0);
+
+	table.add(route("0.0.0.0/0", 0)).unwrap();
+	table.add(route("10.0.0.0/8", 1)).unwrap();
+	table.add(route("10.1.0.0/16", 2)).unwrap();
+	table.add(Route { metric: 10, ..route("10.1.0.0/16", 3) }).unwrap();
+
+	assert_eq!(lookup(&table, "192.168.0.1"), Some(0));
+	assert_eq!(lookup(&table, "10.2.0.1"), Some(1));
+	assert_eq!(lookup(&table, "10.1.0.1"), Some(2));
+	assert_eq!(lookup(&table, "fd00::1"), None);
+
+	// The lower metric is preferred among routes with the same prefix length.
+	table.remove("10.1.0.0/16".parse().unwrap(), link::Id(2)).unwrap();
+	assert_eq!(lookup(&table, "10.1.0.1"), Some(3));
+
+	table.remove_link(link::Id(3));
+	assert_eq!(lookup(&table, "10.1.0.1"), Some(1));
+	assert!(table.remove("10.1.0.0/16".parse().unwrap(), link::Id(3)).is_err());
+}
+
+#[test]
+fn test_normalise() {
+	let mut table = Table::default();
+
+	table.add(Route::new("10.0.0.5/8".parse().unwrap(), link::Id(0))).unwrap();
+	assert!(table.add(Route::new("10.0.0.0/8".parse().unwrap(), link::Id(0))).is_err());
+	assert_eq!(table.iter().next().unwrap().dst.to_string(), "10.0.0.0/8");
+
+	table.add(Route::new("fd00::1/16".parse().unwrap(), link::Id(0))).unwrap();
+	assert_eq!(table.iter().nth(1).unwrap().dst.to_string(), "fd00::/16");
+
+	// Routes may be removed by any address within the prefix.
+	table.remove("10.1.2.3/8".parse().unwrap(), link::Id(0)).unwrap();
+	table.remove("fd00::/16".parse().unwrap(), link::Id(0)).unwrap();
+	assert_eq!(table.iter().count(), 0);
+}
+
+#[test]
+fn test_via() {
+	let mut table = Table::default();
+
+	let route = Route { via: Some("10.0.0.254".parse().unwrap()), ..Route::new("10.1.0.0/16".parse().unwrap(), link::Id(1)) };
+	table.add(route).unwrap();
+
+	let found = table.lookup("10.1.2.3".parse().unwrap()).unwrap();
+	assert_eq!(found.via, route.via);
+	assert_eq!(found.to_string(), "10.1.0.0/16 dev link 1 via 10.0.0.254 metric 0");
+
+	assert_eq!(Route::new(Cidr::ALL_V6, link::Id(0)).to_string(), "::/0 dev link 0 metric 0");
+
+	// The next hop must be of the family of the prefix.
+	assert!(table.add(Route { via: Some("fe80::1".parse().unwrap()), ..Route::new(Cidr::ALL_V4, link::Id(1)) }).is_err());
+}
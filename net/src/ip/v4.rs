@@ -1,16 +1,22 @@
 use core::mem::size_of;
 use core::net::{IpAddr, Ipv4Addr};
+use std::rc::Rc;
 
 use bilge::prelude::*;
 use collections::bytes::{Cursor, Slice};
-use log::warn;
+use log::{debug, warn};
+use stakker::CX;
 use utils::bytes::{self, Cast};
 use utils::endian::{u16be, BigEndian};
 use utils::error::*;
 
-use super::{fragment, Interface};
+use super::filter::{Action, Chain, Packet};
+use super::options::{self, Opt};
+use super::fragment;
+use crate::icmp;
+use crate::trace::{Event, Headers, Reason};
 use crate::ip::Version::V4;
-use crate::ip::{Checksum, Protocol, ToS};
+use crate::ip::{Checksum, Df, Info, Options, Params, Protocol, ToS};
 
 #[bitsize(8)]
 #[derive(FromBits, Cast)]
@@ -44,20 +50,29 @@ pub(super) struct Header {
 	dst: Ipv4Addr,
 }
 
-impl Interface {
-	pub fn recv_v4(self, interface: &mut crate::Interface, buf: Slice) -> Result {
-		let header: &Header = buf.split();
+impl crate::Interface {
+	pub(super) fn recv_v4(&mut self, cx: CX![], buf: Slice) -> Result {
+		let mut packet = buf.clone();
 
-		if header.dst != self.v4 {
-			warn!("Found IP packet with destination {}, expected {}", header.dst, self.v4);
+		let Some(header) = buf.try_split::<Header>() else {
+			warn!("IP packet is shorter than the minimum header length (got {} bytes)", buf.len());
+			self.drop_packet(Reason::Truncated, || None);
 			return Err(());
-		}
+		};
 
 		let header_len = 4 * header.ver.ihl().value() as usize;
 
-		let options: &[u8] = buf.split_n(header_len - size_of::<Header>());
+		if header_len < size_of::<Header>() {
+			warn!("IP header length ({header_len}) is smaller than the minimum header length");
+			self.drop_packet(Reason::HeaderLength, || Headers::parse(&packet));
+			return Err(());
+		}
 
-		// TODO: Process options
+		let Some(options) = buf.try_split_bytes(header_len - size_of::<Header>()) else {
+			warn!("IP packet is shorter than its header length ({header_len})");
+			self.drop_packet(Reason::Truncated, || Headers::parse(&packet));
+			return Err(());
+		};
 
 		if header.csm != [0, 0] {
 			let mut csum = Checksum::of(bytes::as_slice(header));
@@ -67,57 +82,342 @@ impl Interface {
 
 			if o != [0, 0] {
 				warn!("Packet has invalid checksum.");
+				self.drop_packet(Reason::Checksum, || Headers::parse(&packet));
 				return Err(());
 			}
 		}
 
-		let payload_len = header.len.get() as usize - header_len;
+		let Some(payload_len) = (header.len.get() as usize).checked_sub(header_len) else {
+			warn!("IP total length ({}) is smaller than the header length ({header_len})", header.len.get());
+			self.drop_packet(Reason::HeaderLength, || Headers::parse(&packet));
+			return Err(());
+		};
 
 		if buf.len() < payload_len {
 			log::warn!("IP packet smaller than specified length field.");
+			self.drop_packet(Reason::Truncated, || Headers::parse(&packet));
 			return Err(());
 		}
 
 		buf.truncate(payload_len);
+		packet.truncate(header_len + payload_len);
+
+		if !self.ip.addrs.is_local(IpAddr::V4(header.dst)) {
+			if self.ip.forwarding {
+				return self.forward_v4(cx, packet, header_len);
+			}
+
+			warn!("Found IP packet with destination {}, which is not a local address", header.dst);
+			self.drop_packet(Reason::NotLocal, || Headers::parse(&packet));
+			return Err(());
+		}
+
+		// Replies to masqueraded flows are forwarded to the host which sent the flow.
+		if self.unmasquerade(cx, &mut packet) {
+			return self.forward_v4(cx, packet, header_len);
+		}
+
+		options::visit(options, |opt| match opt {
+			// Source routes are not followed, so this host must be the final hop of the route.
+			Opt::SourceRoute { next, route, .. } if next < route.len() => {
+				warn!("Dropping source-routed packet with {} remaining hops", route.len() - next);
+				Err(())
+			}
+			Opt::RouterAlert(value) => Ok(debug!("Recieved packet with Router Alert option ({value})")),
+			_ => Ok(()),
+		})
+		.map_err(|()| self.drop_packet(Reason::Options, || Headers::parse(&packet)))?;
 
 		let frag = header.frg.get();
 
-		let start = frag.ofst().value();
+		let start = frag.ofst().value() * 8;
 		let more = frag.more();
 
 		let proto = header.proto.get();
 		let src = IpAddr::V4(header.src);
+		let info = Info { dst: IpAddr::V4(header.dst), tos: header.tos, ttl: header.ttl };
 
 		if start == 0 && !more {
 			// Process the packet regularly if it is not fragmented
-			interface.handle(proto, src, buf)
+			self.handle(cx, proto, src, info, buf, &packet)
 		} else {
 			// Construct a fragmentation key and fragment.
 			let key = fragment::Key { ident: frag.idnt() as u32, proto, addr: src };
 			let fragment = fragment::Fragment { start, more, buf };
 
 			// Process them with the fragmentation handler
-			interface.handle_fragment(key, fragment)
+			self.handle_fragment(cx, key, info, &packet[..header_len], fragment)
 		}
 	}
 
-	pub fn write_v4(&self, buf: Cursor, protocol: Protocol, addr: Ipv4Addr, tos: ToS, f: impl FnOnce(Cursor)) {
-		let (header, mut buf): (&mut Header, _) = buf.split();
+	/// Forwards an IPv4 packet which is not addressed to the interface to the link selected by the routing table, fragmenting it if it
+	/// exceeds the MTU of the link and the Don't Fragment flag is not set.
+	fn forward_v4(&mut self, cx: CX![], mut packet: Slice, header_len: usize) -> Result {
+		let header: &Header = bytes::cast(&*packet);
+		let (src, dst) = (header.src, header.dst);
+		let headers = Headers { proto: packet[9], src: IpAddr::V4(src), dst: IpAddr::V4(dst) };
+
+		if [src, dst].iter().any(|a| a.is_unspecified() || a.is_loopback() || a.is_link_local() || a.is_broadcast() || a.is_multicast()) {
+			warn!("Not forwarding packet from {src} to {dst}");
+			self.drop_packet(Reason::Martian, || Some(headers));
+			return Err(());
+		}
+
+		// Source-routed packets are not forwarded, as recommended by RFC 7126.
+		options::visit(&packet[size_of::<Header>()..header_len], |opt| match opt {
+			Opt::SourceRoute { .. } => Err(warn!("Not forwarding source-routed packet from {src} to {dst}")),
+			_ => Ok(()),
+		})
+		.map_err(|()| self.drop_packet(Reason::SourceRoute, || Some(headers)))?;
+
+		let frg = header.frg.get();
+
+		let filtered = match frg.ofst().value() {
+			0 => Packet::new(packet[9], IpAddr::V4(src), IpAddr::V4(dst), header.tos, &packet[header_len..]),
+			_ => Packet::fragment(packet[9], IpAddr::V4(src), IpAddr::V4(dst), header.tos, packet.len() - header_len),
+		};
+
+		match self.inspect(cx, Chain::Forward, filtered) {
+			Action::Accept => {}
+			Action::Drop => {
+				self.drop_packet(Reason::Filtered(Chain::Forward), || Some(headers));
+				return Err(());
+			}
+			Action::Reject => {
+				self.drop_packet(Reason::Filtered(Chain::Forward), || Some(headers));
+				self.send_icmp_error(cx, icmp::Error::Prohibited, &packet);
+				return Err(());
+			}
+		}
+
+		if header.ttl <= 1 {
+			self.drop_packet(Reason::TimeExceeded, || Some(headers));
+			self.send_icmp_error(cx, icmp::Error::TimeExceeded, &packet);
+			return Err(warn!("Time-to-live of packet from {src} to {dst} exceeded"));
+		}
+
+		let Some((link, mtu)) = self.ip.routes.lookup(IpAddr::V4(dst)).and_then(|r| Some((r.link, self.link(r.link)?.mtu))) else {
+			self.drop_packet(Reason::NoRoute, || Some(headers));
+			return Err(warn!("No route to {dst}"));
+		};
 
-		header.ver = Meta::new(u4::new(5), V4);
-		header.tos = tos;
+		if packet.len() > mtu && frg.dont() {
+			self.drop_packet(Reason::TooBig, || Some(headers));
+			self.send_icmp_error(cx, icmp::Error::TooBig(mtu), &packet);
+			return Err(warn!("Packet of length {} from {src} to {dst} exceeds the MTU ({mtu})", packet.len()));
+		}
+
+		if self.nat.masquerades(link) {
+			self.masquerade(cx, &mut packet).map_err(|()| self.drop_packet(Reason::Masquerade, || Some(headers)))?;
+		}
+
+		self.ip.stats.forw_datagrams += 1;
+		self.trace.emit(|| Event::Forward(headers));
+
+		let header: &mut Header = bytes::cast_mut(&mut *packet);
+		header.ttl -= 1;
+		header.csm = [0, 0];
+
+		let csum = Checksum::of(&packet[..header_len]).end();
+		bytes::cast_mut::<Header, _>(&mut *packet).csm = csum;
+
+		if packet.len() <= mtu {
+			self.emit(IpAddr::V4(dst), move |buf| {
+				buf.push(&*packet);
+			});
+
+			return Ok(());
+		}
 
-		header.ttl = 64;
-		header.proto = protocol.into();
+		let header: &Header = bytes::cast(&*packet);
+		let src = header.src;
 
-		header.src = self.v4;
-		header.dst = addr;
+		let options = Options::decode(&packet[size_of::<Header>()..header_len]).map_err(|()| self.drop_packet(Reason::TooBig, || Some(headers)))?;
+		let params = Params { tos: header.tos, ttl: header.ttl, options, ..Params::default() };
+		let protocol = header.proto.get();
 
-		f(buf.fork());
+		// Only the options with the copied flag are included in fragments after the first.
+		let copied = Params { options: params.options.copied(), ..params };
 
-		header.len = ((size_of::<Header>() + buf.pivot()) as u16).into();
-		header.frg = Fragment::new(u13::new(0), false, true, 0).into();
+		let (Some(first), Some(rest)) = (fragment::data_len(mtu, header_len), fragment::data_len(mtu, size_of::<Header>() + copied.options.len())) else {
+			self.drop_packet(Reason::TooBig, || Some(headers));
+			return Err(warn!("The MTU ({mtu}) leaves no room for the data of fragments of packet from {src} to {dst}"));
+		};
 
-		header.csm = Checksum::of(bytes::as_slice(header)).end();
+		let base = frg.ofst().value() as usize * 8;
+		let len = packet.len() - header_len;
+
+		self.ip.stats.frag_oks += 1;
+
+		let mut start = 0;
+
+		while start < len {
+			let (params, data_len) = if start == 0 { (params, first) } else { (copied, rest) };
+			let end = len.min(start + data_len);
+
+			// The fragments of a fragment are offset from its start, and the last only ends the packet if the original did.
+			let frg = Fragment::new(u13::new(((base + start) / 8) as u16), frg.more() || end < len, false, frg.idnt());
+			let packet = packet.clone();
+
+			self.emit(IpAddr::V4(dst), move |buf| write(buf, protocol, src, dst, &params, frg, &packet[header_len..][start..end]));
+			self.ip.stats.frag_creates += 1;
+
+			start = end;
+		}
+
+		Ok(())
 	}
+
+	/// Writes an IPv4 packet to the link, fragmenting it if it exceeds the path MTU and `df` allows it.
+	pub(super) fn send_v4(&mut self, protocol: Protocol, src: Ipv4Addr, dst: Ipv4Addr, params: Params, mtu: usize, payload: Vec<u8>) {
+		let header_len = size_of::<Header>() + params.options.len();
+
+		let headers = || Some(Headers { proto: protocol.into(), src: IpAddr::V4(src), dst: IpAddr::V4(dst) });
+
+		if header_len + payload.len() <= mtu {
+			let df = params.df != Df::Dont || params.probe;
+
+			// Packets without the Don't Fragment flag may be fragmented on the path, so their identification value must be unique, as
+			// specified by RFC 6864.
+			let ident = if df { 0 } else { self.ident.next(protocol, IpAddr::V4(src), IpAddr::V4(dst)) as u16 };
+
+			let frg = Fragment::new(u13::new(0), false, df, ident);
+			return self.emit(IpAddr::V4(dst), move |buf| write(buf, protocol, src, dst, &params, frg, &payload));
+		}
+
+		if params.df == Df::Do || params.probe {
+			warn!("Discarding packet of length {} which exceeds the MTU ({mtu})", header_len + payload.len());
+			self.drop_packet(Reason::TooBig, headers);
+			return;
+		}
+
+		// Only the options with the copied flag are included in fragments after the first.
+		let copied = Params { options: params.options.copied(), ..params };
+
+		let (Some(first), Some(rest)) = (fragment::data_len(mtu, header_len), fragment::data_len(mtu, size_of::<Header>() + copied.options.len())) else {
+			warn!("The MTU ({mtu}) leaves no room for the data of fragments with {} bytes of options", params.options.len());
+			self.drop_packet(Reason::TooBig, headers);
+			return;
+		};
+
+		self.ip.stats.frag_oks += 1;
+
+		let ident = self.ident.next(protocol, IpAddr::V4(src), IpAddr::V4(dst)) as u16;
+		let payload = Rc::new(payload);
+
+		let mut start = 0;
+
+		while start < payload.len() {
+			let (params, len) = if start == 0 { (params, first) } else { (copied, rest) };
+			let end = payload.len().min(start + len);
+
+			let frg = Fragment::new(u13::new((start / 8) as u16), end < payload.len(), false, ident);
+			let payload = payload.clone();
+
+			self.emit(IpAddr::V4(dst), move |buf| write(buf, protocol, src, dst, &params, frg, &payload[start..end]));
+			self.ip.stats.frag_creates += 1;
+
+			start = end;
+		}
+	}
+}
+
+/// Returns the maximum length of the payload of an IPv4 packet with the given header options.
+pub(super) fn max_payload(options: &Options) -> usize {
+	u16::MAX as usize - size_of::<Header>() - options.len()
+}
+
+/// Writes a single IPv4 packet containing `payload`.
+fn write(buf: Cursor, protocol: Protocol, src: Ipv4Addr, dst: Ipv4Addr, params: &Params, frg: Fragment, payload: &[u8]) {
+	let options = &params.options;
+
+	let (header, buf): (&mut Header, _) = buf.split();
+	buf.push(&options[..]).push(payload);
+
+	let header_len = size_of::<Header>() + options.len();
+
+	header.ver = Meta::new(u4::new(header_len as u8 / 4), V4);
+	header.tos = params.tos;
+
+	header.ttl = params.ttl;
+	header.proto = protocol.into();
+
+	header.src = src;
+	header.dst = dst;
+
+	header.len = ((header_len + payload.len()) as u16).into();
+	header.frg = frg.into();
+
+	let mut csum = Checksum::of(bytes::as_slice(header));
+	csum.push(options);
+
+	header.csm = csum.end();
+}
+
+#[test]
+fn test_ident() {
+	use stakker::Fwd;
+
+	use crate::{link, udp, SocketAddr};
+
+	let (mut s, a, sent) = link::capture();
+
+	let mut sock = a.query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+	let addr = SocketAddr { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), port: 7 };
+
+	for df in [Df::Dont, Df::Dont, Df::Want] {
+		sock.set_df(df);
+		sock.write(addr, |buf| {
+			buf.push(b"x");
+		});
+	}
+
+	link::run(&mut s);
+
+	let frg: Vec<Fragment> = sent.borrow().iter().map(|p| bytes::cast::<Header, _>(&p[..]).frg.get()).collect();
+
+	// Packets which may be fragmented on the path have distinct identification values, but atomic packets need not.
+	assert!(!frg[0].dont() && !frg[1].dont() && frg[0].idnt() != frg[1].idnt());
+	assert!(frg[2].dont() && frg[2].idnt() == 0);
+}
+
+#[test]
+fn test_fragment_min_mtu() {
+	use stakker::Fwd;
+
+	use super::addr::Cidr;
+	use super::route::Route;
+	use crate::link::{self, Pair};
+	use crate::{udp, SocketAddr};
+
+	let (mut s, a, _) = link::capture();
+
+	let sent = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+	let w = sent.clone();
+
+	let (small, _) = Pair::new(&mut s, Fwd::new(|_| ()), Fwd::new(move |buf: Slice| w.borrow_mut().push(buf.to_vec())));
+	let (tiny, _) = Pair::new(&mut s, Fwd::new(|_| ()), Fwd::new(|_| ()));
+
+	a.query(&mut s, |n, cx| {
+		// Links below the minimum MTU are rejected, and IPv6 is not routed over links below the minimum IPv6 MTU.
+		assert!(n.add_link(cx, tiny, crate::pmtu::MIN_V4 - 1).is_err());
+
+		let id = n.add_link(cx, small, crate::pmtu::MIN_V4).unwrap();
+		n.add_route(cx, Route::new(Cidr::new(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 0)), 16).unwrap(), id)).unwrap();
+		assert!(n.add_route(cx, Route::new(Cidr::new("fd01::".parse().unwrap(), 16).unwrap(), id)).is_err());
+	});
+
+	let mut sock = a.query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+
+	// The longest options leave room for 8 octets of data in the first fragment, and the later fragments do not copy them.
+	sock.set_options(Options::default().record_route(9).unwrap());
+	sock.set_df(Df::Dont);
+	sock.write(SocketAddr { addr: IpAddr::V4(Ipv4Addr::new(10, 1, 0, 1)), port: 7 }, |buf| {
+		buf.push(&[0u8; 100]);
+	});
+
+	link::run(&mut s);
+
+	let lens: Vec<usize> = sent.borrow().iter().map(|p| p.len()).collect();
+	assert_eq!(lens, [68, 68, 68, 24]);
 }
@@ -1,15 +1,20 @@
 use core::mem::size_of;
 use core::net::{IpAddr, Ipv6Addr};
+use std::rc::Rc;
 
 use bilge::prelude::*;
 use collections::bytes::{Cursor, Slice};
 use log::warn;
-use utils::bytes::Cast;
+use stakker::CX;
+use utils::bytes::{self, Cast};
 use utils::endian::{u16be, BigEndian};
 use utils::error::*;
 
-use super::{Interface, Protocol};
-use crate::ip::ToS;
+use super::filter::{Action, Chain, Packet};
+use super::{ext, fragment, Protocol};
+use crate::icmp;
+use crate::trace::{Event, Headers, Reason};
+use crate::ip::{Info, Params};
 use crate::ip::Version::V6;
 
 #[bitsize(32)]
@@ -31,43 +36,259 @@ struct Header {
 	dst: Ipv6Addr,
 }
 
-impl Interface {
-	pub fn recv_v6(self, interface: &mut crate::Interface, buf: Slice) -> Result {
-		let header: &Header = buf.split();
+impl crate::Interface {
+	pub(super) fn recv_v6(&mut self, cx: CX![], buf: Slice) -> Result {
+		let mut packet = buf.clone();
 
-		if header.dst != self.v6 {
-			warn!("Found IP packet with destination {}, expected {}", header.dst, self.v6);
+		let Some(header) = buf.try_split::<Header>() else {
+			warn!("IPv6 packet is shorter than the header length (got {} bytes)", buf.len());
+			self.drop_packet(Reason::Truncated, || None);
 			return Err(());
-		}
+		};
 
-		let payload_len = header.len.get() as usize - size_of::<Header>();
+		let payload_len = header.len.get() as usize;
 
 		if buf.len() < payload_len {
 			log::warn!("IP packet smaller than specified length field.");
+			self.drop_packet(Reason::Truncated, || Headers::parse(&packet));
 			return Err(());
 		}
 
 		buf.truncate(payload_len);
+		packet.truncate(size_of::<Header>() + payload_len);
+
+		if !self.ip.addrs.is_local(IpAddr::V6(header.dst)) {
+			if self.ip.forwarding {
+				return self.forward_v6(cx, packet);
+			}
+
+			warn!("Found IP packet with destination {}, which is not a local address", header.dst);
+			self.drop_packet(Reason::NotLocal, || Headers::parse(&packet));
+			return Err(());
+		}
+
+		// Replies to masqueraded flows are forwarded to the host which sent the flow.
+		if self.unmasquerade(cx, &mut packet) {
+			return self.forward_v6(cx, packet);
+		}
 
-		let proto = header.nxt.get();
+		let mut proto = header.nxt.get();
 		let src = IpAddr::V6(header.src);
+		let info = Info { dst: IpAddr::V6(header.dst), tos: header.ver.get().tos(), ttl: header.ttl };
 
-		interface.handle(proto, src, buf)
+		// The Hop-by-Hop Options header may only immediately follow the IPv6 header.
+		if proto == Protocol::HopByHop {
+			let (nxt, data) = ext::split(&buf).map_err(|()| self.drop_packet(Reason::Options, || Headers::parse(&packet)))?;
+			ext::options(data).map_err(|problem| self.report_problem(cx, &packet, data, problem))?;
+			proto = nxt;
+		}
+
+		self.handle_v6(cx, proto, src, info, buf, &packet)
 	}
 
-	pub fn write_v6(&self, buf: Cursor, protocol: Protocol, addr: Ipv6Addr, tos: ToS, f: impl FnOnce(Cursor)) {
-		let (header, mut buf): (&mut Header, _) = buf.split();
+	/// Walks the extension header chain of an IPv6 packet to find the upper-layer protocol, passing the payload to it. Problems with the
+	/// extension headers are reported to the source, quoting the packet, of which the payload is a part.
+	pub(super) fn handle_v6(&mut self, cx: CX![], mut proto: Protocol, src: IpAddr, info: Info, buf: Slice, packet: &[u8]) -> Result {
+		loop {
+			proto = match proto {
+				Protocol::HopByHop => {
+					warn!("Hop-by-Hop Options header does not immediately follow the IPv6 header");
+					self.drop_packet(Reason::Options, || Headers::parse(packet));
+					return Err(());
+				}
+				Protocol::Ipv6Opts => {
+					let (nxt, data) = ext::split(&buf).map_err(|()| self.drop_packet(Reason::Options, || Headers::parse(packet)))?;
+					ext::options(data).map_err(|problem| self.report_problem(cx, packet, data, problem))?;
+					nxt
+				}
+				Protocol::Ipv6Route => {
+					let (nxt, data) = ext::split(&buf).map_err(|()| self.drop_packet(Reason::Options, || Headers::parse(packet)))?;
+					ext::routing(data).map_err(|problem| self.report_problem(cx, packet, data, problem))?;
+					nxt
+				}
+				Protocol::Ipv6Frag => {
+					let header = ext::split_fragment(&buf).map_err(|()| self.drop_packet(Reason::Options, || Headers::parse(packet)))?;
+					let frag = header.frg.get();
+
+					let start = frag.ofst().value() * 8;
+					let more = frag.more();
 
-		header.ver = Meta::new(u20::MIN, tos, V6).into();
+					// Atomic fragments are processed as whole packets.
+					if start == 0 && !more {
+						header.nxt.get()
+					} else {
+						if more && !buf.len().is_multiple_of(8) {
+							warn!("IPv6 fragment length ({}) is not a multiple of 8 octets", buf.len());
+							self.drop_packet(Reason::Options, || Headers::parse(packet));
+							return Err(());
+						}
 
-		header.nxt = protocol.into();
-		header.ttl = 64;
+						// The first fragment must contain the entire header chain.
+						if start == 0 {
+							ext::check_chain(header.nxt.get(), &buf).map_err(|_| {
+								self.drop_packet(Reason::Options, || Headers::parse(packet));
 
-		header.src = self.v6;
-		header.dst = addr;
+								self.send_icmp_error(cx, icmp::Error::ParameterProblem { code: ext::INCOMPLETE_CHAIN, pointer: 0 }, packet);
+							})?;
+						}
 
-		f(buf.fork());
+						// Construct a fragmentation key and fragment.
+						let key = fragment::Key { ident: header.idnt.get(), proto: header.nxt.get(), addr: src };
+						let header = &packet[..packet.len() - buf.len()];
+						let fragment = fragment::Fragment { start, more, buf };
 
-		header.len = ((size_of::<Header>() + buf.pivot()) as u16).into();
+						// Process them with the fragmentation handler
+						return self.handle_fragment(cx, key, info, header, fragment);
+					}
+				}
+				// There is no upper-layer payload.
+				Protocol::Ipv6NoNxt => return Ok(()),
+				_ => return self.handle(cx, proto, src, info, buf, packet),
+			};
+		}
+	}
+
+	/// Counts, traces and reports a problem with an extension header of a received packet, given the header data in which the problem was found.
+	fn report_problem(&mut self, cx: CX![], packet: &[u8], data: &[u8], problem: Option<ext::Problem>) {
+		self.drop_packet(Reason::Options, || Headers::parse(packet));
+
+		if let Some(ext::Problem { code, at }) = problem {
+			// The data is a part of the packet, so its offset is the distance between their starts.
+			let pointer = (data.as_ptr() as usize - packet.as_ptr() as usize + at) as u32;
+			self.send_icmp_error(cx, icmp::Error::ParameterProblem { code, pointer }, packet);
+		}
 	}
+
+	/// Forwards an IPv6 packet which is not addressed to the interface to the link selected by the routing table. Packets which exceed the
+	/// MTU of the link are reported to their source, as IPv6 routers do not fragment packets.
+	fn forward_v6(&mut self, cx: CX![], mut packet: Slice) -> Result {
+		let header: &Header = bytes::cast(&*packet);
+		let (src, dst) = (header.src, header.dst);
+		let headers = Headers { proto: packet[6], src: IpAddr::V6(src), dst: IpAddr::V6(dst) };
+
+		if [src, dst].iter().any(|a| a.is_unspecified() || a.is_loopback() || a.is_unicast_link_local() || a.is_multicast()) {
+			warn!("Not forwarding packet from {src} to {dst}");
+			self.drop_packet(Reason::Martian, || Some(headers));
+			return Err(());
+		}
+
+		let Some(upper) = ext::upper(&packet) else {
+			warn!("Not forwarding packet from {src} to {dst} with truncated extension headers");
+			self.drop_packet(Reason::Options, || Some(headers));
+			return Err(());
+		};
+
+		let tos = header.ver.get().tos();
+
+		// The transport header follows the extension headers, and is only carried by the first fragment.
+		let filtered = match upper.at {
+			Some(at) => Packet::new(upper.proto.into(), IpAddr::V6(src), IpAddr::V6(dst), tos, &packet[at..]),
+			None => Packet::fragment(upper.proto.into(), IpAddr::V6(src), IpAddr::V6(dst), tos, packet.len() - upper.field - size_of::<ext::Fragment>()),
+		};
+
+		match self.inspect(cx, Chain::Forward, filtered) {
+			Action::Accept => {}
+			Action::Drop => {
+				self.drop_packet(Reason::Filtered(Chain::Forward), || Some(headers));
+				return Err(());
+			}
+			Action::Reject => {
+				self.drop_packet(Reason::Filtered(Chain::Forward), || Some(headers));
+				self.send_icmp_error(cx, icmp::Error::Prohibited, &packet);
+				return Err(());
+			}
+		}
+
+		if header.ttl <= 1 {
+			self.drop_packet(Reason::TimeExceeded, || Some(headers));
+			self.send_icmp_error(cx, icmp::Error::TimeExceeded, &packet);
+			return Err(warn!("Hop limit of packet from {src} to {dst} exceeded"));
+		}
+
+		let Some((link, mtu)) = self.ip.routes.lookup(IpAddr::V6(dst)).and_then(|r| Some((r.link, self.link(r.link)?.mtu))) else {
+			self.drop_packet(Reason::NoRoute, || Some(headers));
+			return Err(warn!("No route to {dst}"));
+		};
+
+		if packet.len() > mtu {
+			self.drop_packet(Reason::TooBig, || Some(headers));
+			self.send_icmp_error(cx, icmp::Error::TooBig(mtu), &packet);
+			return Err(warn!("Packet of length {} from {src} to {dst} exceeds the MTU ({mtu})", packet.len()));
+		}
+
+		if self.nat.masquerades(link) {
+			self.masquerade(cx, &mut packet).map_err(|()| self.drop_packet(Reason::Masquerade, || Some(headers)))?;
+		}
+
+		self.ip.stats.forw_datagrams += 1;
+		self.trace.emit(|| Event::Forward(headers));
+
+		bytes::cast_mut::<Header, _>(&mut *packet).ttl -= 1;
+
+		self.emit(IpAddr::V6(dst), move |buf| {
+			buf.push(&*packet);
+		});
+
+		Ok(())
+	}
+
+	/// Writes an IPv6 packet to the link, fragmenting it if it exceeds the path MTU.
+	pub(super) fn send_v6(&mut self, protocol: Protocol, src: Ipv6Addr, dst: Ipv6Addr, params: Params, mtu: usize, payload: Vec<u8>) {
+		if size_of::<Header>() + payload.len() <= mtu {
+			return self.emit(IpAddr::V6(dst), move |buf| write(buf, protocol, src, dst, &params, None, &payload));
+		}
+
+		if params.probe {
+			warn!("Discarding probe of length {} which exceeds the MTU ({mtu})", size_of::<Header>() + payload.len());
+			self.drop_packet(Reason::TooBig, || Some(Headers { proto: protocol.into(), src: IpAddr::V6(src), dst: IpAddr::V6(dst) }));
+			return;
+		}
+
+		let Some(len) = fragment::data_len(mtu, size_of::<Header>() + size_of::<ext::Fragment>()) else {
+			warn!("The MTU ({mtu}) leaves no room for the data of fragments");
+			self.drop_packet(Reason::TooBig, || Some(Headers { proto: protocol.into(), src: IpAddr::V6(src), dst: IpAddr::V6(dst) }));
+			return;
+		};
+
+		self.ip.stats.frag_oks += 1;
+
+		let ident = self.ident.next(protocol, IpAddr::V6(src), IpAddr::V6(dst));
+		let payload = Rc::new(payload);
+
+		for start in (0..payload.len()).step_by(len) {
+			let end = payload.len().min(start + len);
+
+			let meta = ext::FragmentMeta::new(end < payload.len(), u13::new((start / 8) as u16));
+			let frg = ext::Fragment::new(protocol, meta, ident);
+			let payload = payload.clone();
+
+			self.emit(IpAddr::V6(dst), move |buf| write(buf, protocol, src, dst, &params, Some(&frg), &payload[start..end]));
+			self.ip.stats.frag_creates += 1;
+		}
+	}
+}
+
+/// Writes a single IPv6 packet containing `payload`, preceded by a Fragment header if `frg` is present.
+fn write(buf: Cursor, protocol: Protocol, src: Ipv6Addr, dst: Ipv6Addr, params: &Params, frg: Option<&ext::Fragment>, payload: &[u8]) {
+	let (header, mut buf): (&mut Header, _) = buf.split();
+
+	header.ver = Meta::new(u20::MIN, params.tos, V6).into();
+
+	header.nxt = protocol.into();
+	header.ttl = params.ttl;
+
+	header.src = src;
+	header.dst = dst;
+
+	let mut len = payload.len();
+
+	if let Some(frg) = frg {
+		header.nxt = Protocol::Ipv6Frag.into();
+		buf = buf.push(frg);
+		len += size_of::<ext::Fragment>();
+	}
+
+	buf.push(payload);
+
+	header.len = (len as u16).into();
 }
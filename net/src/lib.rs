@@ -2,47 +2,81 @@
 
 use core::net::{Ipv4Addr, Ipv6Addr};
 
+use log::error;
 use stakker::{ActorOwn, CX};
-use wireguard::Wireguard;
 
 extern crate alloc;
 
 pub mod dns;
+pub mod icmp;
 mod ip;
+pub mod link;
 pub mod pcap;
+pub mod raw;
+pub mod stats;
 pub mod tcp;
+pub mod trace;
 pub mod udp;
 
-pub use ip::SocketAddr;
+pub use ip::{addr, conntrack, filter, fragment, options, pmtu, route, Df, DiffServ, Info, SocketAddr, ToS, ECN};
+pub use link::Link;
 
 pub struct Interface {
-	link: ActorOwn<Wireguard>,
+	/// The links owned by the interface, indexed by their identifiers. Removed links leave an empty slot.
+	links: Vec<Option<link::Handle>>,
+	/// The tunnels carried by the interface.
+	tunnels: link::tunnel::Table,
 
 	#[cfg(feature = "pcap")]
 	pcap: pcap::Writer,
+	trace: trace::Hook,
 
 	ip: ip::Interface,
 
 	fragment: ip::fragment::Store,
+	ident: ip::fragment::Ident,
+	pmtu: ip::pmtu::Cache,
+	nat: ip::nat::Table,
+	filter: ip::filter::Filter,
+	conntrack: ip::conntrack::Table,
 
+	icmp: icmp::Interface,
 	udp: udp::Interface,
 	tcp: tcp::Interface,
+	raw: raw::Interface,
 }
 
 impl Interface {
-	pub fn init(_: CX![], link: ActorOwn<Wireguard>, v4: Ipv4Addr, v6: Ipv6Addr) -> Option<Self> {
+	/// Creates an interface owning a single link with the given MTU, with default routes of both families over it. The link is the first
+	/// returned by [`Interface::links`]. The actor fails if the MTU is below the minimum MTU of IPv6.
+	pub fn init<L: Link>(cx: CX![], link: ActorOwn<L>, mtu: usize, v4: Ipv4Addr, v6: Ipv6Addr) -> Option<Self> {
+		if mtu < pmtu::MIN_V6 {
+			error!("Link MTU ({mtu}) is below the minimum IPv6 MTU ({})", pmtu::MIN_V6);
+			cx.fail_str("Link MTU is below the minimum IPv6 MTU");
+			return None;
+		}
+
 		Some(Self {
-			link,
+			links: vec![Some(link::Handle::new(link, mtu))],
+			tunnels: link::tunnel::Table::default(),
 
 			#[cfg(feature = "pcap")]
 			pcap: pcap::Writer::new("./log.pcap").unwrap(),
+			trace: trace::Hook::default(),
 
-			ip: ip::Interface::new(v4, v6),
+			ip: ip::Interface::new(v4, v6, link::Id(0)),
 
 			fragment: ip::fragment::Store::default(),
+			ident: ip::fragment::Ident::default(),
+			pmtu: ip::pmtu::Cache::default(),
+			nat: ip::nat::Table::default(),
+			filter: ip::filter::Filter::default(),
+			conntrack: ip::conntrack::Table::default(),
 
+			icmp: icmp::Interface::default(),
 			udp: udp::Interface::default(),
 			tcp: tcp::Interface::default(),
+			raw: raw::Interface::default(),
 		})
 	}
 }
@@ -0,0 +1,124 @@
+//! Link-layer transports for the IP stack.
+
+use core::fmt::Display;
+
+use collections::bytes::Cursor;
+use log::{error, warn};
+use stakker::{call, ActorOwn, CX};
+use utils::error::*;
+use wireguard::Wireguard;
+
+use crate::pmtu;
+
+pub mod netem;
+mod pair;
+pub mod tunnel;
+
+pub use netem::Netem;
+pub use pair::Pair;
+#[cfg(test)]
+pub(crate) use pair::{capture, connect, router, run};
+pub use tunnel::Tunnel;
+
+/// A link-layer transport which carries raw IP packets.
+///
+/// Links are actors. Packets received by a link are delivered to the stack through the `Fwd<Slice>` the link was constructed with, which
+/// should forward to [`crate::Interface::recv`]. The maximum transmission unit of a link, which is the largest IP packet that can be written
+/// to it, is given when the link is added to an interface.
+pub trait Link: Sized + 'static {
+	/// Writes a single packet to the link. `f` writes the packet into the cursor, advancing the pivot to the end of the packet.
+	fn write(&mut self, cx: CX![], f: impl FnOnce(Cursor) + 'static);
+}
+
+/// The standard WireGuard MTU, leaving room for the outer IP and UDP headers and the data message overhead.
+pub const WIREGUARD_MTU: usize = 1420;
+
+impl Link for Wireguard {
+	fn write(&mut self, cx: CX![], f: impl FnOnce(Cursor) + 'static) {
+		Wireguard::write(self, cx, f)
+	}
+}
+
+/// Identifies a link owned by an interface.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Id(pub(crate) usize);
+
+impl Display for Id {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_fmt(format_args!("link {}", self.0))
+	}
+}
+
+/// An object-safe view of an owned link actor.
+trait Erased {
+	/// Queues a packet write on the link actor.
+	fn write(&self, f: Box<dyn FnOnce(Cursor)>);
+}
+
+impl<L: Link> Erased for ActorOwn<L> {
+	fn write(&self, f: Box<dyn FnOnce(Cursor)>) {
+		call!([self], write(move |buf: Cursor<'_>| f(buf)))
+	}
+}
+
+/// A type-erased owning handle to a link actor.
+pub(crate) struct Handle {
+	/// The owning reference to the link actor.
+	link: Box<dyn Erased>,
+	/// The maximum transmission unit of the link.
+	pub mtu: usize,
+}
+
+impl Handle {
+	pub fn new<L: Link>(link: ActorOwn<L>, mtu: usize) -> Self {
+		Self { link: Box::new(link), mtu }
+	}
+
+	/// Queues a packet write on the link.
+	pub fn write(&self, f: impl FnOnce(Cursor) + 'static) {
+		self.link.write(Box::new(f))
+	}
+}
+
+impl crate::Interface {
+	/// Adds a link with the given MTU to the interface. Packets are only written to the link once a route over it is added. Fails if the MTU
+	/// is below the minimum MTU of IPv4, and routes to IPv6 prefixes may only be added over links with at least the minimum MTU of IPv6.
+	pub fn add_link<L: Link>(&mut self, _: CX![], link: ActorOwn<L>, mtu: usize) -> Result<Id> {
+		if mtu < pmtu::MIN_V4 {
+			error!("Link MTU ({mtu}) is below the minimum MTU ({})", pmtu::MIN_V4);
+			return Err(());
+		}
+
+		// Identifiers are not reused, so that a stale identifier cannot refer to a different link.
+		self.links.push(Some(Handle::new(link, mtu)));
+		Ok(Id(self.links.len() - 1))
+	}
+
+	/// Removes a link from the interface, along with every route over it, and stops masquerading packets forwarded to it. The link actor is
+	/// dropped.
+	pub fn remove_link(&mut self, _: CX![], id: Id) -> Result {
+		if self.links.get_mut(id.0).and_then(Option::take).is_none() {
+			warn!("Cannot remove unknown {id}");
+			return Err(());
+		}
+
+		self.ip.routes.remove_link(id);
+		self.nat.remove_link(id);
+
+		Ok(())
+	}
+
+	/// Returns the links owned by the interface.
+	pub fn links(&self) -> impl Iterator<Item = Id> + '_ {
+		self.links.iter().enumerate().filter(|(_, h)| h.is_some()).map(|(idx, _)| Id(idx))
+	}
+
+	/// Returns the maximum transmission unit of a link.
+	pub fn mtu(&self, id: Id) -> Option<usize> {
+		self.link(id).map(|h| h.mtu)
+	}
+
+	pub(crate) fn link(&self, id: Id) -> Option<&Handle> {
+		self.links.get(id.0)?.as_ref()
+	}
+}
@@ -0,0 +1,195 @@
+//! Network emulation for exercising the stack on unreliable links.
+
+use core::time::Duration;
+
+use collections::bytes::Cursor;
+use log::{error, trace};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use stakker::{actor, ret_nop, ActorOwn, Core, Stakker, CX};
+use utils::error::*;
+
+use super::Link;
+
+/// The impairments applied to packets written to a [`Netem`] link. Probabilities must be within `0.0..=1.0`.
+#[derive(Clone, Default)]
+pub struct Config {
+	/// The seed of the random number generator. Links with the same seed and configuration make the same decisions for the same packets.
+	pub seed: u64,
+	/// The probability that a packet is dropped.
+	pub loss: f64,
+	/// The fixed latency added to every packet.
+	pub delay: Duration,
+	/// The maximum random latency added on top of `delay`.
+	pub jitter: Duration,
+	/// The probability that a packet skips the latency, overtaking packets which are still delayed.
+	pub reorder: f64,
+	/// The probability that a packet is sent twice.
+	pub duplicate: f64,
+	/// The probability that a single random bit of a packet is flipped.
+	pub corrupt: f64,
+}
+
+/// A link which applies seeded random loss, latency, reordering, duplication, and corruption to packets before passing them to an inner link.
+///
+/// Only packets written to the link are impaired. To impair both directions of a link, wrap the transports on both ends.
+pub struct Netem<L: Link> {
+	/// The wrapped link.
+	inner: ActorOwn<L>,
+	/// The MTU of the wrapped link, which is also the MTU of this link.
+	mtu: usize,
+	/// The impairments to apply.
+	config: Config,
+	/// The seeded random number generator.
+	rng: StdRng,
+}
+
+impl<L: Link> Netem<L> {
+	/// Wraps a link with an MTU, which is also the MTU of the returned link. Fails if a probability of the configuration is not within
+	/// `0.0..=1.0`.
+	pub fn new(core: &mut Core, inner: ActorOwn<L>, mtu: usize, config: Config) -> Result<ActorOwn<Self>> {
+		let probabilities = [("loss", config.loss), ("reorder", config.reorder), ("duplicate", config.duplicate), ("corrupt", config.corrupt)];
+
+		if let Some((name, p)) = probabilities.into_iter().find(|(_, p)| !(0.0..=1.0).contains(p)) {
+			error!("The {name} probability of a network emulation link ({p}) is not within 0.0..=1.0");
+			return Err(());
+		}
+
+		Ok(actor!(core, <Netem<L>>::init(inner, mtu, config), ret_nop!()))
+	}
+
+	fn init(_: CX![], inner: ActorOwn<L>, mtu: usize, config: Config) -> Option<Self> {
+		let rng = StdRng::seed_from_u64(config.seed);
+		Some(Self { inner, mtu, config, rng })
+	}
+
+	/// Schedules a packet to be written to the inner link after a random delay.
+	fn schedule(&mut self, cx: CX![], packet: Vec<u8>) {
+		let delay = if self.rng.gen_bool(self.config.reorder) {
+			Duration::ZERO
+		} else {
+			self.config.delay + self.rng.gen_range(Duration::ZERO..=self.config.jitter)
+		};
+
+		let inner = self.inner.access_actor().clone();
+
+		let send = move |s: &mut Stakker| {
+			inner.apply(s, move |link, cx| {
+				link.write(cx, move |buf| {
+					buf.push(&packet[..]);
+				})
+			})
+		};
+
+		if delay.is_zero() {
+			cx.defer(send);
+		} else {
+			cx.after(delay, send);
+		}
+	}
+}
+
+impl<L: Link> Link for Netem<L> {
+	fn write(&mut self, cx: CX![], f: impl FnOnce(Cursor) + 'static) {
+		let mut packet = vec![0; self.mtu];
+		Cursor::vec(&mut packet, f);
+
+		if self.rng.gen_bool(self.config.loss) {
+			return trace!("Dropping packet");
+		}
+
+		if !packet.is_empty() && self.rng.gen_bool(self.config.corrupt) {
+			let bit = self.rng.gen_range(0..packet.len() * 8);
+			trace!("Corrupting bit {bit} of packet");
+			packet[bit / 8] ^= 1 << (bit % 8);
+		}
+
+		if self.rng.gen_bool(self.config.duplicate) {
+			trace!("Duplicating packet");
+			self.schedule(cx, packet.clone());
+		}
+
+		self.schedule(cx, packet);
+	}
+}
+
+#[test]
+fn test_reproducible() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use std::time::Instant;
+
+	use collections::bytes::Slice;
+	use stakker::Fwd;
+
+	use super::Pair;
+
+	// Writes numbered packets to a link with the impairments, returning the packets received on the other end in order.
+	let deliver = |config: Config| {
+		let mut now = Instant::now();
+		let mut s = Stakker::new(now);
+
+		let got = Rc::new(RefCell::new(Vec::new()));
+		let g = got.clone();
+
+		let (end, _peer) = Pair::new(&mut s, Fwd::new(|_| ()), Fwd::new(move |buf: Slice| g.borrow_mut().push(buf.to_vec())));
+		let link = Netem::new(&mut s, end, Pair::MTU, config).unwrap();
+
+		s.run(now, false);
+
+		for i in 0..100 {
+			link.query(&mut s, |link, cx| {
+				link.write(cx, move |buf| {
+					buf.push(&[i; 8]);
+				})
+			});
+		}
+
+		for _ in 0..50 {
+			now += Duration::from_millis(1);
+			s.run(now, false);
+		}
+
+		got.take()
+	};
+
+	let config = Config {
+		seed: 1,
+		loss: 0.2,
+		delay: Duration::from_millis(10),
+		jitter: Duration::from_millis(10),
+		reorder: 0.2,
+		duplicate: 0.2,
+		corrupt: 0.2,
+	};
+
+	let got = deliver(config.clone());
+	assert!(got == deliver(config.clone()));
+	assert!(got != deliver(Config { seed: 2, ..config.clone() }));
+
+	let sent = |i: u8| got.iter().filter(|p| p[0] == i).count();
+
+	// Some packets are lost, duplicated, reordered, and corrupted.
+	assert!((0..100).any(|i| sent(i) == 0));
+	assert!((0..100).any(|i| sent(i) == 2));
+	assert!(got.windows(2).any(|w| w[0][0] > w[1][0]));
+	assert!(got.iter().any(|p| p.iter().any(|&b| b != p[0])));
+
+	// Without impairments, every packet is delivered in order.
+	let got = deliver(Config { delay: Duration::from_millis(10), ..Config::default() });
+	assert!(got.iter().map(|p| p[0]).eq(0..100));
+}
+
+#[test]
+fn test_invalid_config() {
+	use stakker::Fwd;
+
+	use super::Pair;
+
+	let mut s = Stakker::new(std::time::Instant::now());
+
+	for config in [Config { loss: 1.5, ..Config::default() }, Config { corrupt: -0.1, ..Config::default() }, Config { duplicate: f64::NAN, ..Config::default() }] {
+		let (end, _peer) = Pair::new(&mut s, Fwd::new(|_| ()), Fwd::new(|_| ()));
+		assert!(Netem::new(&mut s, end, Pair::MTU, config).is_err());
+	}
+}
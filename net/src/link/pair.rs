@@ -0,0 +1,149 @@
+//! An in-memory link between two stacks in the same runtime.
+
+use collections::bytes::{Cursor, Slice};
+use stakker::{actor, ret_nop, ActorOwn, Core, Fwd, CX};
+
+use super::Link;
+
+/// One endpoint of an in-memory link pair. Packets written to an endpoint are delivered, in order, to the stack on the other end.
+pub struct Pair {
+	/// The receive handler of the stack on the other end of the link.
+	peer: Fwd<Slice>,
+}
+
+impl Pair {
+	/// The MTU of an endpoint.
+	pub const MTU: usize = 1500;
+
+	/// Creates a connected pair of endpoints, returning the endpoints for the stacks receiving through `a` and `b` respectively.
+	pub fn new(core: &mut Core, a: Fwd<Slice>, b: Fwd<Slice>) -> (ActorOwn<Self>, ActorOwn<Self>) {
+		let a_end = actor!(core, Pair::init(b), ret_nop!());
+		let b_end = actor!(core, Pair::init(a), ret_nop!());
+
+		(a_end, b_end)
+	}
+
+	fn init(_: CX![], peer: Fwd<Slice>) -> Option<Self> {
+		Some(Self { peer })
+	}
+}
+
+impl Link for Pair {
+	fn write(&mut self, _: CX![], f: impl FnOnce(Cursor) + 'static) {
+		let mut vec = vec![0; Self::MTU];
+		Cursor::vec(&mut vec, f);
+
+		let mut buf = Slice::new(vec.len());
+		buf.copy_from_slice(&vec);
+
+		self.peer.fwd(buf);
+	}
+}
+
+/// Creates two interfaces connected by a pair of endpoints, with the addresses 10.0.0.1 and fd00::1, and 10.0.0.2 and fd00::2 respectively.
+#[cfg(test)]
+pub(crate) fn connect() -> (stakker::Stakker, ActorOwn<crate::Interface>, ActorOwn<crate::Interface>) {
+	use core::net::Ipv4Addr;
+	use std::time::Instant;
+
+	use stakker::{actor_new, call, fwd_to, Stakker};
+
+	use crate::Interface;
+
+	let mut s = Stakker::new(Instant::now());
+
+	let a = actor_new!(s, Interface, ret_nop!());
+	let b = actor_new!(s, Interface, ret_nop!());
+
+	let (la, lb) = Pair::new(&mut s, fwd_to!([a], recv() as (Slice)), fwd_to!([b], recv() as (Slice)));
+
+	call!([a], Interface::init(la, Pair::MTU, Ipv4Addr::new(10, 0, 0, 1), "fd00::1".parse().unwrap()));
+	call!([b], Interface::init(lb, Pair::MTU, Ipv4Addr::new(10, 0, 0, 2), "fd00::2".parse().unwrap()));
+
+	run(&mut s);
+
+	(s, a, b)
+}
+
+/// Creates two interfaces connected through a router, which forwards between its links. The first has the addresses 10.0.0.1 and fd00::1,
+/// and the router is reached through it at 10.0.0.254 and fd00::fe. The second has the addresses 10.1.0.1 and fd01::1, and is reached through
+/// a link of the router with the given MTU, at 10.1.0.254 and fd01::fe. Returns the first interface, the router, and the second interface.
+#[cfg(test)]
+pub(crate) fn router(mtu: usize) -> (stakker::Stakker, ActorOwn<crate::Interface>, ActorOwn<crate::Interface>, ActorOwn<crate::Interface>) {
+	use core::net::Ipv4Addr;
+	use std::time::Instant;
+
+	use stakker::{actor_new, call, fwd_to, Stakker};
+
+	use crate::route::Route;
+	use crate::Interface;
+
+	let mut s = Stakker::new(Instant::now());
+
+	let a = actor_new!(s, Interface, ret_nop!());
+	let r = actor_new!(s, Interface, ret_nop!());
+	let c = actor_new!(s, Interface, ret_nop!());
+
+	let (la, ra) = Pair::new(&mut s, fwd_to!([a], recv() as (Slice)), fwd_to!([r], recv() as (Slice)));
+	let (rc, lc) = Pair::new(&mut s, fwd_to!([r], recv() as (Slice)), fwd_to!([c], recv() as (Slice)));
+
+	call!([a], Interface::init(la, Pair::MTU, Ipv4Addr::new(10, 0, 0, 1), "fd00::1".parse().unwrap()));
+	call!([r], Interface::init(ra, Pair::MTU, Ipv4Addr::new(10, 0, 0, 254), "fd00::fe".parse().unwrap()));
+	call!([c], Interface::init(lc, Pair::MTU, Ipv4Addr::new(10, 1, 0, 1), "fd01::1".parse().unwrap()));
+
+	run(&mut s);
+
+	r.query(&mut s, |n, cx| {
+		let id = n.add_link(cx, rc, mtu).unwrap();
+
+		for cidr in ["10.1.0.254/16", "fd01::fe/16"] {
+			let cidr = cidr.parse().unwrap();
+			n.add_address(cx, cidr).unwrap();
+			n.add_route(cx, Route::new(cidr, id)).unwrap();
+		}
+
+		n.set_forwarding(cx, true);
+	});
+
+	(s, a, r, c)
+}
+
+/// The packets written to a link.
+#[cfg(test)]
+pub(crate) type Sent = std::rc::Rc<std::cell::RefCell<Vec<Vec<u8>>>>;
+
+/// Creates an interface with the addresses 10.0.0.1 and fd00::1, returning it with the packets written to its link.
+#[cfg(test)]
+pub(crate) fn capture() -> (stakker::Stakker, ActorOwn<crate::Interface>, Sent) {
+	use core::net::Ipv4Addr;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+	use std::time::Instant;
+
+	use stakker::{actor_new, call, Stakker};
+
+	use crate::Interface;
+
+	let mut s = Stakker::new(Instant::now());
+
+	let a = actor_new!(s, Interface, ret_nop!());
+
+	let sent = Rc::new(RefCell::new(Vec::new()));
+	let w = sent.clone();
+
+	let (la, _) = Pair::new(&mut s, Fwd::new(|_| ()), Fwd::new(move |buf: Slice| w.borrow_mut().push(buf.to_vec())));
+
+	call!([a], Interface::init(la, Pair::MTU, Ipv4Addr::new(10, 0, 0, 1), "fd00::1".parse().unwrap()));
+
+	run(&mut s);
+
+	(s, a, sent)
+}
+
+/// Runs the queued operations of the runtime, and those they queue, without advancing time.
+#[cfg(test)]
+pub(crate) fn run(s: &mut stakker::Stakker) {
+	for _ in 0..16 {
+		s.run(std::time::Instant::now(), false);
+	}
+}
@@ -0,0 +1,356 @@
+//! IP-in-IP and GRE tunnels, which carry the packets of one interface as the payload of the packets of another.
+
+use core::net::IpAddr;
+use std::collections::{hash_map, HashMap};
+
+use collections::bytes::{Cursor, Slice};
+use log::{error, warn};
+use stakker::{actor, ret_nop, Actor, ActorOwn, Fwd, CX};
+use utils::error::*;
+
+use super::Link;
+use crate::ip::{Checksum, Params, Protocol};
+use crate::pmtu;
+
+/// The protocol number of IPv4 encapsulated in IP, as specified by RFC 2003.
+const IPV4_IN_IP: u8 = 4;
+/// The protocol number of IPv6 encapsulated in IP, as specified by RFC 2473.
+const IPV6_IN_IP: u8 = 41;
+/// The protocol number of Generic Routing Encapsulation, as specified by RFC 2784.
+const GRE: u8 = 47;
+
+/// The GRE flag indicating that the checksum and reserved fields are present.
+const GRE_CHECKSUM: u16 = 0x8000;
+/// The EtherTypes identifying the protocol of the payload of GRE packets.
+const ETHERTYPE_V4: u16 = 0x0800;
+const ETHERTYPE_V6: u16 = 0x86dd;
+
+/// The length of the GRE header, without the checksum.
+const GRE_HEADER_LEN: usize = 4;
+/// The lengths of the outer IPv4 and IPv6 headers, without options or extension headers.
+const V4_HEADER_LEN: usize = 20;
+const V6_HEADER_LEN: usize = 40;
+
+/// The encapsulation of the packets carried by a tunnel.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Encap {
+	/// IPv4 and IPv6 packets are the payload of the outer packets.
+	Ipip,
+	/// IPv4 and IPv6 packets follow a GRE header, without a key or sequence number.
+	Gre,
+}
+
+/// A link to the remote endpoint of a tunnel, over another interface.
+pub struct Tunnel {
+	/// The interface carrying the outer packets.
+	outer: Actor<crate::Interface>,
+	encap: Encap,
+	remote: IpAddr,
+	/// The MTU of the tunnel.
+	mtu: usize,
+}
+
+impl Tunnel {
+	/// Creates a tunnel over the interface to a remote endpoint, returning it with its MTU, which is that of the link routed to the endpoint
+	/// less the headers of the encapsulation. Packets received from the endpoint are decapsulated and passed to `recv`, which should forward
+	/// to [`crate::Interface::recv`] of the interface the tunnel is a link of. Fails if there is already a tunnel with the same encapsulation
+	/// to the endpoint, there is no route to the endpoint, or the MTU of the tunnel would be below the minimum MTU of IPv4.
+	///
+	/// The MTU is fixed when the tunnel is created, as it is registered with the interface the tunnel is a link of. It does not follow later
+	/// changes to the route to the endpoint or to the path MTU, so the tunnel should be recreated if the route changes. Outer packets which
+	/// exceed the MTU of a later route are fragmented like any other.
+	pub fn new(this: &mut crate::Interface, cx: CX![crate::Interface], encap: Encap, remote: IpAddr, recv: Fwd<Slice>) -> Result<(ActorOwn<Self>, usize)> {
+		let Some(link_mtu) = this.ip.routes.lookup(remote).and_then(|route| this.mtu(route.link)) else {
+			error!("No route to tunnel endpoint {remote}");
+			return Err(());
+		};
+
+		let header_len = match remote {
+			IpAddr::V4(_) => V4_HEADER_LEN,
+			IpAddr::V6(_) => V6_HEADER_LEN,
+		};
+
+		let encap_len = match encap {
+			Encap::Ipip => 0,
+			Encap::Gre => GRE_HEADER_LEN,
+		};
+
+		let Some(mtu) = link_mtu.checked_sub(header_len + encap_len).filter(|&mtu| mtu >= pmtu::MIN_V4) else {
+			error!("The MTU of the link to tunnel endpoint {remote} ({link_mtu}) is too small for the encapsulation");
+			return Err(());
+		};
+
+		match this.tunnels.map.entry((encap, remote)) {
+			hash_map::Entry::Vacant(entry) => entry.insert(recv),
+			hash_map::Entry::Occupied(_) => {
+				error!("A {encap:?} tunnel to {remote} already exists");
+				return Err(());
+			}
+		};
+
+		let outer = cx.access_actor().clone();
+
+		Ok((actor!(cx, Tunnel::init(outer, encap, remote, mtu), ret_nop!()), mtu))
+	}
+
+	fn init(_: CX![], outer: Actor<crate::Interface>, encap: Encap, remote: IpAddr, mtu: usize) -> Option<Self> {
+		Some(Self { outer, encap, remote, mtu })
+	}
+}
+
+impl Link for Tunnel {
+	fn write(&mut self, _: CX![], f: impl FnOnce(Cursor) + 'static) {
+		let mut packet = vec![0; self.mtu];
+		Cursor::vec(&mut packet, f);
+
+		let (encap, remote) = (self.encap, self.remote);
+		let outer = self.outer.clone();
+
+		self.outer.defer(move |s| outer.apply(s, move |this, cx| this.encapsulate(cx, encap, remote, packet)));
+	}
+}
+
+impl Drop for Tunnel {
+	fn drop(&mut self) {
+		let key = (self.encap, self.remote);
+		let outer = self.outer.clone();
+
+		self.outer.defer(move |s| outer.apply(s, move |this, _| assert!(this.tunnels.map.remove(&key).is_some())));
+	}
+}
+
+/// The tunnels over an interface.
+#[derive(Default)]
+pub(crate) struct Table {
+	/// The receive handlers of the tunnels, keyed by their encapsulation and remote endpoint.
+	map: HashMap<(Encap, IpAddr), Fwd<Slice>>,
+}
+
+/// Removes the GRE header of a packet, returning the IP version of its payload.
+fn strip_gre(src: IpAddr, buf: &Slice) -> Result<u8> {
+	if buf.len() < 4 {
+		warn!("GRE packet from {src} is too short (got {} bytes)", buf.len());
+		return Err(());
+	}
+
+	let flags = u16::from_be_bytes([buf[0], buf[1]]);
+
+	// Keys, sequence numbers and versions other than zero are not supported.
+	if flags & !GRE_CHECKSUM != 0 {
+		warn!("GRE packet from {src} has unsupported flags or version ({flags:#06x})");
+		return Err(());
+	}
+
+	if flags & GRE_CHECKSUM != 0 && (buf.len() < 8 || Checksum::of(buf).end() != [0, 0]) {
+		warn!("GRE packet from {src} has invalid checksum");
+		return Err(());
+	}
+
+	let header = buf.split_bytes(if flags & GRE_CHECKSUM != 0 { 8 } else { 4 });
+
+	match u16::from_be_bytes([header[2], header[3]]) {
+		ETHERTYPE_V4 => Ok(4),
+		ETHERTYPE_V6 => Ok(6),
+		ty => Err(warn!("GRE packet from {src} carries unsupported protocol {ty:#06x}")),
+	}
+}
+
+impl crate::Interface {
+	/// Writes a packet to the remote endpoint of a tunnel, behind the headers of its encapsulation.
+	fn encapsulate(&mut self, cx: CX![], encap: Encap, remote: IpAddr, packet: Vec<u8>) {
+		let (proto, ethertype) = match packet.first().map(|b| b >> 4) {
+			Some(4) => (IPV4_IN_IP, ETHERTYPE_V4),
+			Some(6) => (IPV6_IN_IP, ETHERTYPE_V6),
+			_ => return warn!("Cannot tunnel packet of unknown IP version"),
+		};
+
+		let Some(local) = self.ip.addrs.source(remote) else {
+			return warn!("No source address for tunnel endpoint {remote}");
+		};
+
+		let proto = match encap {
+			Encap::Ipip => proto,
+			Encap::Gre => GRE,
+		};
+
+		self.write(cx, Protocol::from(proto), local, remote, Params::default(), move |buf| {
+			let buf = match encap {
+				Encap::Ipip => buf,
+				Encap::Gre => buf.push(&[0u8, 0][..]).push(&ethertype.to_be_bytes()[..]),
+			};
+
+			buf.push(&packet[..]);
+		});
+	}
+
+	/// Passes a packet received from the remote endpoint of a tunnel to the tunnel, removing the headers of its encapsulation. Returns
+	/// `None` if the packet does not belong to a tunnel.
+	pub(crate) fn decapsulate(&self, proto: u8, src: IpAddr, buf: Slice) -> Option<Result> {
+		let encap = match proto {
+			IPV4_IN_IP | IPV6_IN_IP => Encap::Ipip,
+			GRE => Encap::Gre,
+			_ => return None,
+		};
+
+		let recv = self.tunnels.map.get(&(encap, src))?;
+
+		let version = match proto {
+			IPV4_IN_IP => 4,
+			IPV6_IN_IP => 6,
+			_ => match strip_gre(src, &buf) {
+				Ok(version) => version,
+				Err(()) => return Some(Err(())),
+			},
+		};
+
+		if buf.first().map(|b| b >> 4) != Some(version) {
+			return Some(Err(warn!("Tunnelled packet from {src} is not an IPv{version} packet")));
+		}
+
+		recv.fwd(buf);
+
+		Some(Ok(()))
+	}
+}
+
+#[test]
+fn test_tunnel() {
+	use core::net::Ipv4Addr;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use stakker::{actor_new, call, fwd_to};
+
+	use crate::ip::{Info, SocketAddr};
+	use crate::link::{self, Pair};
+	use crate::{udp, Interface};
+
+	let (mut s, a, b) = link::connect();
+
+	for encap in [Encap::Ipip, Encap::Gre] {
+		// Interfaces with the addresses 192.168.0.1 and fd10::1, and 192.168.0.2 and fd10::2, linked by tunnels between the outer interfaces.
+		let mut inner = Vec::new();
+
+		for (outer, remote, v4, v6) in [(&a, "10.0.0.2", [192, 168, 0, 1], "fd10::1"), (&b, "10.0.0.1", [192, 168, 0, 2], "fd10::2")] {
+			let n = actor_new!(s, Interface, ret_nop!());
+			let recv = fwd_to!([n], recv() as (Slice));
+
+			let (tunnel, mtu) = outer.query(&mut s, |o, cx| Tunnel::new(o, cx, encap, remote.parse().unwrap(), recv)).unwrap().unwrap();
+			assert_eq!(mtu, Pair::MTU - V4_HEADER_LEN - if encap == Encap::Gre { GRE_HEADER_LEN } else { 0 });
+
+			call!([n], Interface::init(tunnel, mtu, Ipv4Addr::from(v4), v6.parse().unwrap()));
+			inner.push(n);
+		}
+
+		link::run(&mut s);
+
+		let received = Rc::new(RefCell::new(Vec::new()));
+		let r = received.clone();
+
+		let sock = inner[0].query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+		let _peer = inner[1].query(&mut s, |n, cx| udp::Socket::bind(n, cx, 7, Fwd::new(move |(src, buf, _): (SocketAddr, Slice, Info)| r.borrow_mut().push((src.addr, buf.to_vec()))))).unwrap().unwrap();
+
+		for (src, dst) in [("192.168.0.1", "192.168.0.2"), ("fd10::1", "fd10::2")] {
+			let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+
+			sock.write(SocketAddr { addr: dst, port: 7 }, |buf| {
+				buf.push(b"tunnelled");
+			});
+
+			link::run(&mut s);
+
+			assert_eq!(received.borrow_mut().pop(), Some((src, b"tunnelled".to_vec())), "{encap:?}");
+		}
+
+		// A second tunnel with the same encapsulation to the same endpoint cannot be created.
+		assert!(a.query(&mut s, |o, cx| Tunnel::new(o, cx, encap, "10.0.0.2".parse().unwrap(), Fwd::new(|_| ()))).unwrap().is_err());
+	}
+
+	// The MTU of tunnels to IPv6 endpoints leaves room for the IPv6 header.
+	let (_tunnel, mtu) = a.query(&mut s, |o, cx| Tunnel::new(o, cx, Encap::Gre, "fd00::2".parse().unwrap(), Fwd::new(|_| ()))).unwrap().unwrap();
+	assert_eq!(mtu, Pair::MTU - V6_HEADER_LEN - GRE_HEADER_LEN);
+}
+
+#[test]
+fn test_decapsulate() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::ip::{self, SocketAddr};
+	use crate::link;
+
+	let (mut s, a, sent) = link::capture();
+
+	let received = Rc::new(RefCell::new(Vec::new()));
+
+	let mut tunnels = Vec::new();
+
+	for encap in [Encap::Ipip, Encap::Gre] {
+		let r = received.clone();
+		let recv = Fwd::new(move |buf: Slice| r.borrow_mut().push((encap, buf.to_vec())));
+
+		tunnels.push(a.query(&mut s, |n, cx| Tunnel::new(n, cx, encap, "10.0.0.2".parse().unwrap(), recv)).unwrap().unwrap());
+	}
+
+	link::run(&mut s);
+
+	let endpoint = |addr: &str| SocketAddr { addr: addr.parse().unwrap(), port: 0 };
+	let v4 = ip::packet(Protocol::Udp, endpoint("192.168.0.2"), endpoint("192.168.0.1"), vec![0; 12]);
+	let v6 = ip::packet(Protocol::Udp, endpoint("fd10::2"), endpoint("fd10::1"), vec![0; 12]);
+
+	let outer = |src: &str, proto: u8, payload: Vec<u8>| ip::packet(Protocol::from(proto), endpoint(src), endpoint("10.0.0.1"), payload);
+
+	// Builds a GRE packet, with a checksum if `csum` is set.
+	let gre = |flags: u16, ethertype: u16, inner: &[u8], csum: bool| {
+		let mut packet = [&flags.to_be_bytes()[..], &ethertype.to_be_bytes()].concat();
+
+		if csum {
+			packet.extend([0; 4]);
+		}
+
+		packet.extend(inner);
+
+		if csum {
+			let csum = Checksum::of(&packet).end();
+			packet[4..6].copy_from_slice(&csum);
+		}
+
+		packet
+	};
+
+	let mut corrupt = gre(GRE_CHECKSUM, ETHERTYPE_V4, &v4, true);
+	corrupt[4] ^= 1;
+
+	for (packet, expected) in [
+		(outer("10.0.0.2", IPV4_IN_IP, v4.clone()), Some((Encap::Ipip, v4.clone()))),
+		(outer("10.0.0.2", IPV6_IN_IP, v6.clone()), Some((Encap::Ipip, v6.clone()))),
+		(outer("10.0.0.2", GRE, gre(0, ETHERTYPE_V4, &v4, false)), Some((Encap::Gre, v4.clone()))),
+		(outer("10.0.0.2", GRE, gre(GRE_CHECKSUM, ETHERTYPE_V6, &v6, true)), Some((Encap::Gre, v6.clone()))),
+		// The checksum must be valid, and keys and sequence numbers are not supported.
+		(outer("10.0.0.2", GRE, corrupt), None),
+		(outer("10.0.0.2", GRE, gre(0x2000, ETHERTYPE_V4, &v4, false)), None),
+		// The version of the inner packet must be that of the encapsulation.
+		(outer("10.0.0.2", IPV4_IN_IP, v6.clone()), None),
+		(outer("10.0.0.2", GRE, gre(0, ETHERTYPE_V6, &v4, false)), None),
+		(outer("10.0.0.2", GRE, gre(0, 0x0806, &v4, false)), None),
+		// Packets from other endpoints are not decapsulated.
+		(outer("10.0.0.3", IPV4_IN_IP, v4.clone()), None),
+	] {
+		ip::deliver(&mut s, &a, &packet);
+		assert_eq!(received.borrow_mut().pop(), expected);
+	}
+
+	sent.borrow_mut().clear();
+
+	// Packets written to a tunnel are encapsulated towards its endpoint.
+	for ((tunnel, _), (proto, header)) in tunnels.iter().zip([(IPV4_IN_IP, vec![]), (GRE, vec![0, 0, 0x08, 0x00])]) {
+		let inner = v4.clone();
+		tunnel.query(&mut s, |t, cx| t.write(cx, move |buf| {
+			buf.push(&inner[..]);
+		}));
+		link::run(&mut s);
+
+		let packet = sent.borrow_mut().pop().unwrap();
+		assert_eq!((packet[9], &packet[16..20], &packet[20..]), (proto, &[10, 0, 0, 2][..], &[&header[..], &v4].concat()[..]));
+	}
+}
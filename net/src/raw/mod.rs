@@ -0,0 +1,174 @@
+//! Raw IP sockets, which send and receive the payloads of a protocol which is not implemented by the stack.
+
+use core::net::IpAddr;
+use std::collections::{hash_map, HashMap};
+
+use collections::bytes::{Cursor, Slice};
+use log::{debug, error, warn};
+use stakker::{Actor, Fwd, CX};
+use utils::error::*;
+
+use crate::ip::{Df, DiffServ, Info, Options, Params, Protocol, ECN};
+
+pub struct Socket {
+	proto: u8,
+	interface: Actor<super::Interface>,
+	/// The IP header parameters of outgoing packets.
+	params: Params,
+}
+
+impl Socket {
+	/// Binds a callback to an IP protocol number, which receives the source address and payload of every packet of the protocol sent to
+	/// the interface. Fails if the protocol is implemented by the stack, or is already bound.
+	pub fn bind(this: &mut super::Interface, cx: CX![super::Interface], proto: u8, callback: Fwd<(IpAddr, Slice, Info)>) -> Result<Self> {
+		if !matches!(Protocol::from(proto), Protocol::Unknown(_)) {
+			error!("Protocol {proto} is implemented by the stack");
+			return Err(());
+		}
+
+		match this.raw.map.entry(proto) {
+			hash_map::Entry::Vacant(entry) => entry.insert(callback),
+			hash_map::Entry::Occupied(_) => {
+				error!("Protocol {proto} is already bound");
+				return Err(());
+			}
+		};
+
+		Ok(Socket {
+			proto,
+			interface: cx.access_actor().clone(),
+			params: Params::default(),
+		})
+	}
+
+	pub fn proto(&self) -> u8 {
+		self.proto
+	}
+
+	/// Sets the IPv4 header options written to outgoing packets.
+	pub fn set_options(&mut self, options: Options) {
+		self.params.options = options;
+	}
+
+	/// Sets whether the Don't Fragment flag is set on outgoing IPv4 packets.
+	pub fn set_df(&mut self, df: Df) {
+		self.params.df = df;
+	}
+
+	/// Sets the time-to-live, or hop limit for IPv6, of outgoing packets.
+	pub fn set_ttl(&mut self, ttl: u8) {
+		self.params.ttl = ttl;
+	}
+
+	/// Sets the Differentiated Services code point of outgoing packets.
+	pub fn set_dscp(&mut self, ds: DiffServ) {
+		self.params.tos.set_ds(ds);
+	}
+
+	/// Sets the Explicit Congestion Notification codepoint of outgoing packets.
+	pub fn set_ecn(&mut self, ecn: ECN) {
+		self.params.tos.set_ecn(ecn);
+	}
+
+	/// Writes a packet of the bound protocol to an address, with the payload written by `f`. The IP header is written by the stack.
+	pub fn write(&self, addr: IpAddr, f: impl FnOnce(Cursor) + 'static) {
+		let proto = Protocol::from(self.proto);
+		let params = self.params;
+
+		let actor = self.interface.access_actor().clone();
+
+		self.interface.defer(move |s| {
+			actor.apply(s, move |this, cx| {
+				let Some(local) = this.ip.addrs.source(addr) else {
+					return warn!("No source address for destination {addr}");
+				};
+
+				this.write(cx, proto, local, addr, params, f);
+			})
+		});
+	}
+}
+
+impl Drop for Socket {
+	fn drop(&mut self) {
+		let proto = self.proto;
+		let i = self.interface.clone();
+
+		self.interface.defer(move |s| i.apply(s, move |this, _| assert!(this.raw.map.remove(&proto).is_some())));
+	}
+}
+
+#[derive(Default)]
+pub(crate) struct Interface {
+	/// The callbacks of the bound protocols, keyed by their protocol numbers.
+	map: HashMap<u8, Fwd<(IpAddr, Slice, Info)>>,
+}
+
+impl Interface {
+	/// Passes a packet to the socket bound to its protocol. Returns false if no socket is bound to the protocol.
+	pub fn recv(&self, proto: u8, addr: IpAddr, info: Info, buf: Slice) -> bool {
+		let Some(callback) = self.map.get(&proto) else {
+			debug!("Protocol {proto} is not bound");
+			return false;
+		};
+
+		callback.fwd((addr, buf, info));
+
+		true
+	}
+}
+
+#[test]
+fn test_raw() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::link;
+
+	let (mut s, a, b) = link::connect();
+
+	// Protocols implemented by the stack, including the IPv6 extension headers, cannot be bound.
+	for proto in [0, 1, 6, 17, 44, 58, 60] {
+		assert!(a.query(&mut s, |n, cx| Socket::bind(n, cx, proto, Fwd::new(|_| ()))).unwrap().is_err(), "{proto}");
+	}
+
+	let received = Rc::new(RefCell::new(Vec::new()));
+	let r = received.clone();
+
+	let mut sock = a.query(&mut s, |n, cx| Socket::bind(n, cx, 253, Fwd::new(|_| ()))).unwrap().unwrap();
+	let peer = b.query(&mut s, |n, cx| Socket::bind(n, cx, 253, Fwd::new(move |(src, buf, info): (IpAddr, Slice, Info)| r.borrow_mut().push((src, buf.to_vec(), info))))).unwrap().unwrap();
+
+	// A protocol can only be bound once.
+	assert!(b.query(&mut s, |n, cx| Socket::bind(n, cx, 253, Fwd::new(|_| ()))).unwrap().is_err());
+
+	sock.set_ttl(5);
+	sock.set_dscp(DiffServ::Ef);
+
+	for (src, dst) in [("10.0.0.1", "10.0.0.2"), ("fd00::1", "fd00::2")] {
+		let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+
+		sock.write(dst, |buf| {
+			buf.push(b"payload");
+		});
+
+		link::run(&mut s);
+
+		// The payload is received with the header fields set by the socket which wrote it.
+		let (from, data, info) = received.borrow_mut().pop().unwrap();
+		assert_eq!((from, &data[..], info.dst, info.ttl, info.tos.ds()), (src, &b"payload"[..], dst, 5, DiffServ::Ef));
+	}
+
+	// Dropping a socket unbinds its protocol, so packets of it are no longer received, and it can be bound again.
+	drop(peer);
+	link::run(&mut s);
+
+	sock.write("10.0.0.2".parse().unwrap(), |buf| {
+		buf.push(b"payload");
+	});
+
+	link::run(&mut s);
+
+	assert!(received.borrow().is_empty());
+	assert_eq!(b.query(&mut s, |n, _| n.stats().ip.in_unknown_protos), Some(1));
+	assert!(b.query(&mut s, |n, cx| Socket::bind(n, cx, 253, Fwd::new(|_| ()))).unwrap().is_ok());
+}
@@ -0,0 +1,129 @@
+//! Counters of the packets processed by each layer of the stack, modelled on the IP and UDP MIBs of RFC 4293 and RFC 4113.
+
+use crate::fragment;
+
+/// IP counters, of both families.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Ip {
+	/// The number of packets received from links, including those with errors.
+	pub in_receives: u64,
+	/// The number of received packets discarded due to errors in their headers, including invalid checksums, options and extension
+	/// headers, and exceeded time-to-live values.
+	pub in_hdr_errors: u64,
+	/// The number of received packets discarded because they were shorter than their headers or their length field.
+	pub in_truncated: u64,
+	/// The number of received packets discarded because their addresses were not local and they could not be forwarded.
+	pub in_addr_errors: u64,
+	/// The number of received packets of a protocol which is not implemented, and was not bound by a raw socket or tunnel.
+	pub in_unknown_protos: u64,
+	/// The number of received packets discarded by the packet filter, or because they could not be translated.
+	pub in_discards: u64,
+	/// The number of received packets passed to upper-layer protocols, after reassembly.
+	pub in_delivers: u64,
+	/// The number of packets forwarded.
+	pub forw_datagrams: u64,
+	/// The number of packets written by upper-layer protocols.
+	pub out_requests: u64,
+	/// The number of written and forwarded packets discarded because there was no route to their destination.
+	pub out_no_routes: u64,
+	/// The number of written packets discarded by the packet filter.
+	pub out_discards: u64,
+	/// The number of packets which were fragmented.
+	pub frag_oks: u64,
+	/// The number of packets discarded because they exceeded the MTU and could not be fragmented.
+	pub frag_fails: u64,
+	/// The number of fragments created.
+	pub frag_creates: u64,
+}
+
+/// UDP counters.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Udp {
+	/// The number of datagrams passed to sockets.
+	pub in_datagrams: u64,
+	/// The number of datagrams received for a port without a socket.
+	pub no_ports: u64,
+	/// The number of datagrams discarded due to errors, including invalid checksums.
+	pub in_errors: u64,
+	/// The number of datagrams discarded due to invalid checksums.
+	pub in_csum_errors: u64,
+	/// The number of datagrams written by sockets.
+	pub out_datagrams: u64,
+}
+
+/// TCP counters.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Tcp {
+	/// The number of segments received.
+	pub in_segs: u64,
+}
+
+/// A snapshot of the counters of an interface.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+	pub ip: Ip,
+	pub fragment: fragment::Stats,
+	pub udp: Udp,
+	pub tcp: Tcp,
+}
+
+impl crate::Interface {
+	/// Returns a snapshot of the counters of every layer.
+	pub fn stats(&self) -> Stats {
+		Stats { ip: self.ip.stats, fragment: self.fragment.stats(), udp: self.udp.stats, tcp: self.tcp.stats }
+	}
+}
+
+#[test]
+fn test_stats() {
+	use stakker::Fwd;
+
+	use crate::ip::{self, Protocol, SocketAddr};
+	use crate::{link, udp};
+
+	let (mut s, a, _sent) = link::capture();
+
+	let sock = a.query(&mut s, |n, cx| udp::Socket::bind(n, cx, 7, Fwd::new(|_| ()))).unwrap().unwrap();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+	let packet = |proto, port| ip::packet(proto, endpoint("10.0.0.2", 1000), endpoint("10.0.0.1", port), [&[0; 6][..], &[0xff, 0xff], &[0; 12]].concat());
+
+	let mut corrupt = packet(Protocol::Udp, 7);
+	*corrupt.last_mut().unwrap() ^= 1;
+
+	let mut header = packet(Protocol::Udp, 7);
+	header[10] ^= 1;
+
+	let truncated = &packet(Protocol::Udp, 7)[..30];
+
+	for packet in [&packet(Protocol::Udp, 7), &corrupt, &packet(Protocol::Udp, 9), &header, truncated, &packet(Protocol::Tcp, 80), &packet(Protocol::from(200), 0)] {
+		ip::deliver(&mut s, &a, packet);
+	}
+
+	let stats = a.query(&mut s, |n, _| n.stats()).unwrap();
+
+	// Datagrams are delivered to UDP before their checksum and port are checked, and errors are written for the missing port and protocol.
+	assert_eq!((stats.ip.in_receives, stats.ip.in_hdr_errors, stats.ip.in_truncated, stats.ip.in_unknown_protos), (7, 1, 1, 1));
+	assert_eq!((stats.ip.in_delivers, stats.ip.out_requests), (4, 2));
+	assert_eq!((stats.udp.in_datagrams, stats.udp.no_ports, stats.udp.in_errors, stats.udp.in_csum_errors), (1, 1, 1, 1));
+	assert_eq!(stats.tcp.in_segs, 1);
+
+	// Packets dropped by the filter are counted as discards of the direction they were filtered in.
+	a.query(&mut s, |n, cx| n.load_filter(cx, "input drop dport 7\noutput drop dport 9")).unwrap().unwrap();
+
+	ip::deliver(&mut s, &a, &packet(Protocol::Udp, 7));
+
+	for port in [8, 9] {
+		sock.write(endpoint("10.0.0.2", port), |buf| {
+			buf.push(b"counted");
+		});
+	}
+
+	link::run(&mut s);
+
+	let stats = a.query(&mut s, |n, _| n.stats()).unwrap();
+
+	assert_eq!((stats.ip.in_receives, stats.ip.in_delivers, stats.ip.in_discards), (8, 4, 1));
+	assert_eq!((stats.ip.out_requests, stats.ip.out_discards, stats.udp.out_datagrams), (4, 1, 2));
+	assert_eq!(stats.udp.in_datagrams, 1);
+}
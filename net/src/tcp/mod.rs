@@ -9,6 +9,7 @@ use utils::endian::{u16be, u32be, u64be, BigEndian};
 use utils::error::*;
 
 use crate::ip::{self, SocketAddr};
+use crate::stats;
 
 #[bitsize(16)]
 struct Control {
@@ -166,10 +167,13 @@ struct TCB {
 #[derive(Default)]
 pub(crate) struct Interface {
 	map: HashMap<Key, TCB>,
+	pub stats: stats::Tcp,
 }
 
 impl Interface {
 	pub fn recv<'a>(&'a mut self, interface: &ip::Interface, addr: IpAddr, buf: Slice) -> Result {
+		self.stats.in_segs += 1;
+
 		Err(())
 	}
 }
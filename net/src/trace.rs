@@ -0,0 +1,251 @@
+//! A hook for tracing the packets processed by the stack.
+
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use stakker::{Fwd, CX};
+
+use crate::filter::Chain;
+use crate::stats;
+
+/// The IP headers of a traced packet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Headers {
+	/// The protocol number of the payload. For IPv6 packets parsed from a link, this is the first Next Header field, which may be an
+	/// extension header.
+	pub proto: u8,
+	pub src: IpAddr,
+	pub dst: IpAddr,
+}
+
+impl Headers {
+	/// Parses the headers of an IP packet, returning `None` if it is too short or of an unknown version. Nothing else is validated.
+	pub fn parse(packet: &[u8]) -> Option<Self> {
+		match packet.first()? >> 4 {
+			4 if packet.len() >= 20 => Some(Self {
+				proto: packet[9],
+				src: IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?)),
+				dst: IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?)),
+			}),
+			6 if packet.len() >= 40 => Some(Self {
+				proto: packet[6],
+				src: IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?)),
+				dst: IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?)),
+			}),
+			_ => None,
+		}
+	}
+}
+
+/// The reason a packet was dropped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reason {
+	/// The IP version was neither 4 nor 6.
+	Version,
+	/// The IPv4 header length was smaller than the minimum header length, or larger than the total length.
+	HeaderLength,
+	/// The IPv4 header checksum was invalid.
+	Checksum,
+	/// The packet was shorter than its headers or its length field.
+	Truncated,
+	/// The IPv4 options or IPv6 extension headers were invalid, or required the packet to be discarded.
+	Options,
+	/// The destination was not a local address, and forwarding is disabled.
+	NotLocal,
+	/// The source or destination address may not be forwarded, such as a loopback or link-local address.
+	Martian,
+	/// The packet was source-routed, and so was not forwarded.
+	SourceRoute,
+	/// The packet was dropped or rejected by a chain of the packet filter.
+	Filtered(Chain),
+	/// The time-to-live or hop limit was exceeded while forwarding the packet.
+	TimeExceeded,
+	/// There was no route to the destination.
+	NoRoute,
+	/// The packet exceeded the MTU, and could not be fragmented.
+	TooBig,
+	/// The source of the packet could not be translated.
+	Masquerade,
+	/// The protocol is not implemented, and is not bound by a raw socket or tunnel.
+	UnknownProtocol,
+	/// A fragment overlapped another fragment of the same packet.
+	FragmentOverlap,
+	/// A fragment exceeded the maximum packet length, or was empty but not the last.
+	FragmentLength,
+	/// The reassembly of the packet was not completed in time.
+	FragmentExpired,
+	/// The reassembly of the packet was discarded to stay within the memory limits.
+	FragmentEvicted,
+	/// The UDP header or length was invalid.
+	UdpHeader,
+	/// The UDP checksum was invalid.
+	UdpChecksum,
+	/// No UDP socket was bound to the destination port.
+	NoPort,
+}
+
+impl Reason {
+	/// Returns the IP counter of packets dropped for the reason. Drops by upper-layer protocols and reassembly are counted by their own
+	/// counters.
+	fn counter(self, stats: &mut stats::Ip) -> Option<&mut u64> {
+		Some(match self {
+			Self::Version | Self::HeaderLength | Self::Checksum | Self::Options | Self::TimeExceeded => &mut stats.in_hdr_errors,
+			Self::Truncated => &mut stats.in_truncated,
+			Self::NotLocal | Self::Martian => &mut stats.in_addr_errors,
+			Self::Filtered(Chain::Output) => &mut stats.out_discards,
+			Self::Filtered(_) | Self::SourceRoute | Self::Masquerade => &mut stats.in_discards,
+			Self::NoRoute => &mut stats.out_no_routes,
+			Self::TooBig => &mut stats.frag_fails,
+			Self::UnknownProtocol => &mut stats.in_unknown_protos,
+			_ => return None,
+		})
+	}
+}
+
+/// An event in the processing of a packet.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+	/// A packet was received from a link.
+	Recv(Headers),
+	/// A received packet was passed to an upper-layer protocol, after reassembly.
+	Deliver(Headers),
+	/// A UDP datagram was passed to the socket bound to its destination port.
+	DeliverUdp { headers: Headers, port: u16 },
+	/// A packet was forwarded.
+	Forward(Headers),
+	/// A packet was written to a link.
+	Send(Headers),
+	/// A packet was dropped. The headers are absent if the packet was dropped before they could be parsed, or was reassembled.
+	Drop { headers: Option<Headers>, reason: Reason },
+}
+
+/// The tracing hook of an interface.
+#[derive(Clone, Default)]
+pub(crate) struct Hook(Option<Fwd<Event>>);
+
+impl Hook {
+	/// Passes an event to the hook, if it is set. The event is only constructed if it is passed.
+	#[inline]
+	pub fn emit(&self, f: impl FnOnce() -> Event) {
+		if let Some(hook) = &self.0 {
+			hook.fwd(f());
+		}
+	}
+
+	/// Passes an event about a packet to the hook, if it is set and the headers of the packet can be parsed.
+	#[inline]
+	pub fn emit_packet(&self, packet: &[u8], f: impl FnOnce(Headers) -> Event) {
+		if let Some(hook) = &self.0 {
+			if let Some(headers) = Headers::parse(packet) {
+				hook.fwd(f(headers));
+			}
+		}
+	}
+}
+
+impl crate::Interface {
+	/// Sets the hook which is passed an event for every packet received, delivered, forwarded, written and dropped by the interface, or
+	/// removes it.
+	pub fn set_trace(&mut self, _: CX![], hook: Option<Fwd<Event>>) {
+		self.trace = Hook(hook);
+	}
+
+	/// Counts and traces a received packet which was passed to an upper-layer protocol.
+	pub(crate) fn deliver(&mut self, headers: Headers) {
+		self.ip.stats.in_delivers += 1;
+		self.trace.emit(|| Event::Deliver(headers));
+	}
+
+	/// Counts and traces a dropped packet, whose headers are only parsed if there is a hook.
+	pub(crate) fn drop_packet(&mut self, reason: Reason, headers: impl FnOnce() -> Option<Headers>) {
+		if let Some(counter) = reason.counter(&mut self.ip.stats) {
+			*counter += 1;
+		}
+
+		self.trace.emit(|| Event::Drop { headers: headers(), reason });
+	}
+}
+
+#[test]
+fn test_trace() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::ip::{self, Protocol, SocketAddr};
+	use crate::{link, udp};
+
+	let (mut s, a, _sent) = link::capture();
+
+	let events = Rc::new(RefCell::new(Vec::new()));
+	let e = events.clone();
+
+	a.query(&mut s, |n, cx| n.set_trace(cx, Some(Fwd::new(move |event| e.borrow_mut().push(event)))));
+
+	let _sock = a.query(&mut s, |n, cx| udp::Socket::bind(n, cx, 7, Fwd::new(|_| ()))).unwrap().unwrap();
+
+	let endpoint = |addr: &str, port| SocketAddr { addr: addr.parse().unwrap(), port };
+
+	// A UDP header with a non-zero checksum, so that it is filled in for IPv4 as well.
+	let transport = || [&[0; 6][..], &[0xff, 0xff], b"ping"].concat();
+
+	for (src, dst) in [("10.0.0.2", "10.0.0.1"), ("fd00::2", "fd00::1")] {
+		let headers = Headers { proto: Protocol::Udp.into(), src: src.parse().unwrap(), dst: dst.parse().unwrap() };
+		let packet = |port| ip::packet(Protocol::Udp, endpoint(src, 1000), endpoint(dst, port), transport());
+
+		// A datagram for a bound port is received, delivered to UDP, and then to the socket.
+		ip::deliver(&mut s, &a, &packet(7));
+		assert_eq!(events.take(), [Event::Recv(headers), Event::Deliver(headers), Event::DeliverUdp { headers, port: 7 }]);
+
+		// A datagram with an invalid checksum is dropped by UDP.
+		let mut corrupt = packet(7);
+		*corrupt.last_mut().unwrap() ^= 1;
+
+		ip::deliver(&mut s, &a, &corrupt);
+		assert_eq!(events.take(), [Event::Recv(headers), Event::Deliver(headers), Event::Drop { headers: Some(headers), reason: Reason::UdpChecksum }]);
+
+		// A datagram for a port without a socket is dropped, and a Port Unreachable error is written to its source.
+		ip::deliver(&mut s, &a, &packet(9));
+
+		let events = events.take();
+		assert_eq!(events[..3], [Event::Recv(headers), Event::Deliver(headers), Event::Drop { headers: Some(headers), reason: Reason::NoPort }]);
+		assert!(matches!(events[3..], [Event::Send(Headers { proto: 1 | 58, src, dst, .. })] if src == headers.dst && dst == headers.src));
+	}
+
+	// Packets dropped before their headers are parsed are traced without them.
+	let mut packet = ip::packet(Protocol::Udp, endpoint("10.0.0.2", 1000), endpoint("10.0.0.1", 7), transport());
+	let headers = Headers::parse(&packet);
+
+	packet[10] ^= 1;
+	ip::deliver(&mut s, &a, &packet);
+	assert_eq!(events.take().last(), Some(&Event::Drop { headers, reason: Reason::Checksum }));
+
+	packet[0] = 0x55;
+	ip::deliver(&mut s, &a, &packet);
+	assert_eq!(events.take().last(), Some(&Event::Drop { headers: None, reason: Reason::Version }));
+
+	// Packets dropped or rejected by the filter are traced with the chain which filtered them.
+	a.query(&mut s, |n, cx| n.load_filter(cx, "input drop dport 7\ninput reject dport 8\noutput drop dport 9")).unwrap().unwrap();
+
+	let sock = a.query(&mut s, |n, cx| udp::Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+
+	for port in [7, 8] {
+		let packet = ip::packet(Protocol::Udp, endpoint("10.0.0.2", 1000), endpoint("10.0.0.1", port), transport());
+		ip::deliver(&mut s, &a, &packet);
+
+		let headers = Headers::parse(&packet);
+		assert_eq!(events.take()[..2], [Event::Recv(headers.unwrap()), Event::Drop { headers, reason: Reason::Filtered(Chain::Input) }]);
+	}
+
+	sock.write(endpoint("10.0.0.2", 9), |buf| {
+		buf.push(b"filtered");
+	});
+
+	link::run(&mut s);
+
+	let headers = Headers { proto: Protocol::Udp.into(), src: "10.0.0.1".parse().unwrap(), dst: "10.0.0.2".parse().unwrap() };
+	assert_eq!(events.take(), [Event::Drop { headers: Some(headers), reason: Reason::Filtered(Chain::Output) }]);
+
+	// Nothing is traced once the hook is removed.
+	a.query(&mut s, |n, cx| n.set_trace(cx, None));
+	ip::deliver(&mut s, &a, &ip::packet(Protocol::Udp, endpoint("10.0.0.2", 1000), endpoint("10.0.0.1", 7), transport()));
+	assert!(events.borrow().is_empty());
+}
@@ -4,15 +4,17 @@ use core::net::IpAddr;
 use collections::bytes::{Cursor, Slice};
 use collections::map::{self, Key, Map};
 use log::{debug, error, info, warn};
-use stakker::{Actor, Fwd, CX};
+use stakker::{Actor, Fwd, Ret, CX};
 use utils::bytes::{self, Cast};
 use utils::endian::u16be;
 use utils::error::*;
 
+use crate::icmp::Report;
+use crate::ip::port::Ephemeral;
 use crate::ip::Protocol::Udp;
-use crate::ip::{self, SocketAddr, ToS};
-
-const EPHEMERAL: u16 = 49152;
+use crate::ip::{self, Df, DiffServ, Info, Options, Params, SocketAddr, ECN};
+use crate::stats;
+use crate::trace::Reason;
 
 #[derive(Cast)]
 #[repr(C)]
@@ -27,10 +29,18 @@ struct Header {
 pub struct Socket {
 	port: u16,
 	interface: Actor<super::Interface>,
+	/// The IP header parameters of outgoing packets.
+	params: Params,
 }
 
 impl Socket {
-	pub fn bind(this: &mut super::Interface, cx: CX![super::Interface], port: u16, callback: Fwd<(SocketAddr, Slice)>) -> Result<Self> {
+	pub fn bind(this: &mut super::Interface, cx: CX![super::Interface], port: u16, callback: Fwd<(SocketAddr, Slice, Info)>) -> Result<Self> {
+		// The port may be the translated source port of a masqueraded flow.
+		if this.nat.in_use(Udp, port) {
+			error!("Address already in use");
+			return Err(());
+		}
+
 		let udp = &mut this.udp;
 
 		let entry = match udp.map.find_entry(&port) {
@@ -41,46 +51,100 @@ impl Socket {
 			}
 		};
 
-		entry.insert(Entry { port, callback });
+		entry.insert(Entry { port, callback, errors: None });
 
-		Ok(Socket { port, interface: cx.access_actor().clone() })
+		Ok(Socket {
+			port,
+			interface: cx.access_actor().clone(),
+			params: Params::default(),
+		})
 	}
 
-	pub fn bind_eph(this: &mut super::Interface, cx: CX![super::Interface], callback: Fwd<(SocketAddr, Slice)>) -> Self {
-		let udp = &mut this.udp;
-
-		// Note: if all ports in the ephemeral range are full, this will loop forever.
-		let entry = loop {
-			// Increment, wrapping to the ephemeral port starting index
-			udp.nxt = udp.nxt.checked_add(1).unwrap_or(EPHEMERAL);
-
-			match udp.map.find_entry(&udp.nxt) {
-				map::Entry::Empty(entry) => break entry,
-				// If the port is already taken, continue
-				_ => {}
-			}
-		};
-
-		entry.insert(Entry { port: udp.nxt, callback });
+	/// Binds a socket to the next free port of the ephemeral range. Panics if every port in the range is in use.
+	pub fn bind_eph(this: &mut super::Interface, cx: CX![super::Interface], callback: Fwd<(SocketAddr, Slice, Info)>) -> Self {
+		let port = bind_eph(this, callback);
 
 		Socket {
-			port: udp.nxt,
+			port,
 			interface: cx.access_actor().clone(),
+			params: Params::default(),
 		}
 	}
 
-	pub fn write(&self, SocketAddr { addr, port }: SocketAddr, f: impl FnOnce(Cursor) + 'static) {
-		let tos = ToS::new(ip::ECN::NotECT, ip::DiffServ::Default);
+	/// Sets the IPv4 header options written to outgoing packets.
+	pub fn set_options(&mut self, options: Options) {
+		self.params.options = options;
+	}
+
+	/// Sets whether the Don't Fragment flag is set on outgoing IPv4 packets.
+	pub fn set_df(&mut self, df: Df) {
+		self.params.df = df;
+	}
+
+	/// Sets the time-to-live, or hop limit for IPv6, of outgoing packets.
+	pub fn set_ttl(&mut self, ttl: u8) {
+		self.params.ttl = ttl;
+	}
+
+	/// Sets the Differentiated Services code point of outgoing packets.
+	pub fn set_dscp(&mut self, ds: DiffServ) {
+		self.params.tos.set_ds(ds);
+	}
+
+	/// Sets the Explicit Congestion Notification codepoint of outgoing packets.
+	pub fn set_ecn(&mut self, ecn: ECN) {
+		self.params.tos.set_ecn(ecn);
+	}
+
+	/// Sets the callback of ICMP error messages about datagrams written by the socket, such as Port Unreachable and Packet Too Big
+	/// messages. Error messages are discarded until a callback is set.
+	pub fn set_error_callback(&self, callback: Fwd<Report>) {
+		let port = self.port;
+		let actor = self.interface.access_actor().clone();
+
+		self.interface.defer(move |s| {
+			actor.apply(s, move |this, _| {
+				if let Some(entry) = this.udp.map.find_entry(&port).filled() {
+					entry.into_ref().errors = Some(callback);
+				}
+			})
+		});
+	}
+
+	/// Returns the estimated MTU of the path to an address through `ret`. The largest payload which can be written without fragmentation
+	/// is this value less the length of the IP and UDP headers.
+	pub fn path_mtu(&self, addr: IpAddr, ret: Ret<usize>) {
+		let actor = self.interface.access_actor().clone();
+
+		self.interface.defer(move |s| actor.apply(s, move |this, cx| ret.ret(this.path_mtu(cx, addr))));
+	}
+
+	pub fn write(&self, addr: SocketAddr, f: impl FnOnce(Cursor) + 'static) {
+		self.write_with(addr, self.params, f);
+	}
+
+	/// Writes a packetization-layer path MTU probe, which is never fragmented and may exceed the estimated path MTU. The size of the probe
+	/// should be chosen with a [`crate::pmtu::Search`].
+	pub fn write_probe(&self, addr: SocketAddr, f: impl FnOnce(Cursor) + 'static) {
+		self.write_with(addr, Params { probe: true, ..self.params }, f);
+	}
 
+	fn write_with(&self, SocketAddr { addr, port }: SocketAddr, params: Params, f: impl FnOnce(Cursor) + 'static) {
 		let src = self.port;
 
 		let actor = self.interface.access_actor().clone();
 
 		self.interface.defer(move |s| {
 			actor.apply(s, move |this, cx| {
-				let mut csum = this.ip.pseudo_checksum(Udp, addr);
+				let Some(local) = this.ip.addrs.source(addr) else {
+					return warn!("No source address for destination {addr}");
+				};
+
+				this.udp.stats.out_datagrams += 1;
 
-				this.write(cx, Udp, addr, tos, move |mut buf| {
+				let mut csum = ip::pseudo_checksum(Udp, local, addr);
+
+				this.write(cx, Udp, local, addr, params, move |mut buf| {
 					{
 						let (header, buf): (&mut Header, _) = buf.fork().split();
 
@@ -122,36 +186,23 @@ pub struct Connected {
 }
 
 impl Connected {
-	pub fn bind(this: &mut super::Interface, cx: CX![super::Interface], addr: SocketAddr, callback: impl Fn(Slice) + 'static) -> Self {
-		let udp = &mut this.udp;
-
-		let callback = Fwd::new(move |(src, buf)| {
+	pub fn bind(this: &mut super::Interface, cx: CX![super::Interface], addr: SocketAddr, callback: impl Fn(Slice, Info) + 'static) -> Self {
+		let callback = Fwd::new(move |(src, buf, info)| {
 			if src == addr {
 				// The packet source matches the bound address
-				callback(buf);
+				callback(buf, info);
 			} else {
 				info!("Recieved unexpected packet from {}", src);
 			}
 		});
 
-		// Note: if all ports in the ephemeral range are full, this will loop forever.
-		let entry = loop {
-			// Increment, wrapping to the ephemeral port starting index
-			udp.nxt = udp.nxt.checked_add(1).unwrap_or(EPHEMERAL);
-
-			match udp.map.find_entry(&udp.nxt) {
-				map::Entry::Empty(entry) => break entry,
-				// If the port is already taken, continue
-				_ => {}
-			}
-		};
-
-		entry.insert(Entry { port: udp.nxt, callback });
+		let port = bind_eph(this, callback);
 
 		Connected {
 			inner: Socket {
-				port: udp.nxt,
+				port,
 				interface: cx.access_actor().clone(),
+				params: Params::default(),
 			},
 			addr,
 		}
@@ -161,28 +212,91 @@ impl Connected {
 		&self.addr
 	}
 
+	/// Sets the IPv4 header options written to outgoing packets.
+	pub fn set_options(&mut self, options: Options) {
+		self.inner.set_options(options);
+	}
+
+	/// Sets whether the Don't Fragment flag is set on outgoing IPv4 packets.
+	pub fn set_df(&mut self, df: Df) {
+		self.inner.set_df(df);
+	}
+
+	/// Sets the time-to-live, or hop limit for IPv6, of outgoing packets.
+	pub fn set_ttl(&mut self, ttl: u8) {
+		self.inner.set_ttl(ttl);
+	}
+
+	/// Sets the Differentiated Services code point of outgoing packets.
+	pub fn set_dscp(&mut self, ds: DiffServ) {
+		self.inner.set_dscp(ds);
+	}
+
+	/// Sets the Explicit Congestion Notification codepoint of outgoing packets.
+	pub fn set_ecn(&mut self, ecn: ECN) {
+		self.inner.set_ecn(ecn);
+	}
+
+	/// Sets the callback of ICMP error messages about datagrams written to the bound address.
+	pub fn set_error_callback(&self, callback: impl Fn(Report) + 'static) {
+		let addr = self.addr;
+
+		self.inner.set_error_callback(Fwd::new(move |report: Report| {
+			if report.quoted.dst == addr {
+				callback(report);
+			}
+		}));
+	}
+
 	pub fn write(&self, f: impl FnOnce(Cursor) + 'static) {
 		self.inner.write(self.addr, f);
 	}
 }
 
+/// Binds a callback to the next free port of the ephemeral range, which is shared with masqueraded flows. Panics if every port in the range
+/// is in use.
+fn bind_eph(this: &mut super::Interface, callback: Fwd<(SocketAddr, Slice, Info)>) -> u16 {
+	let Some(port) = this.udp.next_port(|port| this.nat.in_use(Udp, port)) else {
+		panic!("Every port in the ephemeral range is in use");
+	};
+
+	// The port was just found to be free.
+	this.udp.map.insert_unique(&port).insert(Entry { port, callback, errors: None });
+
+	port
+}
+
+#[derive(Default)]
 pub(crate) struct Interface {
-	/// The port number of the last created ephemeral socket
-	nxt: u16,
+	/// The allocator of ports for ephemeral sockets.
+	ports: Ephemeral,
 	map: Map<Entry, 1024>,
+	pub stats: stats::Udp,
 }
 
 impl Interface {
-	pub fn recv<'a>(&'a self, interface: &ip::Interface, addr: IpAddr, buf: Slice) -> Result {
-		let len: u32 = buf.len().try_into().map_err(|_| log::warn!("UDP packet too big ({} bytes)", buf.len()))?;
+	/// Returns the next port of the ephemeral range which is not bound, and for which `in_use` returns false.
+	pub fn next_port(&mut self, mut in_use: impl FnMut(u16) -> bool) -> Option<u16> {
+		let map = &self.map;
+		self.ports.next(|port| map.find(&port).is_some() || in_use(port))
+	}
+
+	/// Passes a datagram to the socket bound to its destination port, returning the port.
+	pub fn recv<'a>(&'a mut self, addr: IpAddr, info: Info, buf: Slice) -> Result<u16, Reason> {
+		let Ok(len) = u32::try_from(buf.len()) else {
+			log::warn!("UDP packet too big ({} bytes)", buf.len());
+			self.stats.in_errors += 1;
+			return Err(Reason::UdpHeader);
+		};
 
 		if buf.len() < size_of::<Header>() {
 			log::warn!("UDP header too short (got {} bytes)", buf.len());
-			return Err(());
+			self.stats.in_errors += 1;
+			return Err(Reason::UdpHeader);
 		}
 
 		if addr.is_ipv6() || bytes::cast::<Header, _>(&*buf).csum != [0, 0] {
-			let mut csum = interface.pseudo_checksum(Udp, addr);
+			let mut csum = ip::pseudo_checksum(Udp, addr, info.dst);
 
 			csum.push(&len.to_be_bytes());
 			csum.push(&buf);
@@ -191,7 +305,9 @@ impl Interface {
 
 			if v != [0, 0] {
 				warn!("Packet with invalid UDP checksum");
-				return Err(());
+				self.stats.in_errors += 1;
+				self.stats.in_csum_errors += 1;
+				return Err(Reason::UdpChecksum);
 			}
 		}
 
@@ -199,30 +315,43 @@ impl Interface {
 
 		let dst = header.dst.get();
 
-		let e = self.map.find(&dst).ok_or_else(|| debug!("Socket at port {dst} not found"))?;
-
 		if header.len.get() as u32 != len {
-			log::warn!("UDP header length ({len}) does not match actual packet length ({})", len);
-			return Err(());
+			log::warn!("UDP header length ({}) does not match actual packet length ({len})", header.len.get());
+			self.stats.in_errors += 1;
+			return Err(Reason::UdpHeader);
 		}
 
+		let Some(e) = self.map.find(&dst) else {
+			debug!("Socket at port {dst} not found");
+			self.stats.no_ports += 1;
+			return Err(Reason::NoPort);
+		};
+
+		self.stats.in_datagrams += 1;
+
 		let port = header.src.get();
 
-		e.callback.fwd((SocketAddr { addr, port }, buf));
+		e.callback.fwd((SocketAddr { addr, port }, buf, info));
 
-		Ok(())
+		Ok(dst)
 	}
-}
 
-impl Default for Interface {
-	fn default() -> Self {
-		Self { nxt: EPHEMERAL, map: Default::default() }
+	/// Passes an ICMP error message about a datagram to the error callback of the socket which wrote it.
+	pub fn report(&self, report: Report) {
+		let port = report.quoted.src.port;
+
+		match self.map.find(&port).and_then(|e| e.errors.as_ref()) {
+			Some(errors) => errors.fwd(report),
+			None => debug!("No error callback for socket at port {port}"),
+		}
 	}
 }
 
 pub(crate) struct Entry {
 	port: u16,
-	callback: Fwd<(SocketAddr, Slice)>,
+	callback: Fwd<(SocketAddr, Slice, Info)>,
+	/// The callback of ICMP error messages, if one is set.
+	errors: Option<Fwd<Report>>,
 }
 
 impl Key for Entry {
@@ -232,3 +361,95 @@ impl Key for Entry {
 		&self.port
 	}
 }
+
+#[test]
+fn test_round_trip() {
+	use core::net::Ipv4Addr;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::link;
+
+	let (mut s, a, b) = link::connect();
+
+	let to_a = Rc::new(RefCell::new(Vec::new()));
+	let to_b = Rc::new(RefCell::new(Vec::new()));
+
+	let (ra, rb) = (to_a.clone(), to_b.clone());
+
+	let sock_a = a.query(&mut s, |n, cx| Socket::bind_eph(n, cx, Fwd::new(move |(src, buf, _): (SocketAddr, Slice, Info)| ra.borrow_mut().push((src, buf.to_vec()))))).unwrap();
+	let sock_b = b.query(&mut s, |n, cx| Socket::bind(n, cx, 7, Fwd::new(move |(src, buf, _): (SocketAddr, Slice, Info)| rb.borrow_mut().push((src, buf.to_vec()))))).unwrap().unwrap();
+
+	// Binding a port which is in use fails.
+	assert!(b.query(&mut s, |n, cx| Socket::bind(n, cx, 7, Fwd::new(|_| ()))).unwrap().is_err());
+
+	for (src, dst) in [(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))), ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap())] {
+		sock_a.write(SocketAddr { addr: dst, port: 7 }, |buf| {
+			buf.push(b"ping");
+		});
+
+		link::run(&mut s);
+
+		let (from, data) = to_b.borrow_mut().pop().unwrap();
+		assert_eq!((from.addr, from.port, &data[..]), (src, sock_a.port, &b"ping"[..]));
+
+		// Reply to the source of the datagram.
+		sock_b.write(from, |buf| {
+			buf.push(b"pong");
+		});
+
+		link::run(&mut s);
+
+		let (from, data) = to_a.borrow_mut().pop().unwrap();
+		assert_eq!((from.addr, from.port, &data[..]), (dst, 7, &b"pong"[..]));
+	}
+
+	assert!(to_a.borrow().is_empty() && to_b.borrow().is_empty());
+	assert_eq!(b.query(&mut s, |n, _| n.stats().udp.in_datagrams), Some(2));
+}
+
+#[test]
+fn test_error_callback() {
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	use crate::icmp::{Kind, Quoted, Unreachable};
+	use crate::link;
+
+	let (mut s, a, _b) = link::connect();
+
+	let reports = Rc::new(RefCell::new(Vec::new()));
+	let others = Rc::new(RefCell::new(Vec::new()));
+
+	let (r, o) = (reports.clone(), others.clone());
+
+	let sock = a.query(&mut s, |n, cx| Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+	let other = a.query(&mut s, |n, cx| Socket::bind_eph(n, cx, Fwd::new(|_| ()))).unwrap();
+
+	other.set_error_callback(Fwd::new(move |report: Report| o.borrow_mut().push(report)));
+
+	let write = |s: &mut stakker::Stakker, dst: IpAddr| {
+		sock.write(SocketAddr { addr: dst, port: 9 }, |buf| {
+			buf.push(b"ping");
+		});
+
+		link::run(s);
+	};
+
+	// Errors are discarded until a callback is set.
+	write(&mut s, "10.0.0.2".parse().unwrap());
+
+	sock.set_error_callback(Fwd::new(move |report: Report| r.borrow_mut().push(report)));
+
+	for (src, dst) in [("10.0.0.1", "10.0.0.2"), ("fd00::1", "fd00::2")] {
+		let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+
+		write(&mut s, dst);
+
+		let quoted = Quoted { proto: Udp.into(), src: SocketAddr { addr: src, port: sock.port }, dst: SocketAddr { addr: dst, port: 9 } };
+		assert_eq!(reports.borrow_mut().pop(), Some(Report { from: dst, kind: Kind::Unreachable(Unreachable::Port), quoted }));
+	}
+
+	// Errors only reach the socket which wrote the datagram.
+	assert!(reports.borrow().is_empty() && others.borrow().is_empty());
+}
@@ -21,6 +21,13 @@ pub const fn cast<T: Cast, A: Cast + ?Sized>(a: &A) -> &T {
 	unsafe { &*ptr }
 }
 
+/// Cast the current type as a reference to another type, returning `None` if it is too small or misaligned.
+#[inline]
+pub fn try_cast<T: Cast, A: Cast + ?Sized>(a: &A) -> Option<&T> {
+	let ptr: *const T = a as *const A as *const T;
+	(size_of::<T>() <= size_of_val(a) && ptr.is_aligned()).then(|| unsafe { &*ptr })
+}
+
 /// Cast the current type as a slice of another type.
 #[inline]
 pub const fn as_slice<T: Cast, A: Cast + ?Sized>(a: &A) -> &[T] {
@@ -82,3 +89,16 @@ impl_expect!(
 	(Ipv4Addr, u16be): 6
 	(Ipv6Addr, u16be): 18
 );
+
+#[test]
+fn test_try_cast() {
+	let words = [0x0102_0304u32, 0x0506_0708];
+	let bytes: &[u8] = as_slice(&words[..]);
+
+	assert_eq!(try_cast::<u32, _>(&bytes[..4]), Some(&words[0]));
+	assert_eq!(try_cast::<u32, _>(&bytes[4..]), Some(&words[1]));
+	assert_eq!(try_cast::<u32, _>(&bytes[..3]), None);
+	// Every offset which is not a multiple of 4 is misaligned for a u32.
+	assert_eq!(try_cast::<u32, _>(&bytes[1..5]), None);
+	assert_eq!(try_cast::<u32, _>(&bytes[2..]), None);
+}
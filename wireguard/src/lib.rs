@@ -81,7 +81,11 @@ impl Wireguard {
 	}
 
 	fn read(&mut self, cx: CX![], buf: Slice) {
-		let _ = match *bytes::cast(&*buf) {
+		let Some(&tag) = bytes::try_cast(&*buf) else {
+			return warn!("Recieved packet too short for a message tag");
+		};
+
+		let _ = match tag {
 			packet::Tag::INITIATION => self.initiation(cx, buf),
 			packet::Tag::RESPONSE => self.response(cx, buf),
 			packet::Tag::COOKIE => self.cookie(cx, buf),
@@ -53,9 +53,13 @@ impl CookieMac {
 		Self { mac1, mac2: None, aead }
 	}
 
-	pub fn check(&mut self, cx: CX![Wireguard], bytes: &[u8]) -> Result {
-		let m1 = bytes.len() - 32;
-		let m2 = bytes.len() - 16;
+	pub fn check(&self, _: CX![Wireguard], bytes: &[u8]) -> Result {
+		let Some(m1) = bytes.len().checked_sub(32) else {
+			warn!("Packet is too short for its MACs");
+			return Err(());
+		};
+
+		let m2 = m1 + 16;
 
 		let mac1 = Mac::new(&self.mac1).chain(&bytes[..m1]).finalize_fixed();
 
@@ -64,13 +68,9 @@ impl CookieMac {
 			return Err(());
 		}
 
-		let mac2 = if self.tau(cx).is_some() {
-			unimplemented!("Cookie sending is not supported")
-		} else {
-			[0u8; 16]
-		};
-
-		if mac2.as_slice() != &bytes[m2..] {
+		// Cookies are never sent, so peers have no cookie with which to compute mac2, and leave it empty. The cookie received from a peer
+		// is only used to compute the mac2 of messages sent to it.
+		if bytes[m2..] != [0u8; 16] {
 			warn!("Packet contains invalid mac2");
 			return Err(());
 		}
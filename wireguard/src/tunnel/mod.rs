@@ -166,7 +166,7 @@ impl Peer {
 	}
 
 	pub fn handle_data<'a>(&mut self, cx: CX![Wireguard], wg: &Interface, buf: &mut Slice) -> Result {
-		let msg: &Data = buf.split();
+		let msg: &Data = buf.try_split().ok_or_else(|| warn!("Data packet is too short for its header"))?;
 
 		match &mut self.wheel {
 			&mut Wheel { pair: Some((i, ref mut k)), .. } if msg.idx == i => {
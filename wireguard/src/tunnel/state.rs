@@ -19,7 +19,7 @@ pub const REKEY_AFTER_MESSAGES: u64 = 2u64.pow(60);
 pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - 2u64.pow(13);
 
 fn open(key: &Aead, ctr: u64, buf: &mut Slice) -> Result {
-	let tag = *buf.rsplit();
+	let tag = *buf.try_rsplit().ok_or_else(|| warn!("Data packet is too short for its authentication tag"))?;
 
 	key.decrypt_in_place_detached(&nonce(ctr), &[], buf, &tag)
 		.map_err(|_| warn!("Failed to decrypt data payload"))?;